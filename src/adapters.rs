@@ -1,5 +1,15 @@
+pub mod bundle_file;
 pub mod clock;
+pub mod encrypted_event_store;
+pub mod event_upcasting;
 pub mod file_event_store;
+pub mod file_key_store;
+pub mod file_snapshot_store;
 pub mod http_api_axum;
+pub mod http_sync_client;
 pub mod memory_event_store;
+pub mod memory_key_store;
 pub mod memory_read_model;
+pub mod memory_snapshot_store;
+pub mod shredding_event_store;
+pub mod signed_event_store;
@@ -0,0 +1,133 @@
+use crate::domain::data::DomainEvent;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+};
+
+const BUNDLE_HEADER: &str = "decentrasync-bundle 1";
+
+#[derive(thiserror::Error, Debug)]
+pub enum BundleError {
+    #[error("Could not access bundle: {0}")]
+    Io(#[from] io::Error),
+    #[error("Not a bundle, or written by an incompatible version")]
+    UnsupportedFormat,
+    #[error("Bundle is corrupted")]
+    Corrupted,
+}
+
+// A bundle carries events, and optionally the keys to unseal them, through any
+// channel that can move a single file.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Bundle {
+    pub events: Vec<DomainEvent>,
+    // Hex-encoded keys by bookmark ID
+    pub keys: BTreeMap<String, String>,
+}
+
+// It's a gzipped header line, a SHA-256 checksum line and the bundle as
+// JSON, the checksum covering the exact JSON bytes.
+pub fn write_bundle(path: &Path, bundle: &Bundle) -> Result<(), BundleError> {
+    let json = serde_json::to_vec(bundle).unwrap();
+    let checksum = hex::encode(Sha256::digest(&json));
+
+    let mut encoder = GzEncoder::new(File::create(path)?, Compression::default());
+    writeln!(encoder, "{}", BUNDLE_HEADER)?;
+    writeln!(encoder, "{}", checksum)?;
+    encoder.write_all(&json)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+pub fn read_bundle(path: &Path) -> Result<Bundle, BundleError> {
+    let mut contents = vec![];
+    GzDecoder::new(File::open(path)?)
+        .read_to_end(&mut contents)
+        .map_err(|_source| BundleError::Corrupted)?;
+
+    let mut parts = contents.splitn(3, |b| *b == b'\n');
+    if parts.next() != Some(BUNDLE_HEADER.as_bytes()) {
+        return Err(BundleError::UnsupportedFormat);
+    }
+    let checksum = parts.next().ok_or(BundleError::Corrupted)?;
+    let json = parts.next().ok_or(BundleError::Corrupted)?;
+    if checksum != hex::encode(Sha256::digest(json)).as_bytes() {
+        return Err(BundleError::Corrupted);
+    }
+
+    serde_json::from_slice(json).map_err(|_source| BundleError::Corrupted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        data::DomainEventMeta,
+        events::{BookmarkEventPayload, DomainEventPayload},
+    };
+    use assert_fs::{fixture::PathChild, TempDir};
+    use std::{fs, time::SystemTime};
+
+    fn bookmark_created_event() -> DomainEvent {
+        DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: "123".to_owned(),
+                created_at: SystemTime::UNIX_EPOCH,
+                instance_id: "laptop".to_owned(),
+                sequence: 1,
+                previous_hash: None,
+            },
+            payload: DomainEventPayload::Bookmark(BookmarkEventPayload::Created {
+                url: "https://example.com".to_owned(),
+                title: "Example".to_owned(),
+            }),
+            signature: None,
+            sealed: None,
+        }
+    }
+
+    fn bundle() -> Bundle {
+        Bundle {
+            events: vec![bookmark_created_event()],
+            keys: BTreeMap::from([("123".to_owned(), "00".repeat(32))]),
+        }
+    }
+
+    #[test]
+    fn test_events_survive_a_round_trip_through_a_bundle() {
+        let temp = TempDir::new().unwrap();
+        let bundle_path = temp.child("laptop.bundle");
+
+        write_bundle(bundle_path.path(), &bundle()).unwrap();
+
+        assert_eq!(read_bundle(bundle_path.path()).unwrap(), bundle());
+    }
+
+    #[test]
+    fn test_altered_bundle_is_rejected() {
+        let temp = TempDir::new().unwrap();
+        let bundle_path = temp.child("laptop.bundle");
+        write_bundle(bundle_path.path(), &bundle()).unwrap();
+
+        let mut contents = vec![];
+        GzDecoder::new(File::open(bundle_path.path()).unwrap())
+            .read_to_end(&mut contents)
+            .unwrap();
+        let altered = String::from_utf8(contents)
+            .unwrap()
+            .replace("Example", "Exemple");
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(altered.as_bytes()).unwrap();
+        fs::write(bundle_path.path(), encoder.finish().unwrap()).unwrap();
+
+        assert!(matches!(
+            read_bundle(bundle_path.path()),
+            Err(BundleError::Corrupted)
+        ));
+    }
+}
@@ -6,6 +6,7 @@ use std::{
 
 pub struct SystemClock {}
 
+#[allow(clippy::new_without_default)]
 impl SystemClock {
     pub fn new() -> Self {
         Self {}
@@ -22,6 +23,7 @@ pub struct FakeClock {
     clock: Arc<RwLock<SystemTime>>,
 }
 
+#[allow(clippy::new_without_default)]
 impl FakeClock {
     pub fn new() -> Self {
         Self {
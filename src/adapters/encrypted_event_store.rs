@@ -0,0 +1,323 @@
+use crate::{
+    domain::{
+        chain,
+        checkpoint::Checkpoint,
+        data::DomainEvent,
+        events::{DomainEventPayload, EncryptedEventPayload},
+        reconciliation::EventIds,
+    },
+    ports::{EventStore, EventStoreError},
+};
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+// Name of the file holding the library's key derivation salt, in the synced
+// log folder next to the events, as every device needs it to derive the key.
+pub const LIBRARY_SALT_FILE: &str = "library.salt";
+
+// Decorates an event store so that payloads are encrypted before reaching
+// it and decrypted when read back. Metadata is left in clear, and bound to
+// the ciphertext as associated data, so that file naming and layout don't
+// change. Events that can't be decrypted with the library key are skipped,
+// which is why the key should be checked against the log before use.
+pub struct EncryptedEventStore {
+    inner: Arc<dyn EventStore>,
+    cipher: ChaCha20Poly1305,
+    write_lock: Mutex<()>,
+    // Events are identified by their decrypted form, which is what peers
+    // are sent, while the inner store identifies them by their ciphertext.
+    // They're only worked out again once the inner store's IDs change.
+    event_ids: Mutex<Option<(EventIds, EventIds)>>,
+}
+
+impl EncryptedEventStore {
+    pub fn new(inner: Arc<dyn EventStore>, passphrase: &str, salt: &[u8]) -> Self {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .unwrap();
+
+        Self {
+            inner,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            write_lock: Mutex::new(()),
+            event_ids: Mutex::new(None),
+        }
+    }
+
+    // A wrong passphrase would otherwise show an empty library, and new
+    // events would be written with a key other devices don't have.
+    pub fn check_library_key(&self) -> Result<(), EventStoreError> {
+        let undecryptable = self.inner.events_iter().any(|e| {
+            matches!(e.payload, DomainEventPayload::Encrypted(_))
+                && decrypt(&self.cipher, e).is_none()
+        });
+        if undecryptable {
+            Err(EventStoreError::Undecryptable)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn encrypt(&self, event: DomainEvent) -> Result<DomainEvent, EventStoreError> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &serde_json::to_vec(&event.payload).unwrap(),
+                    aad: &serde_json::to_vec(&event.meta).unwrap(),
+                },
+            )
+            .map_err(|_source| EventStoreError::Generic)?;
+
+        Ok(DomainEvent {
+            payload: DomainEventPayload::Encrypted(EncryptedEventPayload {
+                nonce: hex::encode(nonce),
+                ciphertext: hex::encode(ciphertext),
+            }),
+            ..event
+        })
+    }
+}
+
+// The salt is random for each library, so that keys can't be attacked for all
+// libraries at once. The device that first encrypts the library creates it;
+// until it has been synced to another device, that one can't open the log.
+pub fn load_or_create_library_salt(
+    path: &Path,
+    inner: &dyn EventStore,
+) -> Result<Vec<u8>, EventStoreError> {
+    if let Some(salt) = fs::read_to_string(path)
+        .ok()
+        .and_then(|salt| hex::decode(salt.trim()).ok())
+        .filter(|salt| !salt.is_empty())
+    {
+        return Ok(salt);
+    }
+    if inner
+        .events_iter()
+        .any(|e| matches!(e.payload, DomainEventPayload::Encrypted(_)))
+    {
+        return Err(EventStoreError::MissingLibrarySalt);
+    }
+    let mut salt = vec![0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    fs::write(path, hex::encode(&salt)).map_err(|_source| EventStoreError::Generic)?;
+    Ok(salt)
+}
+
+// Events written before encryption was enabled are passed through as they
+// are.
+fn decrypt(cipher: &ChaCha20Poly1305, event: DomainEvent) -> Option<DomainEvent> {
+    let encrypted = match &event.payload {
+        DomainEventPayload::Encrypted(encrypted) => encrypted,
+        _ => return Some(event),
+    };
+
+    let nonce = hex::decode(&encrypted.nonce).ok()?;
+    if nonce.len() != 12 {
+        return None;
+    }
+    let plaintext = cipher
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &hex::decode(&encrypted.ciphertext).ok()?,
+                aad: &serde_json::to_vec(&event.meta).unwrap(),
+            },
+        )
+        .ok()?;
+
+    Some(DomainEvent {
+        payload: serde_json::from_slice(&plaintext).ok()?,
+        ..event
+    })
+}
+
+impl EventStore for EncryptedEventStore {
+    fn store_events(
+        &self,
+        events: Vec<DomainEvent>,
+        expected_version: Option<u64>,
+    ) -> Result<(), EventStoreError> {
+        // Metadata is bound to the ciphertext, so events are linked first
+        let _write_lock = self.write_lock.lock().unwrap();
+        let events = chain::link(events, || self.chain_head());
+        self.inner.store_events(
+            events
+                .into_iter()
+                .map(|e| self.encrypt(e))
+                .collect::<Result<_, _>>()?,
+            expected_version,
+        )
+    }
+
+    fn import_event(&self, event: DomainEvent) -> Result<(), EventStoreError> {
+        self.inner.import_event(self.encrypt(event)?)
+    }
+
+    fn replace_event(&self, event: DomainEvent) -> Result<(), EventStoreError> {
+        self.inner.replace_event(self.encrypt(event)?)
+    }
+
+    fn get_events_for_aggregate(&self, aggregate_id: &str) -> Vec<DomainEvent> {
+        self.inner
+            .get_events_for_aggregate(aggregate_id)
+            .into_iter()
+            .filter_map(|e| decrypt(&self.cipher, e))
+            .collect()
+    }
+
+    fn aggregate_version(&self, aggregate_id: &str) -> u64 {
+        self.inner.aggregate_version(aggregate_id)
+    }
+
+    fn events_iter(&self) -> Box<dyn Iterator<Item = DomainEvent>> {
+        let cipher = self.cipher.clone();
+        Box::new(
+            self.inner
+                .events_iter()
+                .filter_map(move |e| decrypt(&cipher, e)),
+        )
+    }
+
+    fn events_after(&self, checkpoint: &Checkpoint) -> Box<dyn Iterator<Item = DomainEvent>> {
+        let cipher = self.cipher.clone();
+        Box::new(
+            self.inner
+                .events_after(checkpoint)
+                .filter_map(move |e| decrypt(&cipher, e)),
+        )
+    }
+
+    fn event_ids(&self) -> EventIds {
+        let inner_ids = self.inner.event_ids();
+        let mut cached = self.event_ids.lock().unwrap();
+        match &*cached {
+            Some((cached_inner_ids, ids)) if *cached_inner_ids == inner_ids => ids.clone(),
+            _ => {
+                let ids = EventIds::from_events(self.events_iter());
+                *cached = Some((inner_ids, ids.clone()));
+                ids
+            }
+        }
+    }
+
+    // Events that can't be decrypted are passed over, as when reading the
+    // whole log.
+    fn chain_head(&self) -> Option<DomainEvent> {
+        let head = self.inner.chain_head()?;
+        decrypt(&self.cipher, head).or_else(|| chain::head(&self.instance_id(), self.events_iter()))
+    }
+
+    fn instance_id(&self) -> String {
+        self.inner.instance_id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        adapters::{clock::FakeClock, memory_event_store::MemoryEventStore},
+        domain::{data::DomainEventMeta, events::BookmarkEventPayload},
+        ports::Clock,
+    };
+    use assert_fs::{fixture::PathChild, TempDir};
+
+    const SALT: &[u8] = b"library salt";
+
+    fn bookmark_created_event() -> DomainEvent {
+        DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: "123".to_owned(),
+                created_at: FakeClock::new().now(),
+                instance_id: "laptop".to_owned(),
+                sequence: 1,
+                previous_hash: None,
+            },
+            payload: DomainEventPayload::Bookmark(BookmarkEventPayload::Created {
+                url: "https://example.com".to_owned(),
+                title: "Example".to_owned(),
+            }),
+            signature: None,
+            sealed: None,
+        }
+    }
+
+    #[test]
+    fn test_payloads_are_encrypted_in_underlying_store() {
+        let inner = Arc::new(MemoryEventStore::with_instance_id("laptop"));
+        let event_store = EncryptedEventStore::new(inner.clone(), "correct horse", SALT);
+
+        event_store
+            .store_events(vec![bookmark_created_event()], None)
+            .unwrap();
+
+        let stored = inner.events_iter().next().unwrap();
+        assert!(matches!(stored.payload, DomainEventPayload::Encrypted(_)));
+        assert_eq!(stored.meta, bookmark_created_event().meta);
+        assert_eq!(
+            event_store.get_events_for_aggregate("123"),
+            vec![bookmark_created_event()]
+        );
+    }
+
+    #[test]
+    fn test_events_cannot_be_replayed_without_library_key() {
+        let inner = Arc::new(MemoryEventStore::with_instance_id("laptop"));
+        EncryptedEventStore::new(inner.clone(), "correct horse", SALT)
+            .store_events(vec![bookmark_created_event()], None)
+            .unwrap();
+
+        let event_store = EncryptedEventStore::new(inner.clone(), "battery staple", SALT);
+
+        assert_eq!(event_store.events_iter().count(), 0);
+        assert_eq!(
+            event_store.check_library_key(),
+            Err(EventStoreError::Undecryptable)
+        );
+        assert_eq!(
+            EncryptedEventStore::new(inner, "correct horse", SALT).check_library_key(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_each_library_gets_a_salt_of_its_own() {
+        let temp = TempDir::new().unwrap();
+        let inner = Arc::new(MemoryEventStore::with_instance_id("laptop"));
+        let path = temp.child(LIBRARY_SALT_FILE);
+        let salt = load_or_create_library_salt(&path, inner.as_ref()).unwrap();
+        EncryptedEventStore::new(inner.clone(), "correct horse", &salt)
+            .store_events(vec![bookmark_created_event()], None)
+            .unwrap();
+
+        assert_eq!(
+            load_or_create_library_salt(&path, inner.as_ref()),
+            Ok(salt.clone())
+        );
+        let other_path = temp.child("other.salt");
+        assert_eq!(
+            load_or_create_library_salt(&other_path, inner.as_ref()),
+            Err(EventStoreError::MissingLibrarySalt)
+        );
+        let other_salt =
+            load_or_create_library_salt(&other_path, &MemoryEventStore::new()).unwrap();
+        assert_ne!(other_salt, salt);
+        assert_eq!(
+            EncryptedEventStore::new(inner, "correct horse", &other_salt).check_library_key(),
+            Err(EventStoreError::Undecryptable)
+        );
+    }
+}
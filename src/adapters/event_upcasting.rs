@@ -0,0 +1,175 @@
+use crate::domain::data::DomainEvent;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+// Version of the shape events are stored in. Bump it along with any change
+// to the serialized form of `DomainEvent` that older code couldn't read,
+// and add an upcaster taking events from the previous version to it.
+pub const SCHEMA_VERSION: u64 = 2;
+
+// Events written before versioning was introduced have no version at all
+const UNVERSIONED: u64 = 1;
+
+// The upcaster at index `i` takes an event from version `i + 1` to `i + 2`.
+const UPCASTERS: [fn(&mut Map<String, Value>); (SCHEMA_VERSION - UNVERSIONED) as usize] =
+    [from_unversioned];
+
+#[derive(Serialize)]
+struct VersionedEvent<'a> {
+    schema_version: u64,
+    #[serde(flatten)]
+    event: &'a DomainEvent,
+}
+
+pub fn to_stored_json(event: &DomainEvent) -> String {
+    serde_json::to_string_pretty(&VersionedEvent {
+        schema_version: SCHEMA_VERSION,
+        event,
+    })
+    .unwrap()
+}
+
+// Reads an event stored in any version up to the current one, running it
+// through the upcasters it hasn't been through yet.
+pub fn from_stored_json(contents: &str) -> Result<DomainEvent, serde_json::Error> {
+    let mut event: Map<String, Value> = serde_json::from_str(contents)?;
+    let version = event
+        .remove("schema_version")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(UNVERSIONED);
+
+    for upcaster in UPCASTERS
+        .iter()
+        .skip((version.max(UNVERSIONED) - UNVERSIONED) as usize)
+    {
+        upcaster(&mut event);
+    }
+    serde_json::from_value(Value::Object(event))
+}
+
+// Unversioned events may predate instance IDs (left empty) and hash chains
+// (sequence 0).
+fn from_unversioned(event: &mut Map<String, Value>) {
+    if let Some(Value::Object(meta)) = event.get_mut("meta") {
+        meta.entry("instance_id")
+            .or_insert_with(|| Value::String("".to_owned()));
+        meta.entry("sequence").or_insert_with(|| Value::from(0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        data::DomainEventMeta,
+        events::{
+            BookmarkEventPayload, DomainEventPayload, EncryptedEventPayload, SealedEventPayload,
+        },
+    };
+    use std::time::{Duration, SystemTime};
+
+    fn meta(instance_id: &str, sequence: u64, previous_hash: Option<&str>) -> DomainEventMeta {
+        DomainEventMeta {
+            aggregate_id: "123".to_owned(),
+            created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(10),
+            instance_id: instance_id.to_owned(),
+            sequence,
+            previous_hash: previous_hash.map(str::to_owned),
+        }
+    }
+
+    fn created() -> DomainEventPayload {
+        DomainEventPayload::Bookmark(BookmarkEventPayload::Created {
+            url: "https://example.com".to_owned(),
+            title: "Example".to_owned(),
+        })
+    }
+
+    fn event(meta: DomainEventMeta, payload: DomainEventPayload) -> DomainEvent {
+        DomainEvent {
+            meta,
+            payload,
+            signature: None,
+            sealed: None,
+        }
+    }
+
+    // One fixture per shape events have ever been stored in
+    #[test]
+    fn test_every_historic_format_is_read() {
+        let fixtures = [
+            (
+                include_str!("../../fixtures/events/unversioned-original.json"),
+                event(meta("", 0, None), created()),
+            ),
+            (
+                include_str!("../../fixtures/events/unversioned-with-instance.json"),
+                event(meta("laptop", 0, None), created()),
+            ),
+            (
+                include_str!("../../fixtures/events/unversioned-signed.json"),
+                DomainEvent {
+                    signature: Some("5f2e".to_owned()),
+                    ..event(meta("laptop", 0, None), created())
+                },
+            ),
+            (
+                include_str!("../../fixtures/events/unversioned-encrypted.json"),
+                event(
+                    meta("laptop", 0, None),
+                    DomainEventPayload::Encrypted(EncryptedEventPayload {
+                        nonce: "00".repeat(12),
+                        ciphertext: "c1f3".to_owned(),
+                    }),
+                ),
+            ),
+            (
+                include_str!("../../fixtures/events/unversioned-chained.json"),
+                event(meta("laptop", 2, Some("9a4c")), created()),
+            ),
+            (
+                include_str!("../../fixtures/events/unversioned-sealed.json"),
+                event(
+                    meta("laptop", 2, Some("9a4c")),
+                    DomainEventPayload::Sealed(SealedEventPayload {
+                        nonce: "00".repeat(12),
+                        ciphertext: "c1f3".to_owned(),
+                    }),
+                ),
+            ),
+            (
+                include_str!("../../fixtures/events/v2.json"),
+                event(meta("laptop", 2, Some("9a4c")), created()),
+            ),
+        ];
+
+        for (contents, expected) in fixtures {
+            assert_eq!(from_stored_json(contents).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_events_from_newer_versions_are_kept_verbatim() {
+        let fixtures = [
+            include_str!("../../fixtures/events/v2-unknown-type.json"),
+            include_str!("../../fixtures/events/v2-unknown-bookmark-event.json"),
+        ];
+
+        for contents in fixtures {
+            let event = from_stored_json(contents).unwrap();
+
+            assert!(matches!(event.payload, DomainEventPayload::Unknown(_)));
+            assert_eq!(to_stored_json(&event), contents.trim_end());
+        }
+    }
+
+    #[test]
+    fn test_stored_events_are_read_back() {
+        let event = event(meta("laptop", 2, Some("9a4c")), created());
+
+        let contents = to_stored_json(&event);
+
+        assert!(contents.contains(&format!("\"schema_version\": {}", SCHEMA_VERSION)));
+        assert_eq!(from_stored_json(&contents).unwrap(), event);
+    }
+}
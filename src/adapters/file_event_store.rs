@@ -1,21 +1,120 @@
-use crate::{domain::data::DomainEvent, ports::EventStore, ports::EventStoreError};
+use crate::{
+    adapters::event_upcasting::{from_stored_json, to_stored_json},
+    domain::{
+        chain,
+        checkpoint::Checkpoint,
+        data::DomainEvent,
+        reconciliation::{self, EventIds},
+    },
+    ports::EventStore,
+    ports::EventStoreError,
+};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::VecDeque,
+    collections::{BTreeMap, HashMap, VecDeque},
     ffi::{OsStr, OsString},
-    fs,
+    fs::{self, File},
+    io::{Read, Write},
     path::{Path, PathBuf},
-    time::UNIX_EPOCH,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 pub struct FileSystemEventStore {
     log_folder_path: OsString,
+    instance_id: String,
+    // Held while checking an aggregate's version and appending to it, and
+    // while importing, so that nothing lands in between.
+    write_lock: Mutex<()>,
+    // IDs of the events read so far, by the name of the file or pack entry
+    // holding them and when that file was last modified. Only events written
+    // or synced into the folder since are read to bring them up to date.
+    event_ids: Mutex<HashMap<String, (SystemTime, String)>>,
+}
+
+// Pack files hold the exact contents of the event files they replace, in
+// the order of the names listed in their index.
+#[derive(Serialize, Deserialize)]
+struct PackIndexEntry {
+    name: String,
+    aggregate_id: String,
 }
 
 impl FileSystemEventStore {
-    pub fn new(path: &OsStr) -> Self {
+    pub fn new(path: &OsStr, instance_id: &str) -> Self {
         Self {
             log_folder_path: path.to_owned(),
+            instance_id: instance_id.to_owned(),
+            write_lock: Mutex::new(()),
+            event_ids: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Event files skipped when reading the log, as they can't be read, e.g.
+    // sync conflict copies, or were written by a newer version.
+    pub fn unreadable_files(&self) -> Vec<PathBuf> {
+        event_file_paths(&self.log_folder_path)
+            .into_iter()
+            .filter(|path| {
+                fs::read_to_string(path)
+                    .ok()
+                    .and_then(|contents| from_stored_json(&contents).ok())
+                    .is_none()
+            })
+            .collect()
+    }
+
+    // Rolls this instance's events created before `created_before` into a
+    // compressed pack file, to keep the number of files in the synced folder
+    // down. Other instances' events are left alone, as they own their files.
+    // Returns the number of events packed.
+    pub fn pack_events(&self, created_before: SystemTime) -> Result<usize, EventStoreError> {
+        let _write_lock = self.write_lock.lock().unwrap();
+        let mut packed: Vec<(String, PathBuf, String, DomainEvent)> = vec![];
+        for path in event_file_paths(&self.log_folder_path) {
+            let contents = match fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(_) => continue,
+            };
+            let event = match from_stored_json(&contents) {
+                Ok(event) => event,
+                Err(_) => continue,
+            };
+            if event.meta.instance_id == self.instance_id && event.meta.created_at < created_before
+            {
+                packed.push((file_name(&path), path, contents, event));
+            }
+        }
+        if packed.is_empty() {
+            return Ok(0);
+        }
+        packed.sort_by_cached_key(|(name, _, _, _)| sort_key(name));
+
+        let pack_stem = format!(
+            "pack-{}-{}",
+            self.instance_id,
+            packed[0].0.trim_end_matches(".json")
+        );
+        let folder = Path::new(&self.log_folder_path);
+        let contents: Vec<&String> = packed.iter().map(|(_, _, c, _)| c).collect();
+        let index: Vec<PackIndexEntry> = packed
+            .iter()
+            .map(|(name, _, _, event)| PackIndexEntry {
+                name: name.clone(),
+                aggregate_id: event.meta.aggregate_id.clone(),
+            })
+            .collect();
+
+        // The index is written last, as it's what makes the pack visible.
+        // Until the event files are removed, the iterator skips duplicates.
+        write_pack(&folder.join(format!("{}.pack", pack_stem)), &contents)?;
+        write_index(&folder.join(format!("{}.idx", pack_stem)), &index)?;
+
+        for (_, path, _, _) in &packed {
+            fs::remove_file(path).map_err(|_source| EventStoreError::Generic)?;
         }
+        Ok(packed.len())
     }
 }
 
@@ -23,80 +122,406 @@ impl Iterator for FilesystemEventStoreIterator {
     type Item = DomainEvent;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.sorted_event_filenames.pop_front() {
-            Some(f) => {
-                Some(serde_json::from_str::<DomainEvent>(&fs::read_to_string(f).unwrap()).unwrap())
+        loop {
+            let source = self.sorted_event_sources.pop_front()?;
+            if let Some(event) = self.event(source) {
+                return Some(event);
             }
-            None => None,
         }
     }
 }
 
 impl EventStore for FileSystemEventStore {
-    fn import_event(&self, _event: DomainEvent) -> Result<(), EventStoreError> {
-        todo!()
+    fn import_event(&self, event: DomainEvent) -> Result<(), EventStoreError> {
+        let imported_event_path =
+            Path::new(self.log_folder_path.as_os_str()).join(event_file_name(&event)?);
+
+        let _write_lock = self.write_lock.lock().unwrap();
+        std::fs::write(imported_event_path, to_stored_json(&event))
+            .map_err(|_source| EventStoreError::Generic)
     }
 
-    fn store_event(&self, event: DomainEvent) -> Result<(), EventStoreError> {
-        let timestamp_millis = event
-            .meta
-            .created_at
-            .duration_since(UNIX_EPOCH)
-            .map_err(|_source| EventStoreError::Generic)?
-            .as_millis();
+    // A single event gets a file of its own. Several are written as a pack,
+    // as its index appearing is what makes all of them visible at once.
+    fn store_events(
+        &self,
+        events: Vec<DomainEvent>,
+        expected_version: Option<u64>,
+    ) -> Result<(), EventStoreError> {
+        let folder = Path::new(&self.log_folder_path);
+        let _write_lock = self.write_lock.lock().unwrap();
+        let events = chain::link(events, || self.chain_head());
+        let first = match events.first() {
+            Some(first) => first,
+            None => return Ok(()),
+        };
 
-        let stored_event_path =
-            Path::new(self.log_folder_path.as_os_str()).join(format!("{}.json", timestamp_millis));
+        if expected_version
+            .is_some_and(|version| version != self.aggregate_version(&first.meta.aggregate_id))
+        {
+            return Err(EventStoreError::Conflict);
+        }
 
-        std::fs::write(
-            stored_event_path,
-            serde_json::to_string_pretty(&event).unwrap(),
-        )
-        .unwrap();
+        if let [event] = events.as_slice() {
+            return fs::write(folder.join(event_file_name(event)?), to_stored_json(event))
+                .map_err(|_source| EventStoreError::Generic);
+        }
 
-        Ok(())
+        let contents: Vec<String> = events.iter().map(to_stored_json).collect();
+        let index = events
+            .iter()
+            .map(|event| {
+                Ok(PackIndexEntry {
+                    name: event_file_name(event)?,
+                    aggregate_id: event.meta.aggregate_id.clone(),
+                })
+            })
+            .collect::<Result<Vec<PackIndexEntry>, EventStoreError>>()?;
+        let pack_stem = format!(
+            "pack-{}-{}",
+            self.instance_id,
+            index[0].name.trim_end_matches(".json")
+        );
+
+        write_pack(
+            &folder.join(format!("{}.pack", pack_stem)),
+            &contents.iter().collect::<Vec<_>>(),
+        )?;
+        write_index(&folder.join(format!("{}.idx", pack_stem)), &index)
+    }
+
+    // Packed events are replaced within their pack, so that the original
+    // doesn't linger on disk. Either way the new contents are renamed over
+    // the old, for a crash to leave one or the other.
+    fn replace_event(&self, event: DomainEvent) -> Result<(), EventStoreError> {
+        let replaces = |contents: &str| {
+            from_stored_json(contents).is_ok_and(|e| {
+                e.meta.instance_id == event.meta.instance_id
+                    && e.meta.sequence == event.meta.sequence
+            })
+        };
+        let replacement = to_stored_json(&event);
+
+        let _write_lock = self.write_lock.lock().unwrap();
+        for (_, source) in event_sources(&self.log_folder_path, Some(&event.meta.aggregate_id)) {
+            match source {
+                EventSource::File(path) => {
+                    let contents = fs::read_to_string(&path).unwrap_or_default();
+                    if replaces(&contents) {
+                        let partial_path = path.with_extension("json.partial");
+                        return fs::write(&partial_path, replacement)
+                            .and_then(|()| fs::rename(&partial_path, &path))
+                            .map_err(|_source| EventStoreError::Generic);
+                    }
+                }
+                EventSource::Packed { pack, position } => {
+                    let mut contents = read_pack(&pack)?;
+                    if replaces(&contents[position]) {
+                        contents[position] = replacement;
+                        return write_pack(&pack, &contents.iter().collect::<Vec<_>>());
+                    }
+                }
+            }
+        }
+        Err(EventStoreError::Generic)
     }
 
     fn get_events_for_aggregate(&self, aggregate_id: &str) -> Vec<DomainEvent> {
-        self.events_iter()
+        FilesystemEventStoreIterator::new(&self.log_folder_path, Some(aggregate_id))
             .filter(|e| e.meta.aggregate_id == aggregate_id)
             .collect::<Vec<DomainEvent>>()
     }
 
+    fn aggregate_version(&self, aggregate_id: &str) -> u64 {
+        self.get_events_for_aggregate(aggregate_id).len() as u64
+    }
+
     fn events_iter(&self) -> Box<dyn Iterator<Item = DomainEvent>> {
-        Box::new(FilesystemEventStoreIterator::new(&self.log_folder_path))
+        Box::new(FilesystemEventStoreIterator::new(
+            &self.log_folder_path,
+            None,
+        ))
+    }
+
+    // Events are named after their instance and sequence number, so those
+    // the checkpoint covers aren't read at all.
+    fn events_after(&self, checkpoint: &Checkpoint) -> Box<dyn Iterator<Item = DomainEvent>> {
+        Box::new(FilesystemEventStoreIterator {
+            sorted_event_sources: event_sources(&self.log_folder_path, None)
+                .into_iter()
+                .filter(|(name, _)| {
+                    origin(name).is_none_or(|(instance_id, sequence)| {
+                        !checkpoint.covers_sequence(instance_id, sequence)
+                    })
+                })
+                .map(|(_, source)| source)
+                .collect(),
+            unpacked: HashMap::new(),
+        })
+    }
+
+    fn event_ids(&self) -> EventIds {
+        let mut cached = self.event_ids.lock().unwrap();
+        let mut reader = FilesystemEventStoreIterator {
+            sorted_event_sources: VecDeque::new(),
+            unpacked: HashMap::new(),
+        };
+        let mut ids_by_name = HashMap::new();
+        for (name, source) in event_sources(&self.log_folder_path, None) {
+            let modified = match source.modified() {
+                Some(modified) => modified,
+                None => continue,
+            };
+            let id = match cached.remove(&name) {
+                Some((cached_modified, id)) if cached_modified == modified => id,
+                _ => match reader.event(source) {
+                    Some(event) => reconciliation::event_id(&event),
+                    None => continue,
+                },
+            };
+            ids_by_name.insert(name, (modified, id));
+        }
+        *cached = ids_by_name;
+
+        let mut event_ids = EventIds::default();
+        for (_, id) in cached.values() {
+            event_ids.insert_id(id);
+        }
+        event_ids
+    }
+
+    // Found by the names of this instance's events, falling back on the one
+    // before for as long as they can't be read.
+    fn chain_head(&self) -> Option<DomainEvent> {
+        let mut own_sources: Vec<(u64, EventSource)> = event_sources(&self.log_folder_path, None)
+            .into_iter()
+            .filter_map(|(name, source)| match origin(&name) {
+                Some((instance_id, sequence)) if instance_id == self.instance_id => {
+                    Some((sequence, source))
+                }
+                _ => None,
+            })
+            .collect();
+        own_sources.sort_by_key(|(sequence, _)| *sequence);
+        let mut reader = FilesystemEventStoreIterator {
+            sorted_event_sources: VecDeque::new(),
+            unpacked: HashMap::new(),
+        };
+        own_sources
+            .into_iter()
+            .rev()
+            .find_map(|(_, source)| reader.event(source))
+    }
+
+    fn instance_id(&self) -> String {
+        self.instance_id.clone()
     }
 }
 
+enum EventSource {
+    File(PathBuf),
+    Packed { pack: PathBuf, position: usize },
+}
+
+impl EventSource {
+    // None if the file went away since the folder was listed
+    fn modified(&self) -> Option<SystemTime> {
+        let path = match self {
+            EventSource::File(path) => path,
+            EventSource::Packed { pack, .. } => pack,
+        };
+        fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+}
+
+// Events are read in the order of their file names, whether they're still
+// in their own file or have been packed. Packs are only decompressed when
+// one of their events is reached.
 struct FilesystemEventStoreIterator {
-    sorted_event_filenames: VecDeque<PathBuf>,
+    sorted_event_sources: VecDeque<EventSource>,
+    unpacked: HashMap<PathBuf, Vec<String>>,
 }
 
 impl FilesystemEventStoreIterator {
-    pub fn new(log_folder_path: &OsStr) -> Self {
-        let mut event_filenames = std::fs::read_dir(log_folder_path)
-            .unwrap()
-            .map(|res| res.map(|e| e.path()))
-            .collect::<Result<VecDeque<_>, std::io::Error>>()
-            .unwrap();
+    pub fn new(log_folder_path: &OsStr, aggregate_id: Option<&str>) -> Self {
+        Self {
+            sorted_event_sources: event_sources(log_folder_path, aggregate_id)
+                .into_iter()
+                .map(|(_, source)| source)
+                .collect(),
+            unpacked: HashMap::new(),
+        }
+    }
+
+    // None for events that can't be read, which are skipped
+    fn contents(&mut self, source: EventSource) -> Option<String> {
+        match source {
+            EventSource::File(path) => fs::read_to_string(path).ok(),
+            EventSource::Packed { pack, position } => self
+                .unpacked
+                .entry(pack.clone())
+                .or_insert_with(|| read_pack(&pack).unwrap_or_default())
+                .get(position)
+                .cloned(),
+        }
+    }
 
-        event_filenames.make_contiguous().sort_unstable();
+    // None as well for events in a shape only a newer version understands
+    fn event(&mut self, source: EventSource) -> Option<DomainEvent> {
+        from_stored_json(&self.contents(source)?).ok()
+    }
+}
 
-        Self {
-            sorted_event_filenames: event_filenames,
+// Event files win over packed copies of the same event, which only exist
+// while a pack is being written. Packs whose index can't be read, or that
+// are missing their events, e.g. while they're still being synced, are
+// left out until they're complete. Sources come in the order of the names
+// of their events.
+fn event_sources(
+    log_folder_path: &OsStr,
+    aggregate_id: Option<&str>,
+) -> Vec<(String, EventSource)> {
+    let mut sources_by_name: BTreeMap<String, EventSource> = BTreeMap::new();
+
+    for index_path in folder_paths(log_folder_path, "idx") {
+        let pack = index_path.with_extension("pack");
+        let index: Vec<PackIndexEntry> = match fs::read_to_string(&index_path)
+            .ok()
+            .and_then(|index| serde_json::from_str(&index).ok())
+        {
+            Some(index) if pack.exists() => index,
+            _ => continue,
+        };
+        for (position, entry) in index.into_iter().enumerate() {
+            if aggregate_id.is_none_or(|id| id == entry.aggregate_id) {
+                sources_by_name.insert(
+                    entry.name,
+                    EventSource::Packed {
+                        pack: pack.clone(),
+                        position,
+                    },
+                );
+            }
         }
     }
+    for path in event_file_paths(log_folder_path) {
+        sources_by_name.insert(file_name(&path), EventSource::File(path));
+    }
+    let mut sources: Vec<(String, EventSource)> = sources_by_name.into_iter().collect();
+    sources.sort_by_cached_key(|(name, _)| sort_key(name));
+    sources
+}
+
+fn folder_paths(log_folder_path: &OsStr, extension: &str) -> Vec<PathBuf> {
+    fs::read_dir(log_folder_path)
+        .unwrap()
+        .map(|res| res.map(|e| e.path()))
+        .collect::<Result<Vec<_>, std::io::Error>>()
+        .unwrap()
+        .into_iter()
+        .filter(|path| path.extension() == Some(OsStr::new(extension)))
+        .collect()
+}
+
+fn event_file_paths(log_folder_path: &OsStr) -> Vec<PathBuf> {
+    folder_paths(log_folder_path, "json")
+}
+
+// Events are named after when they were created, for the log to be read in
+// order, and after their origin and place in its chain, so that no two events
+// ever share a name, even when created at the same millisecond.
+fn event_file_name(event: &DomainEvent) -> Result<String, EventStoreError> {
+    let timestamp_millis = event
+        .meta
+        .created_at
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_source| EventStoreError::Generic)?
+        .as_millis();
+    Ok(format!(
+        "{}-{}-{}.json",
+        timestamp_millis, event.meta.instance_id, event.meta.sequence
+    ))
+}
+
+// Instance and sequence number of the event a file is named after, unless
+// it was named before those were part of the name.
+fn origin(name: &str) -> Option<(&str, u64)> {
+    let (rest, sequence) = name.strip_suffix(".json")?.rsplit_once('-')?;
+    let (_timestamp, instance_id) = rest.split_once('-')?;
+    Some((instance_id, sequence.parse().ok()?))
+}
+
+// Names order events by when they were created, then by instance and place
+// in its chain, comparing numbers as such, so that the 10th event of a command
+// comes after the 9th. Names from before instances and sequence numbers were
+// part of them keep their old order among themselves.
+fn sort_key(name: &str) -> (u128, String, u64, String) {
+    let timestamp_millis = name
+        .split(['-', '.'])
+        .next()
+        .and_then(|millis| millis.parse().ok())
+        .unwrap_or_default();
+    let (instance_id, sequence) = origin(name).unwrap_or_default();
+    (
+        timestamp_millis,
+        instance_id.to_owned(),
+        sequence,
+        name.to_owned(),
+    )
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap().to_string_lossy().into_owned()
+}
+
+// Packs are written aside and renamed into place, so that rewriting one
+// never leaves it truncated.
+fn write_pack(pack: &Path, contents: &[&String]) -> Result<(), EventStoreError> {
+    let partial_pack = pack.with_extension("pack.partial");
+    let mut encoder = GzEncoder::new(
+        File::create(&partial_pack).map_err(|_source| EventStoreError::Generic)?,
+        Compression::best(),
+    );
+    encoder
+        .write_all(&serde_json::to_vec(contents).unwrap())
+        .map_err(|_source| EventStoreError::Generic)?;
+    encoder
+        .finish()
+        .map_err(|_source| EventStoreError::Generic)?;
+    fs::rename(&partial_pack, pack).map_err(|_source| EventStoreError::Generic)
+}
+
+fn read_pack(pack: &Path) -> Result<Vec<String>, EventStoreError> {
+    let mut contents = vec![];
+    GzDecoder::new(File::open(pack).map_err(|_source| EventStoreError::Generic)?)
+        .read_to_end(&mut contents)
+        .map_err(|_source| EventStoreError::Generic)?;
+    serde_json::from_slice(&contents).map_err(|_source| EventStoreError::Generic)
+}
+
+// The index is what makes a pack visible, so it's written aside and renamed
+// into place, for it to never be seen half-written.
+fn write_index(index_path: &Path, index: &[PackIndexEntry]) -> Result<(), EventStoreError> {
+    let partial_index_path = index_path.with_extension("idx.partial");
+    fs::write(
+        &partial_index_path,
+        serde_json::to_string_pretty(index).unwrap(),
+    )
+    .and_then(|()| fs::rename(&partial_index_path, index_path))
+    .map_err(|_source| EventStoreError::Generic)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::adapters::clock::FakeClock;
+    use crate::adapters::memory_event_store::MemoryEventStore;
+    use crate::domain::compaction::compacted;
     use crate::domain::data::DomainEventMeta;
     use crate::domain::events::{BookmarkEventPayload, DomainEventPayload};
     use crate::ports::Clock;
     use assert_fs::assert::PathAssert;
-    use assert_fs::fixture::PathChild;
+    use assert_fs::fixture::{FileWriteStr, PathChild};
     use assert_fs::TempDir;
     use std::sync::Arc;
     use std::time::{Duration, SystemTime};
@@ -106,31 +531,39 @@ mod tests {
         let temp = TempDir::new().unwrap();
         let log_folder_path = temp.path().as_os_str();
         let clock = Arc::new(FakeClock::new());
-        let event_store = FileSystemEventStore::new(log_folder_path);
+        let event_store = FileSystemEventStore::new(log_folder_path, "laptop");
 
         clock.advance(Duration::from_secs(10));
         let event = DomainEvent {
             meta: DomainEventMeta {
                 aggregate_id: "123".to_owned(),
                 created_at: clock.now(),
+                instance_id: "laptop".to_owned(),
+                sequence: 0,
+                previous_hash: None,
             },
             payload: DomainEventPayload::Bookmark(BookmarkEventPayload::Created {
                 url: "https://example.com".to_owned(),
                 title: "Example".to_owned(),
             }),
+            signature: None,
+            sealed: None,
         };
 
-        event_store.store_event(event).unwrap();
+        event_store.store_events(vec![event], None).unwrap();
 
-        let event_file = temp.child("10000.json");
+        let event_file = temp.child("10000-laptop-1.json");
         event_file.assert(
             r#"{
+  "schema_version": 2,
   "meta": {
     "aggregate_id": "123",
     "created_at": {
       "secs_since_epoch": 10,
       "nanos_since_epoch": 0
-    }
+    },
+    "instance_id": "laptop",
+    "sequence": 1
   },
   "payload": {
     "type": "bookmark",
@@ -145,10 +578,11 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::get_first)]
     fn test_entire_log_of_events_can_be_read_from_disk_on_demand() {
         let temp = TempDir::new().unwrap();
         let log_folder_path = temp.path().as_os_str();
-        let es = FileSystemEventStore::new(log_folder_path);
+        let es = FileSystemEventStore::new(log_folder_path, "laptop");
 
         setup_sample_log(log_folder_path);
 
@@ -159,16 +593,397 @@ mod tests {
             &DomainEvent {
                 meta: DomainEventMeta {
                     aggregate_id: "123".to_owned(),
-                    created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(10)
+                    created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(10),
+                    instance_id: "".to_owned(),
+                    sequence: 0,
+                    previous_hash: None,
                 },
                 payload: DomainEventPayload::Bookmark(BookmarkEventPayload::Created {
                     url: "https://example.com".to_owned(),
                     title: "Example".to_owned()
-                })
+                }),
+                signature: None,
+                sealed: None,
             }
         )
     }
 
+    #[test]
+    fn test_imported_event_does_not_overwrite_local_one() {
+        let temp = TempDir::new().unwrap();
+        let log_folder_path = temp.path().as_os_str();
+        let event_store = FileSystemEventStore::new(log_folder_path, "laptop");
+        let event = |instance_id: &str| DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: "123".to_owned(),
+                created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(10),
+                instance_id: instance_id.to_owned(),
+                sequence: 1,
+                previous_hash: None,
+            },
+            payload: DomainEventPayload::Bookmark(BookmarkEventPayload::Deleted),
+            signature: None,
+            sealed: None,
+        };
+
+        event_store
+            .store_events(vec![event("laptop")], None)
+            .unwrap();
+        event_store.import_event(event("phone")).unwrap();
+
+        temp.child("10000-phone-1.json")
+            .assert(predicates::path::exists());
+        assert_eq!(event_store.events_iter().count(), 2);
+    }
+
+    #[test]
+    fn test_events_stored_at_the_same_millisecond_are_all_kept() {
+        let temp = TempDir::new().unwrap();
+        let event_store = FileSystemEventStore::new(temp.path().as_os_str(), "laptop");
+        let event = |aggregate_id: &str| DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: aggregate_id.to_owned(),
+                created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(10),
+                instance_id: "laptop".to_owned(),
+                sequence: 0,
+                previous_hash: None,
+            },
+            payload: DomainEventPayload::Bookmark(BookmarkEventPayload::Deleted),
+            signature: None,
+            sealed: None,
+        };
+
+        event_store
+            .store_events(vec![event("123")], Some(0))
+            .unwrap();
+        event_store
+            .store_events(vec![event("456")], Some(0))
+            .unwrap();
+
+        assert_eq!(event_store.events_iter().count(), 2);
+    }
+
+    #[test]
+    fn test_events_after_checkpoint_are_told_apart_by_file_name() {
+        let temp = TempDir::new().unwrap();
+        let event_store = FileSystemEventStore::new(temp.path().as_os_str(), "laptop");
+        let event = |seconds| DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: "123".to_owned(),
+                created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(seconds),
+                instance_id: "laptop".to_owned(),
+                sequence: 0,
+                previous_hash: None,
+            },
+            payload: DomainEventPayload::Bookmark(BookmarkEventPayload::Deleted),
+            signature: None,
+            sealed: None,
+        };
+        for seconds in [10, 11, 12] {
+            event_store
+                .store_events(vec![event(seconds)], None)
+                .unwrap();
+        }
+        let mut checkpoint = Checkpoint::default();
+        for event in event_store.events_iter().take(2) {
+            checkpoint.advance(&event);
+        }
+        // Covered events aren't read, so not even a broken file gets in the way
+        temp.child("10000-laptop-1.json").write_str("{").unwrap();
+
+        let events: Vec<DomainEvent> = event_store.events_after(&checkpoint).collect();
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].meta.sequence, 3);
+    }
+
+    #[test]
+    fn test_event_ids_match_those_of_memory_store() {
+        let temp = TempDir::new().unwrap();
+        let event_store = FileSystemEventStore::new(temp.path().as_os_str(), "laptop");
+        let memory_event_store = MemoryEventStore::with_instance_id("laptop");
+
+        setup_sample_log(temp.path().as_os_str());
+        for event in event_store.events_iter() {
+            memory_event_store.import_event(event).unwrap();
+        }
+
+        assert_eq!(event_store.event_ids(), memory_event_store.event_ids());
+        assert_eq!(
+            event_store.event_ids().fingerprint("").count,
+            event_store.events_iter().count()
+        );
+    }
+
+    #[test]
+    fn test_event_ids_follow_events_written_since_they_were_last_read() {
+        let temp = TempDir::new().unwrap();
+        let event_store = FileSystemEventStore::new(temp.path().as_os_str(), "laptop");
+        let other_event_store = FileSystemEventStore::new(temp.path().as_os_str(), "phone");
+        let event = |instance_id: &str, secs: u64| DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: "123".to_owned(),
+                created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+                instance_id: instance_id.to_owned(),
+                sequence: 1,
+                previous_hash: None,
+            },
+            payload: DomainEventPayload::Bookmark(BookmarkEventPayload::Deleted),
+            signature: None,
+            sealed: None,
+        };
+
+        event_store
+            .store_events(vec![event("laptop", 10)], None)
+            .unwrap();
+        assert_eq!(event_store.event_ids().fingerprint("").count, 1);
+
+        other_event_store
+            .store_events(vec![event("phone", 20)], None)
+            .unwrap();
+        event_store
+            .replace_event(compacted(&event("laptop", 10)))
+            .unwrap();
+
+        assert_eq!(
+            event_store.event_ids(),
+            EventIds::from_events(event_store.events_iter())
+        );
+        assert_eq!(event_store.event_ids().fingerprint("").count, 2);
+    }
+
+    #[test]
+    fn test_packed_events_are_read_back_in_order() {
+        let temp = TempDir::new().unwrap();
+        let log_folder_path = temp.path().as_os_str();
+        let event_store = FileSystemEventStore::new(log_folder_path, "laptop");
+        let event = |instance_id: &str, secs: u64| DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: format!("{}", secs),
+                created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+                instance_id: instance_id.to_owned(),
+                sequence: secs,
+                previous_hash: None,
+            },
+            payload: DomainEventPayload::Bookmark(BookmarkEventPayload::Deleted),
+            signature: None,
+            sealed: None,
+        };
+
+        event_store
+            .store_events(vec![event("laptop", 10)], None)
+            .unwrap();
+        event_store.import_event(event("phone", 15)).unwrap();
+        event_store
+            .store_events(vec![event("laptop", 20)], None)
+            .unwrap();
+        event_store
+            .store_events(vec![event("laptop", 30)], None)
+            .unwrap();
+        let events: Vec<DomainEvent> = event_store.events_iter().collect();
+
+        let packed = event_store
+            .pack_events(SystemTime::UNIX_EPOCH + Duration::from_secs(25))
+            .unwrap();
+
+        assert_eq!(packed, 2);
+        temp.child("10000-laptop-10.json")
+            .assert(predicates::path::missing());
+        temp.child("pack-laptop-10000-laptop-10.pack")
+            .assert(predicates::path::exists());
+        assert_eq!(fs::read_dir(log_folder_path).unwrap().count(), 4);
+        assert_eq!(event_store.events_iter().collect::<Vec<_>>(), events);
+        assert_eq!(
+            event_store.get_events_for_aggregate("20"),
+            vec![event("laptop", 20)]
+        );
+    }
+
+    #[test]
+    fn test_incomplete_packs_are_skipped() {
+        let temp = TempDir::new().unwrap();
+        let log_folder_path = temp.path().as_os_str();
+        let event_store = FileSystemEventStore::new(log_folder_path, "laptop");
+        setup_sample_log(log_folder_path);
+        let events: Vec<DomainEvent> = event_store.events_iter().collect();
+
+        temp.child("pack-phone-10000.idx")
+            .write_str(r#"[{"name": "12000.json", "aggregate_id": "123"}]"#)
+            .unwrap();
+        temp.child("pack-phone-20000.idx")
+            .write_str(r#"[{"name": "#)
+            .unwrap();
+        temp.child("pack-phone-20000.pack").write_str("").unwrap();
+
+        assert_eq!(event_store.events_iter().collect::<Vec<_>>(), events);
+    }
+
+    #[test]
+    fn test_unreadable_event_files_are_skipped() {
+        let temp = TempDir::new().unwrap();
+        let log_folder_path = temp.path().as_os_str();
+        let event_store = FileSystemEventStore::new(log_folder_path, "laptop");
+        setup_sample_log(log_folder_path);
+        let events: Vec<DomainEvent> = event_store.events_iter().collect();
+
+        temp.child("10000.sync-conflict-20230131.json")
+            .write_str(r#"{"meta": {"#)
+            .unwrap();
+        temp.child("12000.json")
+            .write_str(r#"{"schema_version": 99, "event": {}}"#)
+            .unwrap();
+
+        assert_eq!(event_store.events_iter().collect::<Vec<_>>(), events);
+        assert_eq!(
+            event_store.event_ids(),
+            EventIds::from_events(events.into_iter())
+        );
+        assert_eq!(event_store.unreadable_files().len(), 2);
+    }
+
+    #[test]
+    fn test_packed_event_is_replaced_within_its_pack() {
+        let temp = TempDir::new().unwrap();
+        let log_folder_path = temp.path().as_os_str();
+        let event_store = FileSystemEventStore::new(log_folder_path, "laptop");
+        let event = |secs: u64| DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: "123".to_owned(),
+                created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+                instance_id: "laptop".to_owned(),
+                sequence: secs,
+                previous_hash: None,
+            },
+            payload: DomainEventPayload::Bookmark(BookmarkEventPayload::Deleted),
+            signature: None,
+            sealed: None,
+        };
+        event_store.store_events(vec![event(10)], None).unwrap();
+        event_store.store_events(vec![event(20)], None).unwrap();
+        event_store
+            .pack_events(SystemTime::UNIX_EPOCH + Duration::from_secs(15))
+            .unwrap();
+
+        let stubs = vec![compacted(&event(10)), compacted(&event(20))];
+        for stub in &stubs {
+            event_store.replace_event(stub.clone()).unwrap();
+        }
+
+        assert_eq!(fs::read_dir(log_folder_path).unwrap().count(), 3);
+        assert_eq!(event_store.get_events_for_aggregate("123"), stubs);
+    }
+
+    #[test]
+    fn test_events_stored_together_are_written_as_one_pack() {
+        let temp = TempDir::new().unwrap();
+        let log_folder_path = temp.path().as_os_str();
+        let event_store = FileSystemEventStore::new(log_folder_path, "laptop");
+        let event = |sequence: u64, payload: BookmarkEventPayload| DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: "123".to_owned(),
+                created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(10),
+                instance_id: "laptop".to_owned(),
+                sequence,
+                previous_hash: None,
+            },
+            payload: DomainEventPayload::Bookmark(payload),
+            signature: None,
+            sealed: None,
+        };
+        let events = vec![
+            event(
+                1,
+                BookmarkEventPayload::Created {
+                    url: "https://example.com".to_owned(),
+                    title: "Example".to_owned(),
+                },
+            ),
+            event(2, BookmarkEventPayload::Deleted),
+        ];
+
+        event_store.store_events(events.clone(), None).unwrap();
+
+        temp.child("pack-laptop-10000-laptop-1.idx")
+            .assert(predicates::path::exists());
+        assert_eq!(fs::read_dir(log_folder_path).unwrap().count(), 2);
+        assert_eq!(event_store.get_events_for_aggregate("123"), events);
+    }
+
+    #[test]
+    fn test_events_of_one_command_are_read_back_in_sequence_past_the_ninth() {
+        let temp = TempDir::new().unwrap();
+        let event_store = FileSystemEventStore::new(temp.path().as_os_str(), "laptop");
+        let event = |instance_id: &str, sequence| DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: "123".to_owned(),
+                created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(10),
+                instance_id: instance_id.to_owned(),
+                sequence,
+                previous_hash: None,
+            },
+            payload: DomainEventPayload::Bookmark(BookmarkEventPayload::TitleUpdated {
+                title: sequence.to_string(),
+            }),
+            signature: None,
+            sealed: None,
+        };
+
+        event_store
+            .store_events((0..12).map(|_| event("laptop", 0)).collect(), None)
+            .unwrap();
+        for sequence in 1..=12 {
+            event_store.import_event(event("phone", sequence)).unwrap();
+        }
+
+        let sequences: Vec<(String, u64)> = event_store
+            .events_iter()
+            .map(|e| (e.meta.instance_id, e.meta.sequence))
+            .collect();
+        let expected: Vec<(String, u64)> = ["laptop", "phone"]
+            .iter()
+            .flat_map(|instance_id| (1..=12).map(|sequence| (instance_id.to_string(), sequence)))
+            .collect();
+        assert_eq!(sequences, expected);
+    }
+
+    #[test]
+    fn test_chain_head_is_found_by_file_name() {
+        let temp = TempDir::new().unwrap();
+        let event_store = FileSystemEventStore::new(temp.path().as_os_str(), "laptop");
+        let event = |instance_id: &str, sequence, secs| DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: "123".to_owned(),
+                created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+                instance_id: instance_id.to_owned(),
+                sequence,
+                previous_hash: None,
+            },
+            payload: DomainEventPayload::Bookmark(BookmarkEventPayload::TitleUpdated {
+                title: sequence.to_string(),
+            }),
+            signature: None,
+            sealed: None,
+        };
+
+        event_store
+            .store_events((0..12).map(|_| event("laptop", 0, 10)).collect(), None)
+            .unwrap();
+        event_store.import_event(event("phone", 20, 20)).unwrap();
+        event_store
+            .store_events(vec![event("laptop", 0, 30)], None)
+            .unwrap();
+
+        let head = event_store.chain_head().unwrap();
+        assert_eq!(head.meta.sequence, 13);
+        assert_eq!(Some(head), chain::head("laptop", event_store.events_iter()));
+        assert!(chain::verify_chains(
+            event_store
+                .events_iter()
+                .filter(|e| e.meta.instance_id == "laptop")
+        )
+        .is_empty());
+    }
+
     fn setup_sample_log(log_folder_path: &OsStr) {
         std::fs::write(
             Path::new(log_folder_path).join("10000.json"),
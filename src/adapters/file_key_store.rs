@@ -0,0 +1,91 @@
+use crate::ports::{AggregateKey, KeyStore, KeyStoreError};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+// Keeps bookmark keys in a file next to (not inside) the synced log folder,
+// along with the IDs of bookmarks whose key was destroyed.
+pub struct FileKeyStore {
+    path: PathBuf,
+    keys: Mutex<StoredKeys>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct StoredKeys {
+    keys: BTreeMap<String, String>,
+    destroyed: BTreeSet<String>,
+}
+
+impl FileKeyStore {
+    pub fn new(path: &Path) -> Self {
+        let keys = fs::read_to_string(path)
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default();
+
+        Self {
+            path: path.to_owned(),
+            keys: Mutex::new(keys),
+        }
+    }
+
+    fn save(&self, keys: &StoredKeys) -> Result<(), KeyStoreError> {
+        fs::write(&self.path, serde_json::to_string_pretty(keys).unwrap())
+            .map_err(|_source| KeyStoreError::Generic)
+    }
+}
+
+impl KeyStore for FileKeyStore {
+    fn key(&self, aggregate_id: &str) -> Option<AggregateKey> {
+        let keys = self.keys.lock().unwrap();
+        hex::decode(keys.keys.get(aggregate_id)?)
+            .ok()?
+            .try_into()
+            .ok()
+    }
+
+    fn save_key(&self, aggregate_id: &str, key: AggregateKey) -> Result<(), KeyStoreError> {
+        let mut keys = self.keys.lock().unwrap();
+        if keys.destroyed.contains(aggregate_id) {
+            return Err(KeyStoreError::Destroyed);
+        }
+        keys.keys.insert(aggregate_id.to_owned(), hex::encode(key));
+        self.save(&keys)
+    }
+
+    fn destroy_key(&self, aggregate_id: &str) -> Result<(), KeyStoreError> {
+        let mut keys = self.keys.lock().unwrap();
+        keys.keys.remove(aggregate_id);
+        keys.destroyed.insert(aggregate_id.to_owned());
+        self.save(&keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::{fixture::PathChild, TempDir};
+
+    #[test]
+    fn test_destroyed_key_stays_destroyed_across_restarts() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.child("keys.json");
+        let key_store = FileKeyStore::new(path.path());
+        key_store.save_key("123", [7; 32]).unwrap();
+        key_store.save_key("456", [8; 32]).unwrap();
+
+        key_store.destroy_key("123").unwrap();
+
+        let key_store = FileKeyStore::new(path.path());
+        assert_eq!(key_store.key("123"), None);
+        assert_eq!(key_store.key("456"), Some([8; 32]));
+        assert_eq!(
+            key_store.save_key("123", [7; 32]),
+            Err(KeyStoreError::Destroyed)
+        );
+    }
+}
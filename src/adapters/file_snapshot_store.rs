@@ -0,0 +1,110 @@
+use crate::{
+    domain::{data::BookmarkSnapshot, revocations::Revocations},
+    ports::SnapshotStore,
+};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+// Keeps snapshots in a file next to (not inside) the synced log folder, for
+// them to save work across restarts too. They're only as good as the log they
+// were taken from, so they're never synced. Revocations are cheap enough to
+// work out once per run, so they're only kept in memory.
+pub struct FileSnapshotStore {
+    path: PathBuf,
+    snapshots_by_id: Mutex<BTreeMap<String, BookmarkSnapshot>>,
+    revocations: Mutex<Option<Revocations>>,
+}
+
+impl FileSnapshotStore {
+    pub fn new(path: &Path) -> Self {
+        let snapshots_by_id = fs::read_to_string(path)
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default();
+
+        Self {
+            path: path.to_owned(),
+            snapshots_by_id: Mutex::new(snapshots_by_id),
+            revocations: Mutex::new(None),
+        }
+    }
+
+    // Failing to write only means snapshots are taken again next run
+    fn write(&self, snapshots_by_id: &BTreeMap<String, BookmarkSnapshot>) {
+        let _ = fs::write(&self.path, serde_json::to_vec(snapshots_by_id).unwrap());
+    }
+}
+
+impl SnapshotStore for FileSnapshotStore {
+    fn load(&self, aggregate_id: &str) -> Option<BookmarkSnapshot> {
+        self.snapshots_by_id
+            .lock()
+            .unwrap()
+            .get(aggregate_id)
+            .cloned()
+    }
+
+    fn save(&self, snapshot: BookmarkSnapshot) {
+        let mut snapshots_by_id = self.snapshots_by_id.lock().unwrap();
+        snapshots_by_id.insert(snapshot.aggregate.id.clone(), snapshot);
+        self.write(&snapshots_by_id);
+    }
+
+    fn invalidate(&self, aggregate_id: &str) {
+        let mut snapshots_by_id = self.snapshots_by_id.lock().unwrap();
+        if snapshots_by_id.remove(aggregate_id).is_some() {
+            self.write(&snapshots_by_id);
+        }
+    }
+
+    fn clear(&self) {
+        let mut snapshots_by_id = self.snapshots_by_id.lock().unwrap();
+        if !snapshots_by_id.is_empty() {
+            snapshots_by_id.clear();
+            self.write(&snapshots_by_id);
+        }
+        *self.revocations.lock().unwrap() = None;
+    }
+
+    fn revocations(&self) -> Option<Revocations> {
+        self.revocations.lock().unwrap().clone()
+    }
+
+    fn save_revocations(&self, revocations: Revocations) {
+        *self.revocations.lock().unwrap() = Some(revocations);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{aggregates::BookmarkAggregate, data::Aggregate};
+    use assert_fs::{fixture::PathChild, TempDir};
+
+    #[test]
+    fn test_snapshots_are_kept_across_restarts() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.child("snapshots.json");
+        let snapshot = |id: &str| BookmarkSnapshot {
+            aggregate: BookmarkAggregate::new(id),
+            covered_events: 20,
+            last_event_id: "abc".to_owned(),
+        };
+        let snapshot_store = FileSnapshotStore::new(path.path());
+        snapshot_store.save(snapshot("123"));
+        snapshot_store.save(snapshot("456"));
+        snapshot_store.invalidate("456");
+
+        let snapshot_store = FileSnapshotStore::new(path.path());
+
+        assert_eq!(
+            snapshot_store.load("123").map(|s| s.last_event_id),
+            Some("abc".to_owned())
+        );
+        assert!(snapshot_store.load("456").is_none());
+    }
+}
@@ -1,17 +1,33 @@
-use crate::{app, domain::errors::DomainError, ports};
+use crate::{
+    app,
+    domain::{
+        aggregates::BookmarkAggregate,
+        commands::BookmarkCommand,
+        data::{DomainEvent, InstanceSelection},
+        errors::DomainError,
+        reconciliation::RangeFingerprint,
+    },
+    ports,
+};
 use axum::{
-    body::{self, Full},
-    extract::{Path, State},
-    http::StatusCode,
+    body::{self, Body, Full},
+    extract::{Path, Query, State},
+    http::{Request, StatusCode},
+    middleware::{self, Next},
     response::IntoResponse,
     response::Response,
     routing::{delete, get, post, put},
     Json, Router,
 };
-use hyper::header;
+use hyper::{header, HeaderMap};
 use rust_embed::RustEmbed;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 use uuid::Uuid;
 
 #[derive(RustEmbed)]
@@ -21,18 +37,30 @@ struct Asset;
 struct ServiceDependencies {
     clock: Arc<dyn ports::Clock>,
     event_store: Arc<dyn ports::EventStore>,
+    snapshot_store: Arc<dyn ports::SnapshotStore>,
+    key_store: Arc<dyn ports::KeyStore>,
     read_model: Arc<dyn ports::ReadModel>,
 }
 
+struct PeerAuthentication {
+    clock: Arc<dyn ports::Clock>,
+    peer_authenticator: Arc<dyn ports::PeerAuthenticator>,
+}
+
+// The local UI and API, for this device only: nothing here is authenticated.
 pub fn create_router(
     event_store: Arc<dyn ports::EventStore>,
+    snapshot_store: Arc<dyn ports::SnapshotStore>,
+    key_store: Arc<dyn ports::KeyStore>,
     read_model: Arc<dyn ports::ReadModel>,
     clock: Arc<dyn ports::Clock>,
 ) -> Router {
     let deps = Arc::new(ServiceDependencies {
-        event_store: event_store.clone(),
-        read_model: read_model.clone(),
-        clock: clock.clone(),
+        event_store,
+        snapshot_store,
+        key_store,
+        read_model,
+        clock,
     });
 
     Router::new()
@@ -42,9 +70,119 @@ pub fn create_router(
         .route("/api/bookmarks/:id", get(read_bookmark))
         .route("/api/bookmarks/:id", delete(delete_bookmark))
         .route("/api/bookmarks/:id/title", put(update_bookmark_title))
+        .route("/api/bookmarks/:id/url", put(update_bookmark_url))
+        .route("/api/bookmarks/:id/note", put(update_bookmark_note))
+        .route("/api/bookmarks/:id/history", get(read_bookmark_history))
+        .route("/api/bookmarks/:id/forget", post(forget_bookmark))
+        .route("/api/preview/bookmarks", get(preview_bookmarks))
+        .route("/api/trash", get(read_trash))
+        .route("/api/trash/:id/restore", post(restore_bookmark))
+        .route("/api/status/chains", get(read_chain_status))
+        .route("/api/sync/peers", get(read_sync_peers))
         .with_state(deps)
 }
 
+// What other instances sync with, served apart from the local API so that it
+// can be exposed to the network on its own. They hand out events and keys, so
+// every request must be signed by a trusted instance.
+pub fn create_peer_router(
+    event_store: Arc<dyn ports::EventStore>,
+    snapshot_store: Arc<dyn ports::SnapshotStore>,
+    key_store: Arc<dyn ports::KeyStore>,
+    read_model: Arc<dyn ports::ReadModel>,
+    peer_authenticator: Arc<dyn ports::PeerAuthenticator>,
+    clock: Arc<dyn ports::Clock>,
+) -> Router {
+    let deps = Arc::new(ServiceDependencies {
+        event_store,
+        snapshot_store,
+        key_store,
+        read_model,
+        clock: clock.clone(),
+    });
+    let authentication = Arc::new(PeerAuthentication {
+        clock,
+        peer_authenticator,
+    });
+
+    Router::new()
+        .route("/api/sync/ranges", post(read_event_ranges))
+        .route("/api/sync/events/by-id", post(read_events_by_id))
+        .route("/api/sync/events", post(import_events))
+        .route("/api/sync/keys/missing", get(read_sealed_aggregates))
+        .route("/api/sync/keys/by-aggregate", post(read_keys))
+        .route("/api/sync/keys", post(import_keys))
+        .route_layer(middleware::from_fn_with_state(
+            authentication,
+            authenticate_peer,
+        ))
+        .with_state(deps)
+}
+
+pub const PEER_INSTANCE_HEADER: &str = "x-decentrasync-instance";
+pub const PEER_TIMESTAMP_HEADER: &str = "x-decentrasync-timestamp";
+pub const PEER_SIGNATURE_HEADER: &str = "x-decentrasync-signature";
+
+// Requests older or newer than this are refused, so that a captured request
+// can't be replayed later on.
+const PEER_REQUEST_MAX_AGE: Duration = Duration::from_secs(5 * 60);
+
+// What a peer signs: the method, the path, when the request was made (in
+// seconds since the epoch) and the body.
+pub fn peer_request_message(method: &str, path: &str, timestamp: u64, body: &[u8]) -> Vec<u8> {
+    let mut message = format!("{}\n{}\n{}\n", method, path, timestamp).into_bytes();
+    message.extend_from_slice(body);
+    message
+}
+
+async fn authenticate_peer(
+    State(state): State<Arc<PeerAuthentication>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let (parts, body) = request.into_parts();
+    let header = |name: &str| {
+        parts
+            .headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_owned())
+    };
+    let (instance_id, timestamp, signature) = match (
+        header(PEER_INSTANCE_HEADER),
+        header(PEER_TIMESTAMP_HEADER).and_then(|t| t.parse::<u64>().ok()),
+        header(PEER_SIGNATURE_HEADER),
+    ) {
+        (Some(instance_id), Some(timestamp), Some(signature)) => {
+            (instance_id, timestamp, signature)
+        }
+        _ => return (StatusCode::UNAUTHORIZED).into_response(),
+    };
+
+    let now = state
+        .clock
+        .now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    if now.abs_diff(Duration::from_secs(timestamp)) > PEER_REQUEST_MAX_AGE {
+        return (StatusCode::UNAUTHORIZED).into_response();
+    }
+
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(_) => return (StatusCode::BAD_REQUEST).into_response(),
+    };
+    let message = peer_request_message(parts.method.as_str(), parts.uri.path(), timestamp, &body);
+    if !state
+        .peer_authenticator
+        .verify(&instance_id, &message, &signature)
+    {
+        return (StatusCode::UNAUTHORIZED).into_response();
+    }
+
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
 async fn root(State(_state): State<Arc<ServiceDependencies>>) -> impl IntoResponse {
     if let Some(asset) = Asset::get("index.html") {
         Response::builder()
@@ -57,6 +195,11 @@ async fn root(State(_state): State<Arc<ServiceDependencies>>) -> impl IntoRespon
     }
 }
 
+#[derive(Deserialize)]
+struct ReadBookmarksQuery {
+    as_of: Option<String>,
+}
+
 #[derive(Serialize)]
 struct ReadBookmarksResponse {
     bookmarks: Vec<ReadBookmarksResponseBookmarkEntry>,
@@ -68,8 +211,56 @@ struct ReadBookmarksResponseBookmarkEntry {
     title: String,
 }
 
-async fn read_bookmarks(State(state): State<Arc<ServiceDependencies>>) -> impl IntoResponse {
-    match app::read_bookmarks(state.read_model.clone()) {
+async fn read_bookmarks(
+    State(state): State<Arc<ServiceDependencies>>,
+    Query(query): Query<ReadBookmarksQuery>,
+) -> impl IntoResponse {
+    let bookmarks = match query.as_of {
+        Some(as_of) => match OffsetDateTime::parse(&as_of, &Rfc3339) {
+            Ok(as_of) => app::read_bookmarks_as_of(as_of.into(), state.event_store.clone()),
+            Err(_) => return (StatusCode::BAD_REQUEST).into_response(),
+        },
+        None => app::read_bookmarks(state.read_model.clone()),
+    };
+
+    match bookmarks {
+        Some(bookmarks) => (
+            StatusCode::OK,
+            Json(ReadBookmarksResponse {
+                bookmarks: bookmarks
+                    .iter()
+                    .map(|b| ReadBookmarksResponseBookmarkEntry {
+                        id: b.id.clone(),
+                        url: b.url.clone(),
+                        title: b.title.clone(),
+                    })
+                    .collect(),
+            }),
+        )
+            .into_response(),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct PreviewBookmarksQuery {
+    excluding: Option<String>,
+    only: Option<String>,
+}
+
+// Comma-separated instance IDs, e.g. `?excluding=abc,def`.
+async fn preview_bookmarks(
+    State(state): State<Arc<ServiceDependencies>>,
+    Query(query): Query<PreviewBookmarksQuery>,
+) -> impl IntoResponse {
+    let split = |ids: String| ids.split(',').map(|id| id.to_owned()).collect();
+    let selection = match (query.excluding, query.only) {
+        (Some(ids), None) => InstanceSelection::Excluding(split(ids)),
+        (None, Some(ids)) => InstanceSelection::Only(split(ids)),
+        _ => return (StatusCode::BAD_REQUEST).into_response(),
+    };
+
+    match app::read_bookmarks_from_instances(&selection, state.event_store.clone()) {
         Some(bookmarks) => (
             StatusCode::OK,
             Json(ReadBookmarksResponse {
@@ -92,6 +283,8 @@ async fn read_bookmarks(State(state): State<Arc<ServiceDependencies>>) -> impl I
 struct CreateBookmarkRequestPayload {
     url: String,
     title: String,
+    #[serde(default)]
+    id: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -99,17 +292,43 @@ struct CreateBoomarkResponsePayload {
     id: String,
 }
 
+// Bookmarks created under the same `Idempotency-Key` get the same ID, so a
+// retried request finds the bookmark it created the first time around.
+const IDEMPOTENCY_KEY_NAMESPACE: Uuid = Uuid::from_u128(0x6f1d_4b2e_9a3c_4e58_b7d0_2c91_f5a8_e364);
+
+// IDs chosen by clients must be UUIDs, like those generated here.
+fn bookmark_id(headers: &HeaderMap, requested_id: Option<String>) -> Result<String, StatusCode> {
+    match headers.get("Idempotency-Key").map(|value| value.to_str()) {
+        Some(Ok(key)) => Ok(Uuid::new_v5(&IDEMPOTENCY_KEY_NAMESPACE, key.as_bytes()).to_string()),
+        Some(Err(_)) => Err(StatusCode::BAD_REQUEST),
+        None => match requested_id {
+            Some(id) => Uuid::parse_str(&id)
+                .map(|id| id.to_string())
+                .map_err(|_source| StatusCode::BAD_REQUEST),
+            None => Ok(Uuid::new_v4().to_string()),
+        },
+    }
+}
+
 async fn create_bookmark(
     State(state): State<Arc<ServiceDependencies>>,
+    headers: HeaderMap,
     Json(payload): Json<CreateBookmarkRequestPayload>,
 ) -> impl IntoResponse {
-    let id = Uuid::new_v4().to_string();
+    let id = match bookmark_id(&headers, payload.id) {
+        Ok(id) => id,
+        Err(status) => return status.into_response(),
+    };
 
-    match app::create_bookmark(
+    match app::dispatch::<BookmarkAggregate>(
         &id,
-        &payload.url,
-        &payload.title,
+        BookmarkCommand::BookmarkPage {
+            url: payload.url.clone(),
+            title: payload.title.clone(),
+        },
+        None,
         state.event_store.clone(),
+        state.snapshot_store.clone(),
         state.read_model.clone(),
         state.clock.clone(),
     ) {
@@ -118,10 +337,55 @@ async fn create_bookmark(
             Json(CreateBoomarkResponsePayload { id }),
         )
             .into_response(),
+        // A repeated request gets the original response, as long as it's
+        // for the same page, even if the bookmark was changed since.
+        Err(DomainError::BookmarkAlreadyExists) | Err(DomainError::NoSuchBookmark)
+            if app::was_bookmarked_as(
+                &id,
+                &payload.url,
+                &payload.title,
+                state.event_store.clone(),
+            ) =>
+        {
+            (
+                StatusCode::CREATED,
+                Json(CreateBoomarkResponsePayload { id }),
+            )
+                .into_response()
+        }
+        Err(DomainError::BookmarkAlreadyExists) | Err(DomainError::NoSuchBookmark) => {
+            (StatusCode::CONFLICT).into_response()
+        }
         _ => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
     }
 }
 
+// Bookmark versions are exposed as ETags, which clients can send back in
+// `If-Match` to only change a bookmark if it's still as they've seen it.
+fn etag(version: u64) -> String {
+    format!("\"{}\"", version)
+}
+
+fn if_match(headers: &HeaderMap) -> Result<Option<u64>, StatusCode> {
+    match headers.get(header::IF_MATCH).map(|value| value.to_str()) {
+        None | Some(Ok("*")) => Ok(None),
+        Some(Ok(value)) => value
+            .trim_matches('"')
+            .parse()
+            .map(Some)
+            .map_err(|_source| StatusCode::PRECONDITION_FAILED),
+        Some(Err(_)) => Err(StatusCode::PRECONDITION_FAILED),
+    }
+}
+
+// Without `If-Match`, the change was retried but kept conflicting.
+fn conflict(expected_version: Option<u64>) -> StatusCode {
+    match expected_version {
+        Some(_) => StatusCode::PRECONDITION_FAILED,
+        None => StatusCode::CONFLICT,
+    }
+}
+
 #[derive(Deserialize)]
 struct UpdateBookmarkTitleRequestPayload {
     title: String,
@@ -130,17 +394,95 @@ struct UpdateBookmarkTitleRequestPayload {
 async fn update_bookmark_title(
     State(state): State<Arc<ServiceDependencies>>,
     Path(id): Path<String>,
+    headers: HeaderMap,
     Json(payload): Json<UpdateBookmarkTitleRequestPayload>,
 ) -> impl IntoResponse {
-    match app::update_bookmark_title(
+    let expected_version = match if_match(&headers) {
+        Ok(expected_version) => expected_version,
+        Err(status) => return status.into_response(),
+    };
+
+    match app::dispatch::<BookmarkAggregate>(
         &id,
-        &payload.title,
+        BookmarkCommand::UpdateTitle {
+            title: payload.title,
+        },
+        expected_version,
         state.event_store.clone(),
+        state.snapshot_store.clone(),
         state.read_model.clone(),
         state.clock.clone(),
     ) {
         Ok(()) => (StatusCode::NO_CONTENT, ()).into_response(),
         Err(DomainError::NoSuchBookmark) => (StatusCode::NOT_FOUND).into_response(),
+        Err(DomainError::Conflict) => conflict(expected_version).into_response(),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct UpdateBookmarkUrlRequestPayload {
+    url: String,
+}
+
+async fn update_bookmark_url(
+    State(state): State<Arc<ServiceDependencies>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<UpdateBookmarkUrlRequestPayload>,
+) -> impl IntoResponse {
+    let expected_version = match if_match(&headers) {
+        Ok(expected_version) => expected_version,
+        Err(status) => return status.into_response(),
+    };
+
+    match app::dispatch::<BookmarkAggregate>(
+        &id,
+        BookmarkCommand::UpdateUrl { url: payload.url },
+        expected_version,
+        state.event_store.clone(),
+        state.snapshot_store.clone(),
+        state.read_model.clone(),
+        state.clock.clone(),
+    ) {
+        Ok(()) => (StatusCode::NO_CONTENT, ()).into_response(),
+        Err(DomainError::NoSuchBookmark) => (StatusCode::NOT_FOUND).into_response(),
+        Err(DomainError::Conflict) => conflict(expected_version).into_response(),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct UpdateBookmarkNoteRequestPayload {
+    note: String,
+}
+
+async fn update_bookmark_note(
+    State(state): State<Arc<ServiceDependencies>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<UpdateBookmarkNoteRequestPayload>,
+) -> impl IntoResponse {
+    let expected_version = match if_match(&headers) {
+        Ok(expected_version) => expected_version,
+        Err(status) => return status.into_response(),
+    };
+
+    match app::dispatch::<BookmarkAggregate>(
+        &id,
+        BookmarkCommand::EditNote {
+            note: payload.note,
+            edit_id: Uuid::new_v4().to_string(),
+        },
+        expected_version,
+        state.event_store.clone(),
+        state.snapshot_store.clone(),
+        state.read_model.clone(),
+        state.clock.clone(),
+    ) {
+        Ok(()) => (StatusCode::NO_CONTENT, ()).into_response(),
+        Err(DomainError::NoSuchBookmark) => (StatusCode::NOT_FOUND).into_response(),
+        Err(DomainError::Conflict) => conflict(expected_version).into_response(),
         _ => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
     }
 }
@@ -148,24 +490,335 @@ async fn update_bookmark_title(
 async fn delete_bookmark(
     State(state): State<Arc<ServiceDependencies>>,
     Path(id): Path<String>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    match app::delete_bookmark(
+    let expected_version = match if_match(&headers) {
+        Ok(expected_version) => expected_version,
+        Err(status) => return status.into_response(),
+    };
+
+    match app::dispatch::<BookmarkAggregate>(
         &id,
+        BookmarkCommand::Delete,
+        expected_version,
         state.event_store.clone(),
+        state.snapshot_store.clone(),
         state.read_model.clone(),
         state.clock.clone(),
     ) {
         Ok(()) => (StatusCode::OK, ()).into_response(),
         Err(DomainError::NoSuchBookmark) => (StatusCode::NO_CONTENT).into_response(),
+        Err(DomainError::Conflict) => conflict(expected_version).into_response(),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+async fn forget_bookmark(
+    State(state): State<Arc<ServiceDependencies>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let expected_version = match if_match(&headers) {
+        Ok(expected_version) => expected_version,
+        Err(status) => return status.into_response(),
+    };
+
+    match app::dispatch::<BookmarkAggregate>(
+        &id,
+        BookmarkCommand::Forget,
+        expected_version,
+        state.event_store.clone(),
+        state.snapshot_store.clone(),
+        state.read_model.clone(),
+        state.clock.clone(),
+    ) {
+        Ok(()) => (StatusCode::OK, ()).into_response(),
+        Err(DomainError::NoSuchBookmark) => (StatusCode::NOT_FOUND).into_response(),
+        Err(DomainError::Conflict) => conflict(expected_version).into_response(),
         _ => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
     }
 }
 
+#[derive(Serialize)]
+struct ReadBookmarkHistoryResponse {
+    events: Vec<ReadBookmarkHistoryResponseEventEntry>,
+}
+#[derive(Serialize)]
+struct ReadBookmarkHistoryResponseEventEntry {
+    created_at: String,
+    instance_id: String,
+    change: String,
+}
+
+async fn read_bookmark_history(
+    State(state): State<Arc<ServiceDependencies>>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match app::read_bookmark_history(&id, state.event_store.clone()) {
+        Some(history) => (
+            StatusCode::OK,
+            Json(ReadBookmarkHistoryResponse {
+                events: history
+                    .iter()
+                    .map(|h| ReadBookmarkHistoryResponseEventEntry {
+                        created_at: OffsetDateTime::from(h.created_at).format(&Rfc3339).unwrap(),
+                        instance_id: h.instance_id.clone(),
+                        change: h.change.clone(),
+                    })
+                    .collect(),
+            }),
+        )
+            .into_response(),
+        _ => (StatusCode::NOT_FOUND, ()).into_response(),
+    }
+}
+
+#[derive(Serialize)]
+struct ReadTrashResponse {
+    bookmarks: Vec<ReadTrashResponseBookmarkEntry>,
+}
+#[derive(Serialize)]
+struct ReadTrashResponseBookmarkEntry {
+    id: String,
+    url: String,
+    title: String,
+    deleted_at: String,
+}
+
+async fn read_trash(State(state): State<Arc<ServiceDependencies>>) -> impl IntoResponse {
+    match app::read_trash(state.read_model.clone()) {
+        Some(trash) => (
+            StatusCode::OK,
+            Json(ReadTrashResponse {
+                bookmarks: trash
+                    .iter()
+                    .map(|t| ReadTrashResponseBookmarkEntry {
+                        id: t.bookmark.id.clone(),
+                        url: t.bookmark.url.clone(),
+                        title: t.bookmark.title.clone(),
+                        deleted_at: OffsetDateTime::from(t.deleted_at).format(&Rfc3339).unwrap(),
+                    })
+                    .collect(),
+            }),
+        )
+            .into_response(),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+async fn restore_bookmark(
+    State(state): State<Arc<ServiceDependencies>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let expected_version = match if_match(&headers) {
+        Ok(expected_version) => expected_version,
+        Err(status) => return status.into_response(),
+    };
+
+    match app::dispatch::<BookmarkAggregate>(
+        &id,
+        BookmarkCommand::Restore,
+        expected_version,
+        state.event_store.clone(),
+        state.snapshot_store.clone(),
+        state.read_model.clone(),
+        state.clock.clone(),
+    ) {
+        Ok(()) => (StatusCode::NO_CONTENT, ()).into_response(),
+        Err(DomainError::NoSuchBookmark) => (StatusCode::NOT_FOUND).into_response(),
+        Err(DomainError::BookmarkNotInTrash) => (StatusCode::CONFLICT).into_response(),
+        Err(DomainError::Conflict) => conflict(expected_version).into_response(),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+#[derive(Serialize)]
+struct ReadChainStatusResponse {
+    intact: bool,
+    issues: Vec<String>,
+}
+
+async fn read_chain_status(State(state): State<Arc<ServiceDependencies>>) -> impl IntoResponse {
+    let issues = app::verify_chains(state.event_store.clone());
+    (
+        StatusCode::OK,
+        Json(ReadChainStatusResponse {
+            intact: issues.is_empty(),
+            issues: issues.iter().map(|i| i.to_string()).collect(),
+        }),
+    )
+}
+
+#[derive(Serialize)]
+struct ReadSyncPeersResponse {
+    peers: Vec<ReadSyncPeersResponsePeerEntry>,
+}
+
+#[derive(Serialize)]
+struct ReadSyncPeersResponsePeerEntry {
+    instance_id: String,
+    up_to_sequence: u64,
+    missing: u64,
+    gaps: Vec<ReadSyncPeersResponseGapEntry>,
+    last_seen_at: String,
+    unknown_events: u64,
+}
+
+#[derive(Serialize)]
+struct ReadSyncPeersResponseGapEntry {
+    from: u64,
+    to: u64,
+}
+
+async fn read_sync_peers(State(state): State<Arc<ServiceDependencies>>) -> impl IntoResponse {
+    match app::read_sync_peers(state.event_store.clone(), state.read_model.clone()) {
+        Some(peers) => (
+            StatusCode::OK,
+            Json(ReadSyncPeersResponse {
+                peers: peers
+                    .iter()
+                    .map(|p| ReadSyncPeersResponsePeerEntry {
+                        instance_id: p.instance_id.clone(),
+                        up_to_sequence: p.up_to_sequence,
+                        missing: p.missing(),
+                        gaps: p
+                            .gaps
+                            .iter()
+                            .map(|(from, to)| ReadSyncPeersResponseGapEntry {
+                                from: *from,
+                                to: *to,
+                            })
+                            .collect(),
+                        last_seen_at: OffsetDateTime::from(p.last_seen_at)
+                            .format(&Rfc3339)
+                            .unwrap(),
+                        unknown_events: p.unknown_events,
+                    })
+                    .collect(),
+            }),
+        )
+            .into_response(),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SyncRangesRequestPayload {
+    pub prefixes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SyncRangesResponsePayload {
+    pub ranges: Vec<RangeFingerprint>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SyncEventIdsPayload {
+    pub ids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SyncEventsPayload {
+    pub events: Vec<DomainEvent>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SyncAggregateIdsPayload {
+    pub aggregate_ids: Vec<String>,
+}
+
+// Hex-encoded keys by bookmark ID
+#[derive(Serialize, Deserialize)]
+pub struct SyncKeysPayload {
+    pub keys: BTreeMap<String, String>,
+}
+
+async fn read_event_ranges(
+    State(state): State<Arc<ServiceDependencies>>,
+    Json(payload): Json<SyncRangesRequestPayload>,
+) -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        Json(SyncRangesResponsePayload {
+            ranges: app::read_event_ranges(&payload.prefixes, state.event_store.clone()),
+        }),
+    )
+}
+
+async fn read_events_by_id(
+    State(state): State<Arc<ServiceDependencies>>,
+    Json(payload): Json<SyncEventIdsPayload>,
+) -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        Json(SyncEventsPayload {
+            events: app::read_events_by_id(&payload.ids, state.event_store.clone()),
+        }),
+    )
+}
+
+// Events that fail to import, e.g. because they're signed by an instance
+// that isn't trusted here, are skipped rather than failing the whole batch.
+async fn import_events(
+    State(state): State<Arc<ServiceDependencies>>,
+    Json(payload): Json<SyncEventsPayload>,
+) -> impl IntoResponse {
+    app::import_events(
+        payload.events,
+        state.event_store.clone(),
+        state.snapshot_store.clone(),
+        state.read_model.clone(),
+    );
+    (StatusCode::NO_CONTENT, ())
+}
+
+async fn read_sealed_aggregates(
+    State(state): State<Arc<ServiceDependencies>>,
+) -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        Json(SyncAggregateIdsPayload {
+            aggregate_ids: app::read_sealed_aggregates(state.event_store.clone()),
+        }),
+    )
+}
+
+async fn read_keys(
+    State(state): State<Arc<ServiceDependencies>>,
+    Json(payload): Json<SyncAggregateIdsPayload>,
+) -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        Json(SyncKeysPayload {
+            keys: app::export_keys(&payload.aggregate_ids, state.key_store.clone()),
+        }),
+    )
+}
+
+async fn import_keys(
+    State(state): State<Arc<ServiceDependencies>>,
+    Json(payload): Json<SyncKeysPayload>,
+) -> impl IntoResponse {
+    match app::import_keys(
+        &payload.keys,
+        state.event_store.clone(),
+        state.snapshot_store.clone(),
+        state.key_store.clone(),
+        state.read_model.clone(),
+    ) {
+        Ok(_) => (StatusCode::NO_CONTENT).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
 #[derive(Serialize)]
 struct ReadBookmarkResponsePayload {
     id: String,
     url: String,
     title: String,
+    previous_urls: Vec<String>,
+    note: String,
 }
 
 async fn read_bookmark(
@@ -175,13 +828,196 @@ async fn read_bookmark(
     match app::read_bookmark(&id, state.read_model.clone()) {
         Some(bookmark) => (
             StatusCode::OK,
+            [(
+                header::ETAG,
+                etag(app::read_bookmark_version(&id, state.event_store.clone())),
+            )],
             Json(ReadBookmarkResponsePayload {
                 id: bookmark.id,
                 url: bookmark.url,
                 title: bookmark.title,
+                previous_urls: bookmark.previous_urls,
+                note: bookmark.note,
             }),
         )
             .into_response(),
         _ => (StatusCode::NOT_FOUND, ()).into_response(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::{
+        clock::FakeClock, memory_event_store::MemoryEventStore, memory_key_store::MemoryKeyStore,
+        memory_read_model::MemoryReadModel, memory_snapshot_store::MemorySnapshotStore,
+        signed_event_store::SignedEventStore,
+    };
+    use ed25519_dalek::SigningKey;
+    use hyper::{Client, Method};
+    use rand_core::OsRng;
+    use std::{
+        collections::HashMap,
+        net::{SocketAddr, TcpListener},
+    };
+
+    fn serve() -> String {
+        let (router, _) = routers();
+        serve_router(router)
+    }
+
+    fn routers() -> (Router, Router) {
+        let event_store = Arc::new(SignedEventStore::new(
+            Arc::new(MemoryEventStore::new()),
+            SigningKey::generate(&mut OsRng),
+            HashMap::new(),
+        ));
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let key_store = Arc::new(MemoryKeyStore::new());
+        let read_model = Arc::new(MemoryReadModel::new());
+        let clock = Arc::new(FakeClock::new());
+        (
+            create_router(
+                event_store.clone(),
+                snapshot_store.clone(),
+                key_store.clone(),
+                read_model.clone(),
+                clock.clone(),
+            ),
+            create_peer_router(
+                event_store.clone(),
+                snapshot_store,
+                key_store,
+                read_model,
+                event_store,
+                clock,
+            ),
+        )
+    }
+
+    fn serve_router(router: Router) -> String {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(router.into_make_service()),
+        );
+        format!("http://{}", addr)
+    }
+
+    async fn send(
+        method: Method,
+        url: String,
+        headers: &[(&str, &str)],
+        body: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        let mut request = hyper::Request::builder()
+            .method(method)
+            .uri(url)
+            .header(header::CONTENT_TYPE, "application/json");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let response = Client::new()
+            .request(request.body(hyper::Body::from(body.to_string())).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (
+            status,
+            serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_repeated_create_request_gets_original_response() {
+        let url = serve();
+        let create = || {
+            send(
+                Method::POST,
+                format!("{}/api/bookmarks", url),
+                &[("Idempotency-Key", "abc")],
+                serde_json::json!({"url": "http://bar", "title": "bar"}),
+            )
+        };
+
+        let (status, created) = create().await;
+        assert_eq!(status, StatusCode::CREATED);
+        let id = created["id"].as_str().unwrap();
+        let (status, _) = send(
+            Method::PUT,
+            format!("{}/api/bookmarks/{}/title", url, id),
+            &[],
+            serde_json::json!({"title": "renamed"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        assert_eq!(create().await, (StatusCode::CREATED, created));
+    }
+
+    #[tokio::test]
+    async fn test_create_request_reusing_an_id_for_another_page_conflicts() {
+        let url = serve();
+        let id = Uuid::new_v4().to_string();
+        let create = |page: &str| {
+            send(
+                Method::POST,
+                format!("{}/api/bookmarks", url),
+                &[],
+                serde_json::json!({"url": page, "title": page, "id": id}),
+            )
+        };
+
+        assert_eq!(
+            create("http://bar").await,
+            (StatusCode::CREATED, serde_json::json!({ "id": id }))
+        );
+        assert_eq!(create("http://foo").await.0, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_create_request_with_invalid_id_is_refused() {
+        let url = serve();
+
+        for id in ["", "../123", "not a uuid"] {
+            let (status, _) = send(
+                Method::POST,
+                format!("{}/api/bookmarks", url),
+                &[],
+                serde_json::json!({"url": "http://bar", "title": "bar", "id": id}),
+            )
+            .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_local_and_peer_apis_are_served_apart() {
+        let (router, peer_router) = routers();
+        let url = serve_router(router);
+        let peer_url = serve_router(peer_router);
+
+        let (status, _) = send(
+            Method::GET,
+            format!("{}/api/sync/keys/missing", url),
+            &[],
+            serde_json::Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        for path in ["/api/bookmarks", "/api/trash"] {
+            let (status, _) = send(
+                Method::GET,
+                format!("{}{}", peer_url, path),
+                &[],
+                serde_json::Value::Null,
+            )
+            .await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        }
+    }
+}
@@ -0,0 +1,518 @@
+use crate::{
+    adapters::http_api_axum::{
+        peer_request_message, SyncAggregateIdsPayload, SyncEventIdsPayload, SyncEventsPayload,
+        SyncKeysPayload, SyncRangesRequestPayload, SyncRangesResponsePayload, PEER_INSTANCE_HEADER,
+        PEER_SIGNATURE_HEADER, PEER_TIMESTAMP_HEADER,
+    },
+    app,
+    domain::{data::DomainEvent, reconciliation::RangeFingerprint},
+    ports,
+};
+use hyper::{body, client::HttpConnector, header, Body, Client, Method, Request, Uri};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum SyncClientError {
+    #[error("Invalid peer URL")]
+    InvalidPeerUrl,
+    #[error("Could not reach peer: {0}")]
+    Unreachable(#[from] hyper::Error),
+    #[error("Unexpected response from peer")]
+    InvalidResponse,
+    #[error("Logs still differ after exchanging all missing events")]
+    NotConverged,
+    #[error("Could not save the keys received from peer")]
+    KeysNotSaved,
+}
+
+// Talks to the sync endpoints of another instance's HTTP API, signing each
+// request for the peer to know it comes from a trusted instance.
+pub struct HttpSyncClient {
+    base_url: String,
+    client: Client<HttpConnector>,
+    authenticator: Arc<dyn ports::PeerAuthenticator>,
+}
+
+impl HttpSyncClient {
+    pub fn new(base_url: &str, authenticator: Arc<dyn ports::PeerAuthenticator>) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            client: Client::new(),
+            authenticator,
+        }
+    }
+
+    pub async fn ranges(
+        &self,
+        prefixes: &[String],
+    ) -> Result<Vec<RangeFingerprint>, SyncClientError> {
+        let payload: SyncRangesResponsePayload = self
+            .request(
+                Method::POST,
+                "/api/sync/ranges",
+                json_body(&SyncRangesRequestPayload {
+                    prefixes: prefixes.to_vec(),
+                }),
+            )
+            .await?;
+        Ok(payload.ranges)
+    }
+
+    pub async fn events_by_id(&self, ids: &[String]) -> Result<Vec<DomainEvent>, SyncClientError> {
+        let payload: SyncEventsPayload = self
+            .request(
+                Method::POST,
+                "/api/sync/events/by-id",
+                json_body(&SyncEventIdsPayload { ids: ids.to_vec() }),
+            )
+            .await?;
+        Ok(payload.events)
+    }
+
+    pub async fn push_events(&self, events: Vec<DomainEvent>) -> Result<(), SyncClientError> {
+        let response = self
+            .client
+            .request(self.build_request(
+                Method::POST,
+                "/api/sync/events",
+                json_body(&SyncEventsPayload { events }),
+            )?)
+            .await?;
+        if !response.status().is_success() {
+            return Err(SyncClientError::InvalidResponse);
+        }
+        Ok(())
+    }
+
+    pub async fn missing_keys(&self) -> Result<Vec<String>, SyncClientError> {
+        let payload: SyncAggregateIdsPayload = self
+            .request(Method::GET, "/api/sync/keys/missing", vec![])
+            .await?;
+        Ok(payload.aggregate_ids)
+    }
+
+    pub async fn keys(
+        &self,
+        aggregate_ids: &[String],
+    ) -> Result<BTreeMap<String, String>, SyncClientError> {
+        let payload: SyncKeysPayload = self
+            .request(
+                Method::POST,
+                "/api/sync/keys/by-aggregate",
+                json_body(&SyncAggregateIdsPayload {
+                    aggregate_ids: aggregate_ids.to_vec(),
+                }),
+            )
+            .await?;
+        Ok(payload.keys)
+    }
+
+    pub async fn push_keys(&self, keys: BTreeMap<String, String>) -> Result<(), SyncClientError> {
+        let response = self
+            .client
+            .request(self.build_request(
+                Method::POST,
+                "/api/sync/keys",
+                json_body(&SyncKeysPayload { keys }),
+            )?)
+            .await?;
+        if !response.status().is_success() {
+            return Err(SyncClientError::InvalidResponse);
+        }
+        Ok(())
+    }
+
+    async fn request<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Vec<u8>,
+    ) -> Result<T, SyncClientError> {
+        let response = self
+            .client
+            .request(self.build_request(method, path, body)?)
+            .await?;
+        if !response.status().is_success() {
+            return Err(SyncClientError::InvalidResponse);
+        }
+        let bytes = body::to_bytes(response.into_body()).await?;
+        serde_json::from_slice(&bytes).map_err(|_source| SyncClientError::InvalidResponse)
+    }
+
+    fn build_request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Vec<u8>,
+    ) -> Result<Request<Body>, SyncClientError> {
+        let uri: Uri = format!("{}{}", self.base_url, path)
+            .parse()
+            .map_err(|_source| SyncClientError::InvalidPeerUrl)?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let (instance_id, signature) = self.authenticator.sign(&peer_request_message(
+            method.as_str(),
+            uri.path(),
+            timestamp,
+            &body,
+        ));
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(PEER_INSTANCE_HEADER, instance_id)
+            .header(PEER_TIMESTAMP_HEADER, timestamp)
+            .header(PEER_SIGNATURE_HEADER, signature)
+            .body(Body::from(body))
+            .map_err(|_source| SyncClientError::InvalidPeerUrl)
+    }
+}
+
+fn json_body(payload: &impl Serialize) -> Vec<u8> {
+    serde_json::to_vec(payload).unwrap()
+}
+
+// Finds the events missing on either side by comparing range fingerprints,
+// then pushes and pulls them, until both sides have the same events. Gives
+// up when a round makes no progress, e.g. because one side doesn't trust the
+// other's keys. Bookmark keys are exchanged last, so that a bookmark
+// forgotten on either side is known to be before its key is offered.
+pub async fn sync_with_peer(
+    peer: &HttpSyncClient,
+    event_store: Arc<dyn ports::EventStore>,
+    snapshot_store: Arc<dyn ports::SnapshotStore>,
+    key_store: Arc<dyn ports::KeyStore>,
+    read_model: Arc<dyn ports::ReadModel>,
+) -> Result<(), SyncClientError> {
+    sync_events_with_peer(
+        peer,
+        event_store.clone(),
+        snapshot_store.clone(),
+        read_model.clone(),
+    )
+    .await?;
+
+    let keys = peer
+        .keys(&app::read_sealed_aggregates(event_store.clone()))
+        .await?;
+    app::import_keys(
+        &keys,
+        event_store,
+        snapshot_store,
+        key_store.clone(),
+        read_model,
+    )
+    .map_err(|_source| SyncClientError::KeysNotSaved)?;
+    peer.push_keys(app::export_keys(&peer.missing_keys().await?, key_store))
+        .await
+}
+
+async fn sync_events_with_peer(
+    peer: &HttpSyncClient,
+    event_store: Arc<dyn ports::EventStore>,
+    snapshot_store: Arc<dyn ports::SnapshotStore>,
+    read_model: Arc<dyn ports::ReadModel>,
+) -> Result<(), SyncClientError> {
+    let mut previous = None;
+    loop {
+        let mut missing_locally = vec![];
+        let mut missing_remotely = vec![];
+        let mut prefixes = vec!["".to_owned()];
+        while !prefixes.is_empty() {
+            let comparison =
+                app::compare_event_ranges(&peer.ranges(&prefixes).await?, event_store.clone());
+            missing_locally.extend(comparison.missing_locally);
+            missing_remotely.extend(comparison.missing_remotely);
+            prefixes = comparison.prefixes_to_split;
+        }
+
+        if missing_locally.is_empty() && missing_remotely.is_empty() {
+            return Ok(());
+        }
+        let differences = Some((missing_locally.clone(), missing_remotely.clone()));
+        if previous == differences {
+            return Err(SyncClientError::NotConverged);
+        }
+
+        peer.push_events(app::read_events_by_id(
+            &missing_remotely,
+            event_store.clone(),
+        ))
+        .await?;
+        app::import_events(
+            peer.events_by_id(&missing_locally).await?,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+        );
+
+        previous = differences;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::{
+        clock::{FakeClock, SystemClock},
+        http_api_axum,
+        memory_event_store::MemoryEventStore,
+        memory_key_store::MemoryKeyStore,
+        memory_read_model::MemoryReadModel,
+        memory_snapshot_store::MemorySnapshotStore,
+        shredding_event_store::ShreddingEventStore,
+        signed_event_store::SignedEventStore,
+    };
+    use crate::domain::{
+        aggregates::BookmarkAggregate,
+        commands::BookmarkCommand,
+        events::{BookmarkEventPayload, DomainEventPayload},
+    };
+    use crate::ports::{EventStore, KeyStore, ReadModel};
+    use ed25519_dalek::SigningKey;
+    use hyper::StatusCode;
+    use rand_core::OsRng;
+    use std::{
+        collections::HashMap,
+        net::{SocketAddr, TcpListener},
+    };
+
+    // Wraps the event stores of two instances that trust each other's keys
+    fn trusting_each_other(
+        laptop: Arc<dyn EventStore>,
+        phone: Arc<dyn EventStore>,
+    ) -> (Arc<SignedEventStore>, Arc<SignedEventStore>) {
+        let laptop_key = SigningKey::generate(&mut OsRng);
+        let phone_key = SigningKey::generate(&mut OsRng);
+        let trusted_keys = HashMap::from([
+            (laptop.instance_id(), laptop_key.verifying_key()),
+            (phone.instance_id(), phone_key.verifying_key()),
+        ]);
+        (
+            Arc::new(SignedEventStore::new(
+                laptop,
+                laptop_key,
+                trusted_keys.clone(),
+            )),
+            Arc::new(SignedEventStore::new(phone, phone_key, trusted_keys)),
+        )
+    }
+
+    // Requests are signed with the current time, which the server checks
+    fn serve(
+        event_store: Arc<dyn ports::EventStore>,
+        key_store: Arc<dyn ports::KeyStore>,
+        read_model: Arc<MemoryReadModel>,
+        peer_authenticator: Arc<dyn ports::PeerAuthenticator>,
+    ) -> String {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = http_api_axum::create_peer_router(
+            event_store,
+            Arc::new(MemorySnapshotStore::new()),
+            key_store,
+            read_model,
+            peer_authenticator,
+            Arc::new(SystemClock::new()),
+        );
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(router.into_make_service()),
+        );
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_two_instances_converge_after_sync() {
+        let clock = Arc::new(FakeClock::new());
+        let (laptop_event_store, phone_event_store) = trusting_each_other(
+            Arc::new(MemoryEventStore::with_instance_id("laptop")),
+            Arc::new(MemoryEventStore::with_instance_id("phone")),
+        );
+        let laptop_snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let laptop_read_model = Arc::new(MemoryReadModel::new());
+        let phone_read_model = Arc::new(MemoryReadModel::new());
+
+        app::dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::BookmarkPage {
+                url: "http://bar".to_owned(),
+                title: "bar".to_owned(),
+            },
+            None,
+            laptop_event_store.clone(),
+            laptop_snapshot_store.clone(),
+            laptop_read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+        app::dispatch::<BookmarkAggregate>(
+            "456",
+            BookmarkCommand::BookmarkPage {
+                url: "http://foo".to_owned(),
+                title: "foo".to_owned(),
+            },
+            None,
+            phone_event_store.clone(),
+            Arc::new(MemorySnapshotStore::new()),
+            phone_read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+
+        let phone = HttpSyncClient::new(
+            &serve(
+                phone_event_store.clone(),
+                Arc::new(MemoryKeyStore::new()),
+                phone_read_model.clone(),
+                phone_event_store.clone(),
+            ),
+            laptop_event_store.clone(),
+        );
+        sync_with_peer(
+            &phone,
+            laptop_event_store.clone(),
+            laptop_snapshot_store.clone(),
+            Arc::new(MemoryKeyStore::new()),
+            laptop_read_model.clone(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(laptop_read_model.read_bookmarks().unwrap().len(), 2);
+        assert_eq!(phone_read_model.read_bookmarks().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_forgotten_bookmark_is_forgotten_by_peer() {
+        let clock = Arc::new(FakeClock::new());
+        let laptop_inner = Arc::new(MemoryEventStore::with_instance_id("laptop"));
+        let (laptop_signed_event_store, phone_signed_event_store) = trusting_each_other(
+            laptop_inner.clone(),
+            Arc::new(MemoryEventStore::with_instance_id("phone")),
+        );
+        let laptop_key_store = Arc::new(MemoryKeyStore::new());
+        let laptop_event_store = Arc::new(ShreddingEventStore::new(
+            laptop_signed_event_store.clone(),
+            laptop_key_store.clone(),
+        ));
+        let laptop_snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let laptop_read_model = Arc::new(MemoryReadModel::new());
+        let phone_key_store = Arc::new(MemoryKeyStore::new());
+        let phone_event_store = Arc::new(ShreddingEventStore::new(
+            phone_signed_event_store.clone(),
+            phone_key_store.clone(),
+        ));
+        let phone_read_model = Arc::new(MemoryReadModel::new());
+        let phone = HttpSyncClient::new(
+            &serve(
+                phone_event_store.clone(),
+                phone_key_store.clone(),
+                phone_read_model.clone(),
+                phone_signed_event_store,
+            ),
+            laptop_signed_event_store,
+        );
+        let sync = || {
+            sync_with_peer(
+                &phone,
+                laptop_event_store.clone(),
+                laptop_snapshot_store.clone(),
+                laptop_key_store.clone(),
+                laptop_read_model.clone(),
+            )
+        };
+
+        app::dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::BookmarkPage {
+                url: "http://private".to_owned(),
+                title: "private".to_owned(),
+            },
+            None,
+            laptop_event_store.clone(),
+            laptop_snapshot_store.clone(),
+            laptop_read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+        sync().await.unwrap();
+        assert_eq!(phone_read_model.read_bookmarks().unwrap().len(), 1);
+
+        app::dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::Forget,
+            None,
+            laptop_event_store.clone(),
+            laptop_snapshot_store.clone(),
+            laptop_read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+        sync().await.unwrap();
+
+        assert_eq!(phone_read_model.read_bookmarks().unwrap(), vec![]);
+        assert_eq!(phone_key_store.key("123"), None);
+        assert_eq!(laptop_key_store.key("123"), None);
+        assert!(!phone_event_store
+            .get_events_for_aggregate("123")
+            .iter()
+            .any(|e| matches!(
+                e.payload,
+                DomainEventPayload::Bookmark(BookmarkEventPayload::Created { .. })
+            )));
+        assert_eq!(phone_event_store.event_ids(), laptop_inner.event_ids());
+    }
+
+    #[tokio::test]
+    async fn test_peer_endpoints_refuse_untrusted_requests() {
+        let (laptop_event_store, phone_event_store) = trusting_each_other(
+            Arc::new(MemoryEventStore::with_instance_id("laptop")),
+            Arc::new(MemoryEventStore::with_instance_id("phone")),
+        );
+        let phone_key_store = Arc::new(MemoryKeyStore::new());
+        phone_key_store.save_key("123", [7; 32]).unwrap();
+        let url = serve(
+            phone_event_store.clone(),
+            phone_key_store,
+            Arc::new(MemoryReadModel::new()),
+            phone_event_store,
+        );
+        let stranger = Arc::new(SignedEventStore::new(
+            Arc::new(MemoryEventStore::with_instance_id("stranger")),
+            SigningKey::generate(&mut OsRng),
+            HashMap::new(),
+        ));
+
+        let keys = HttpSyncClient::new(&url, stranger)
+            .keys(&["123".to_owned()])
+            .await;
+        assert!(matches!(keys, Err(SyncClientError::InvalidResponse)));
+
+        let response = Client::new()
+            .request(
+                Request::builder()
+                    .method(Method::GET)
+                    .uri(format!("{}/api/sync/keys/missing", url))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let keys = HttpSyncClient::new(&url, laptop_event_store)
+            .keys(&["123".to_owned()])
+            .await
+            .unwrap();
+        assert_eq!(keys.len(), 1);
+    }
+}
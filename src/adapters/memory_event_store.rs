@@ -1,34 +1,82 @@
 use crate::{
-    domain::data::DomainEvent,
+    domain::{chain, data::DomainEvent, reconciliation::EventIds},
     ports::{EventStore, EventStoreError},
 };
 use std::sync::Mutex;
+use uuid::Uuid;
 
 pub struct MemoryEventStore {
     events: Mutex<Vec<DomainEvent>>,
+    event_ids: Mutex<EventIds>,
+    instance_id: String,
 }
 
+#[allow(clippy::new_without_default)]
 impl MemoryEventStore {
     pub fn new() -> Self {
+        Self::with_instance_id(&Uuid::new_v4().to_string())
+    }
+
+    pub fn with_instance_id(instance_id: &str) -> Self {
         let events: Mutex<Vec<DomainEvent>> = Mutex::new(vec![]);
-        Self { events }
+        Self {
+            events,
+            event_ids: Mutex::new(EventIds::default()),
+            instance_id: instance_id.to_owned(),
+        }
     }
 }
 
 impl EventStore for MemoryEventStore {
     fn import_event(&self, event: DomainEvent) -> Result<(), EventStoreError> {
+        self.event_ids.lock().unwrap().insert(&event);
         let mut lock = self.events.lock().unwrap();
         lock.push(event);
+        #[allow(clippy::unnecessary_sort_by)]
         lock.sort_by(|a, b| a.meta.created_at.cmp(&b.meta.created_at));
         Ok(())
     }
 
-    fn store_event(&self, event: DomainEvent) -> Result<(), EventStoreError> {
+    fn store_events(
+        &self,
+        events: Vec<DomainEvent>,
+        expected_version: Option<u64>,
+    ) -> Result<(), EventStoreError> {
+        let mut event_ids = self.event_ids.lock().unwrap();
         let mut lock = self.events.lock().unwrap();
-        lock.push(event);
+        let events = chain::link(events, || {
+            chain::head(&self.instance_id, lock.iter().cloned())
+        });
+        if let (Some(expected_version), Some(first)) = (expected_version, events.first()) {
+            let version = lock
+                .iter()
+                .filter(|e| e.meta.aggregate_id == first.meta.aggregate_id)
+                .count() as u64;
+            if version != expected_version {
+                return Err(EventStoreError::Conflict);
+            }
+        }
+        for event in events {
+            event_ids.insert(&event);
+            lock.push(event);
+        }
+        Ok(())
+    }
+
+    fn replace_event(&self, event: DomainEvent) -> Result<(), EventStoreError> {
+        let mut lock = self.events.lock().unwrap();
+        let stored = lock
+            .iter_mut()
+            .find(|e| {
+                e.meta.instance_id == event.meta.instance_id
+                    && e.meta.sequence == event.meta.sequence
+            })
+            .ok_or(EventStoreError::Generic)?;
+        *stored = event;
         Ok(())
     }
 
+    #[allow(clippy::map_clone)]
     fn get_events_for_aggregate(&self, aggregate_id: &str) -> Vec<DomainEvent> {
         self.events
             .lock()
@@ -39,8 +87,25 @@ impl EventStore for MemoryEventStore {
             .collect()
     }
 
+    fn aggregate_version(&self, aggregate_id: &str) -> u64 {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|e| e.meta.aggregate_id == aggregate_id)
+            .count() as u64
+    }
+
     fn events_iter(&self) -> Box<dyn Iterator<Item = DomainEvent>> {
-        todo!();
+        Box::new(self.events.lock().unwrap().clone().into_iter())
+    }
+
+    fn event_ids(&self) -> EventIds {
+        self.event_ids.lock().unwrap().clone()
+    }
+
+    fn instance_id(&self) -> String {
+        self.instance_id.clone()
     }
 }
 
@@ -63,11 +128,16 @@ mod tests {
             meta: DomainEventMeta {
                 aggregate_id: "abc".to_owned(),
                 created_at: earlier_external_event_time,
+                instance_id: "phone".to_owned(),
+                sequence: 0,
+                previous_hash: None,
             },
             payload: DomainEventPayload::Bookmark(BookmarkEventPayload::Created {
                 url: "https://google.com".to_owned(),
                 title: "Google".to_owned(),
             }),
+            signature: None,
+            sealed: None,
         };
 
         clock.advance(Duration::from_secs(10));
@@ -76,18 +146,52 @@ mod tests {
             meta: DomainEventMeta {
                 aggregate_id: "123".to_owned(),
                 created_at: later_local_event_time,
+                instance_id: "laptop".to_owned(),
+                sequence: 0,
+                previous_hash: None,
             },
             payload: DomainEventPayload::Bookmark(BookmarkEventPayload::Created {
                 url: "https://example.com".to_owned(),
                 title: "Example".to_owned(),
             }),
+            signature: None,
+            sealed: None,
         };
 
-        event_store.store_event(later_local_event).unwrap();
+        event_store
+            .store_events(vec![later_local_event], None)
+            .unwrap();
         event_store.import_event(earlier_external_event).unwrap();
 
         let events = event_store.events.lock().unwrap();
 
         assert_eq!(events[0].meta.created_at, earlier_external_event_time);
     }
+
+    #[test]
+    fn test_storing_against_outdated_version_appends_nothing() {
+        let event_store = MemoryEventStore::new();
+        let clock = FakeClock::new();
+
+        let event = |sequence| DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: "123".to_owned(),
+                created_at: clock.now(),
+                instance_id: "laptop".to_owned(),
+                sequence,
+                previous_hash: None,
+            },
+            payload: DomainEventPayload::Bookmark(BookmarkEventPayload::TitleUpdated {
+                title: "Example".to_owned(),
+            }),
+            signature: None,
+            sealed: None,
+        };
+
+        event_store.store_events(vec![event(0)], Some(0)).unwrap();
+        let result = event_store.store_events(vec![event(1)], Some(0));
+
+        assert!(matches!(result, Err(EventStoreError::Conflict)));
+        assert_eq!(event_store.aggregate_version("123"), 1);
+    }
 }
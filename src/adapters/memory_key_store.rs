@@ -0,0 +1,51 @@
+use crate::ports::{AggregateKey, KeyStore, KeyStoreError};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+pub struct MemoryKeyStore {
+    keys_by_id: Mutex<HashMap<String, AggregateKey>>,
+    destroyed: Mutex<HashSet<String>>,
+}
+
+impl MemoryKeyStore {
+    pub fn new() -> Self {
+        Self {
+            keys_by_id: Mutex::new(HashMap::new()),
+            destroyed: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl Default for MemoryKeyStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyStore for MemoryKeyStore {
+    fn key(&self, aggregate_id: &str) -> Option<AggregateKey> {
+        self.keys_by_id.lock().unwrap().get(aggregate_id).copied()
+    }
+
+    fn save_key(&self, aggregate_id: &str, key: AggregateKey) -> Result<(), KeyStoreError> {
+        if self.destroyed.lock().unwrap().contains(aggregate_id) {
+            return Err(KeyStoreError::Destroyed);
+        }
+        self.keys_by_id
+            .lock()
+            .unwrap()
+            .insert(aggregate_id.to_owned(), key);
+        Ok(())
+    }
+
+    fn destroy_key(&self, aggregate_id: &str) -> Result<(), KeyStoreError> {
+        self.keys_by_id.lock().unwrap().remove(aggregate_id);
+        self.destroyed
+            .lock()
+            .unwrap()
+            .insert(aggregate_id.to_owned());
+        Ok(())
+    }
+}
@@ -1,59 +1,310 @@
-use crate::domain::data::{BookmarkData, DomainEvent};
+use crate::domain::checkpoint::Checkpoint;
+use crate::domain::data::{
+    BookmarkData, DomainEvent, LogPosition, PeerSyncData, TrashedBookmarkData,
+};
 use crate::domain::events::{BookmarkEventPayload, DomainEventPayload};
+use crate::domain::note::Note;
 use crate::ports::{ReadModel, ReadModelError};
-use std::{collections::HashMap, sync::Mutex};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
 
 pub struct MemoryReadModel {
     bookmarks_by_id: Mutex<HashMap<String, BookmarkData>>,
+    notes_by_id: Mutex<HashMap<String, Note>>,
+    trash_by_id: Mutex<HashMap<String, TrashedBookmarkData>>,
+    peers_by_id: Mutex<HashMap<String, PeerProgress>>,
+    // Which creation won for each ID, as in the aggregate
+    created_by_id: Mutex<HashMap<String, LogPosition>>,
+    // Held while projecting, so that an event is projected only once
+    checkpoint: Mutex<Checkpoint>,
+    path: Option<PathBuf>,
 }
 
+// Everything projected so far, along with how far that is, as saved to the
+// file the read model is kept in, if any.
+#[derive(Default, Serialize, Deserialize)]
+struct SavedReadModel {
+    bookmarks_by_id: HashMap<String, BookmarkData>,
+    notes_by_id: HashMap<String, Note>,
+    trash_by_id: HashMap<String, TrashedBookmarkData>,
+    peers_by_id: HashMap<String, PeerProgress>,
+    #[serde(default)]
+    created_by_id: HashMap<String, LogPosition>,
+    checkpoint: Checkpoint,
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
+struct PeerProgress {
+    sequences: BTreeSet<u64>,
+    last_seen_at: Option<SystemTime>,
+    unknown_events: u64,
+}
+
+impl PeerProgress {
+    fn to_sync_data(&self, instance_id: &str) -> PeerSyncData {
+        let mut gaps = vec![];
+        let mut previous = 0;
+        for sequence in &self.sequences {
+            if *sequence > previous + 1 {
+                gaps.push((previous + 1, sequence - 1));
+            }
+            previous = *sequence;
+        }
+        let up_to_sequence = gaps.first().map_or(previous, |(from, _)| from - 1);
+
+        PeerSyncData {
+            instance_id: instance_id.to_owned(),
+            up_to_sequence,
+            gaps,
+            last_seen_at: self.last_seen_at.unwrap_or(SystemTime::UNIX_EPOCH),
+            unknown_events: self.unknown_events,
+        }
+    }
+}
+
+#[allow(clippy::new_without_default)]
 impl MemoryReadModel {
     pub fn new() -> Self {
         let bookmarks_by_id: Mutex<HashMap<String, BookmarkData>> = Mutex::new(HashMap::new());
-        Self { bookmarks_by_id }
+        let notes_by_id: Mutex<HashMap<String, Note>> = Mutex::new(HashMap::new());
+        let trash_by_id: Mutex<HashMap<String, TrashedBookmarkData>> = Mutex::new(HashMap::new());
+        let peers_by_id: Mutex<HashMap<String, PeerProgress>> = Mutex::new(HashMap::new());
+        Self {
+            bookmarks_by_id,
+            notes_by_id,
+            trash_by_id,
+            peers_by_id,
+            created_by_id: Mutex::new(HashMap::new()),
+            checkpoint: Mutex::new(Checkpoint::default()),
+            path: None,
+        }
     }
-}
 
-impl ReadModel for MemoryReadModel {
-    fn update(&self, event: &DomainEvent) -> Result<(), ReadModelError> {
+    // Keeps the projections in a file, so that they only need to catch up
+    // with events stored since they were last saved after a restart. A file
+    // that can't be read is started over from scratch.
+    pub fn with_file(mut self, path: &Path) -> Self {
+        let saved: SavedReadModel = fs::read_to_string(path)
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default();
+        self.bookmarks_by_id = Mutex::new(saved.bookmarks_by_id);
+        self.notes_by_id = Mutex::new(saved.notes_by_id);
+        self.trash_by_id = Mutex::new(saved.trash_by_id);
+        self.peers_by_id = Mutex::new(saved.peers_by_id);
+        self.created_by_id = Mutex::new(saved.created_by_id);
+        self.checkpoint = Mutex::new(saved.checkpoint);
+        self.path = Some(path.to_owned());
+        self
+    }
+
+    fn project(&self, event: &DomainEvent) -> Result<(), ReadModelError> {
+        if let DomainEventPayload::Unknown(_) = event.payload {
+            self.peers_by_id
+                .lock()
+                .unwrap()
+                .entry(event.meta.instance_id.clone())
+                .or_default()
+                .unknown_events += 1;
+        }
+        if event.meta.sequence > 0 {
+            let mut peers_by_id = self.peers_by_id.lock().unwrap();
+            let peer = peers_by_id
+                .entry(event.meta.instance_id.clone())
+                .or_default();
+            peer.sequences.insert(event.meta.sequence);
+            peer.last_seen_at = peer.last_seen_at.max(Some(event.meta.created_at));
+        }
+
         let mut bookmarks_by_id = self.bookmarks_by_id.lock().unwrap();
 
         match &event.payload {
+            // The first creation in the log wins, whichever is received
+            // first. One received late takes over whatever is left of the
+            // bookmark, while later changes are only applied on top of it
+            // again once the read model is rebuilt.
             DomainEventPayload::Bookmark(BookmarkEventPayload::Created { url, title }) => {
-                if bookmarks_by_id.contains_key(&*event.meta.aggregate_id) {
-                    Err(ReadModelError::Generic)
-                } else {
-                    bookmarks_by_id.insert(
+                let id = &*event.meta.aggregate_id;
+                let position = event.meta.log_position();
+                let mut trash_by_id = self.trash_by_id.lock().unwrap();
+                let mut created_by_id = self.created_by_id.lock().unwrap();
+                match created_by_id.get(id) {
+                    Some(created_by) if *created_by <= position => {}
+                    Some(_) => {
+                        let bookmark = match bookmarks_by_id.get_mut(id) {
+                            Some(bookmark) => Some(bookmark),
+                            None => trash_by_id.get_mut(id).map(|trashed| &mut trashed.bookmark),
+                        };
+                        if let Some(bookmark) = bookmark {
+                            bookmark.url = url.to_owned();
+                            bookmark.title = title.to_owned();
+                        }
+                        created_by_id.insert(id.to_owned(), position);
+                    }
+                    // Saved before the winning creation was kept
+                    None if bookmarks_by_id.contains_key(id) || trash_by_id.contains_key(id) => {}
+                    None => {
+                        bookmarks_by_id.insert(
+                            id.to_owned(),
+                            BookmarkData {
+                                id: id.to_owned(),
+                                url: url.to_owned(),
+                                title: title.to_owned(),
+                                previous_urls: vec![],
+                                note: "".to_owned(),
+                            },
+                        );
+                        created_by_id.insert(id.to_owned(), position);
+                    }
+                }
+                Ok(())
+            }
+            DomainEventPayload::Bookmark(BookmarkEventPayload::Deleted) => {
+                if let Some(bookmark) = bookmarks_by_id.remove(&*event.meta.aggregate_id) {
+                    self.trash_by_id.lock().unwrap().insert(
                         event.meta.aggregate_id.to_owned(),
-                        BookmarkData {
-                            id: event.meta.aggregate_id.to_owned(),
-                            url: url.to_owned(),
-                            title: title.to_owned(),
+                        TrashedBookmarkData {
+                            bookmark,
+                            deleted_at: event.meta.created_at,
                         },
                     );
-                    Ok(())
                 }
+                Ok(())
             }
-            DomainEventPayload::Bookmark(BookmarkEventPayload::Deleted) => {
-                bookmarks_by_id.remove(&*event.meta.aggregate_id);
+            DomainEventPayload::Bookmark(BookmarkEventPayload::Restored) => {
+                // Nothing to restore if the trash entry has already been
+                // purged here, e.g. when another instance keeps its trash
+                // for longer.
+                if let Some(trashed) = self
+                    .trash_by_id
+                    .lock()
+                    .unwrap()
+                    .remove(&*event.meta.aggregate_id)
+                {
+                    bookmarks_by_id.insert(event.meta.aggregate_id.to_owned(), trashed.bookmark);
+                }
                 Ok(())
             }
             DomainEventPayload::Bookmark(BookmarkEventPayload::TitleUpdated { title }) => {
+                bookmarks_by_id
+                    .entry(event.meta.aggregate_id.to_owned())
+                    .and_modify(|bookmark| bookmark.title = title.clone());
+                Ok(())
+            }
+            DomainEventPayload::Bookmark(BookmarkEventPayload::UrlUpdated { url }) => {
                 bookmarks_by_id
                     .entry(event.meta.aggregate_id.to_owned())
                     .and_modify(|bookmark| {
-                        *bookmark = BookmarkData {
-                            id: bookmark.id.clone(),
-                            url: bookmark.url.clone(),
-                            title: title.clone(),
+                        // Peers may still send changes to the same URL
+                        if bookmark.url != *url {
+                            let previous_url = std::mem::replace(&mut bookmark.url, url.clone());
+                            bookmark.previous_urls.push(previous_url);
                         }
                     });
                 Ok(())
             }
+            DomainEventPayload::Bookmark(BookmarkEventPayload::NoteEdited { ops }) => {
+                // Edits made concurrently with a deletion still count once
+                // the bookmark is restored.
+                let mut trash_by_id = self.trash_by_id.lock().unwrap();
+                let bookmark = match bookmarks_by_id.get_mut(&*event.meta.aggregate_id) {
+                    Some(bookmark) => Some(bookmark),
+                    None => trash_by_id
+                        .get_mut(&*event.meta.aggregate_id)
+                        .map(|trashed| &mut trashed.bookmark),
+                };
+                if let Some(bookmark) = bookmark {
+                    let mut notes_by_id = self.notes_by_id.lock().unwrap();
+                    let note = notes_by_id
+                        .entry(event.meta.aggregate_id.to_owned())
+                        .or_default();
+                    note.apply(ops);
+                    bookmark.note = note.text();
+                }
+                Ok(())
+            }
+            DomainEventPayload::Bookmark(BookmarkEventPayload::Purged) => {
+                let mut trash_by_id = self.trash_by_id.lock().unwrap();
+                if trash_by_id.remove(&*event.meta.aggregate_id).is_some() {
+                    self.notes_by_id
+                        .lock()
+                        .unwrap()
+                        .remove(&*event.meta.aggregate_id);
+                }
+                Ok(())
+            }
+            DomainEventPayload::Bookmark(BookmarkEventPayload::Forgotten) => {
+                bookmarks_by_id.remove(&*event.meta.aggregate_id);
+                self.trash_by_id
+                    .lock()
+                    .unwrap()
+                    .remove(&*event.meta.aggregate_id);
+                self.notes_by_id
+                    .lock()
+                    .unwrap()
+                    .remove(&*event.meta.aggregate_id);
+                Ok(())
+            }
+            DomainEventPayload::Instance(_) => Ok(()),
+            DomainEventPayload::Encrypted(_) => Ok(()),
+            DomainEventPayload::Sealed(_) => Ok(()),
+            DomainEventPayload::Compacted(_) => Ok(()),
+            // Counted above, so that it can be pointed out that a newer
+            // version is around.
+            DomainEventPayload::Unknown(_) => Ok(()),
             _ => todo!(),
         }
     }
+}
+
+impl ReadModel for MemoryReadModel {
+    fn update(&self, event: &DomainEvent) -> Result<(), ReadModelError> {
+        let mut checkpoint = self.checkpoint.lock().unwrap();
+        if checkpoint.covers(event) {
+            return Ok(());
+        }
+        self.project(event)?;
+        checkpoint.advance(event);
+        Ok(())
+    }
+
+    fn skip(&self, event: &DomainEvent) {
+        self.checkpoint.lock().unwrap().advance(event);
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        self.checkpoint.lock().unwrap().clone()
+    }
 
+    // Written aside and renamed into place, so that a crash never leaves
+    // projections behind that don't match their checkpoint.
+    fn save(&self) -> Result<(), ReadModelError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let checkpoint = self.checkpoint.lock().unwrap();
+        let saved = SavedReadModel {
+            bookmarks_by_id: self.bookmarks_by_id.lock().unwrap().clone(),
+            notes_by_id: self.notes_by_id.lock().unwrap().clone(),
+            trash_by_id: self.trash_by_id.lock().unwrap().clone(),
+            peers_by_id: self.peers_by_id.lock().unwrap().clone(),
+            created_by_id: self.created_by_id.lock().unwrap().clone(),
+            checkpoint: checkpoint.clone(),
+        };
+        let partial_path = path.with_extension("json.partial");
+        fs::write(&partial_path, serde_json::to_vec(&saved).unwrap())
+            .and_then(|()| fs::rename(&partial_path, path))
+            .map_err(|_source| ReadModelError::Generic)
+    }
+
+    #[allow(clippy::needless_return)]
     fn read_bookmarks(&self) -> Option<Vec<BookmarkData>> {
         let bookmarks_by_id = self.bookmarks_by_id.lock().unwrap();
         let mut items: Vec<BookmarkData> = bookmarks_by_id.values().cloned().collect();
@@ -61,6 +312,7 @@ impl ReadModel for MemoryReadModel {
         return Some(items);
     }
 
+    #[allow(clippy::manual_map)]
     fn read_bookmark(&self, id: &str) -> Option<BookmarkData> {
         let bookmarks_by_id = self.bookmarks_by_id.lock().unwrap();
         match bookmarks_by_id.get(id) {
@@ -68,12 +320,45 @@ impl ReadModel for MemoryReadModel {
             None => None,
         }
     }
+
+    fn read_trash(&self) -> Option<Vec<TrashedBookmarkData>> {
+        let trash_by_id = self.trash_by_id.lock().unwrap();
+        let mut items: Vec<TrashedBookmarkData> = trash_by_id.values().cloned().collect();
+        items.sort_unstable_by_key(|t| t.bookmark.id.clone());
+        Some(items)
+    }
+
+    fn read_peers(&self) -> Option<Vec<PeerSyncData>> {
+        let peers_by_id = self.peers_by_id.lock().unwrap();
+        let mut items: Vec<PeerSyncData> = peers_by_id
+            .iter()
+            .map(|(instance_id, peer)| peer.to_sync_data(instance_id))
+            .collect();
+        items.sort_unstable_by_key(|p| p.instance_id.clone());
+        Some(items)
+    }
+
+    fn clear(&self) -> Result<(), ReadModelError> {
+        let mut checkpoint = self.checkpoint.lock().unwrap();
+        self.bookmarks_by_id.lock().unwrap().clear();
+        self.notes_by_id.lock().unwrap().clear();
+        self.trash_by_id.lock().unwrap().clear();
+        self.peers_by_id.lock().unwrap().clear();
+        self.created_by_id.lock().unwrap().clear();
+        *checkpoint = Checkpoint::default();
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{adapters::clock::FakeClock, domain::data::DomainEventMeta, ports::Clock};
+    use crate::{
+        adapters::clock::FakeClock,
+        domain::{data::DomainEventMeta, events::EncryptedEventPayload},
+        ports::Clock,
+    };
+    use assert_fs::{fixture::PathChild, TempDir};
 
     #[test]
     fn test_read_model_exposes_bookmark_by_id() {
@@ -85,11 +370,16 @@ mod tests {
                 meta: DomainEventMeta {
                     aggregate_id: "123".to_owned(),
                     created_at: clock.now(),
+                    instance_id: "laptop".to_owned(),
+                    sequence: 0,
+                    previous_hash: None,
                 },
                 payload: DomainEventPayload::Bookmark(BookmarkEventPayload::Created {
                     url: "https://example.com".to_owned(),
                     title: "Example".to_owned(),
                 }),
+                signature: None,
+                sealed: None,
             })
             .unwrap();
 
@@ -97,4 +387,140 @@ mod tests {
 
         assert_eq!(bookmark.url, "https://example.com");
     }
+
+    #[test]
+    fn test_read_model_tracks_received_sequences_per_instance() {
+        let read_model = MemoryReadModel::new();
+        let clock = FakeClock::new();
+
+        for sequence in [1, 2, 5, 7] {
+            clock.advance(std::time::Duration::from_secs(1));
+            read_model
+                .update(&DomainEvent {
+                    meta: DomainEventMeta {
+                        aggregate_id: "phone".to_owned(),
+                        created_at: clock.now(),
+                        instance_id: "phone".to_owned(),
+                        sequence,
+                        previous_hash: None,
+                    },
+                    payload: DomainEventPayload::Encrypted(EncryptedEventPayload {
+                        nonce: "".to_owned(),
+                        ciphertext: "".to_owned(),
+                    }),
+                    signature: None,
+                    sealed: None,
+                })
+                .unwrap();
+        }
+
+        let peers = read_model.read_peers().unwrap();
+
+        assert_eq!(
+            peers,
+            vec![PeerSyncData {
+                instance_id: "phone".to_owned(),
+                up_to_sequence: 2,
+                gaps: vec![(3, 4), (6, 6)],
+                last_seen_at: clock.now(),
+                unknown_events: 0,
+            }]
+        );
+        assert_eq!(peers[0].missing(), 3);
+    }
+
+    #[test]
+    fn test_read_model_counts_events_it_does_not_know() {
+        let read_model = MemoryReadModel::new();
+        let clock = FakeClock::new();
+
+        read_model
+            .update(&DomainEvent {
+                meta: DomainEventMeta {
+                    aggregate_id: "123".to_owned(),
+                    created_at: clock.now(),
+                    instance_id: "phone".to_owned(),
+                    sequence: 1,
+                    previous_hash: None,
+                },
+                payload: serde_json::from_str(r#"{"type": "tag", "name": "Reading list"}"#)
+                    .unwrap(),
+                signature: None,
+                sealed: None,
+            })
+            .unwrap();
+
+        let peers = read_model.read_peers().unwrap();
+
+        assert_eq!(peers[0].unknown_events, 1);
+        assert_eq!(peers[0].up_to_sequence, 1);
+        assert_eq!(read_model.read_bookmarks(), Some(vec![]));
+    }
+
+    #[test]
+    fn test_read_model_applies_note_edits_to_trashed_bookmarks() {
+        let read_model = MemoryReadModel::new();
+        let clock = FakeClock::new();
+        let event = |payload| DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: "123".to_owned(),
+                created_at: clock.now(),
+                instance_id: "laptop".to_owned(),
+                sequence: 0,
+                previous_hash: None,
+            },
+            payload: DomainEventPayload::Bookmark(payload),
+            signature: None,
+            sealed: None,
+        };
+
+        for payload in [
+            BookmarkEventPayload::Created {
+                url: "https://example.com".to_owned(),
+                title: "Example".to_owned(),
+            },
+            BookmarkEventPayload::Deleted,
+            BookmarkEventPayload::NoteEdited {
+                ops: Note::new().diff("to read", "edit"),
+            },
+            BookmarkEventPayload::Restored,
+        ] {
+            read_model.update(&event(payload)).unwrap();
+        }
+
+        assert_eq!(read_model.read_bookmark("123").unwrap().note, "to read");
+    }
+
+    #[test]
+    fn test_saved_read_model_carries_on_from_its_checkpoint() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.child("read-model.json");
+        let event = DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: "123".to_owned(),
+                created_at: SystemTime::UNIX_EPOCH,
+                instance_id: "laptop".to_owned(),
+                sequence: 1,
+                previous_hash: None,
+            },
+            payload: DomainEventPayload::Bookmark(BookmarkEventPayload::Created {
+                url: "https://example.com".to_owned(),
+                title: "Example".to_owned(),
+            }),
+            signature: None,
+            sealed: None,
+        };
+        let read_model = MemoryReadModel::new().with_file(path.path());
+        read_model.update(&event).unwrap();
+        read_model.save().unwrap();
+
+        let read_model = MemoryReadModel::new().with_file(path.path());
+
+        assert!(read_model.checkpoint().covers(&event));
+        assert_eq!(
+            read_model.read_bookmark("123").unwrap().url,
+            "https://example.com"
+        );
+        assert_eq!(read_model.update(&event), Ok(()));
+    }
 }
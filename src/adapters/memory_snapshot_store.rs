@@ -0,0 +1,59 @@
+use crate::{
+    domain::{data::BookmarkSnapshot, revocations::Revocations},
+    ports::SnapshotStore,
+};
+use std::{collections::HashMap, sync::Mutex};
+
+pub struct MemorySnapshotStore {
+    snapshots_by_id: Mutex<HashMap<String, BookmarkSnapshot>>,
+    revocations: Mutex<Option<Revocations>>,
+}
+
+impl MemorySnapshotStore {
+    pub fn new() -> Self {
+        Self {
+            snapshots_by_id: Mutex::new(HashMap::new()),
+            revocations: Mutex::new(None),
+        }
+    }
+}
+
+impl Default for MemorySnapshotStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SnapshotStore for MemorySnapshotStore {
+    fn load(&self, aggregate_id: &str) -> Option<BookmarkSnapshot> {
+        self.snapshots_by_id
+            .lock()
+            .unwrap()
+            .get(aggregate_id)
+            .cloned()
+    }
+
+    fn save(&self, snapshot: BookmarkSnapshot) {
+        self.snapshots_by_id
+            .lock()
+            .unwrap()
+            .insert(snapshot.aggregate.id.clone(), snapshot);
+    }
+
+    fn invalidate(&self, aggregate_id: &str) {
+        self.snapshots_by_id.lock().unwrap().remove(aggregate_id);
+    }
+
+    fn clear(&self) {
+        self.snapshots_by_id.lock().unwrap().clear();
+        *self.revocations.lock().unwrap() = None;
+    }
+
+    fn revocations(&self) -> Option<Revocations> {
+        self.revocations.lock().unwrap().clone()
+    }
+
+    fn save_revocations(&self, revocations: Revocations) {
+        *self.revocations.lock().unwrap() = Some(revocations);
+    }
+}
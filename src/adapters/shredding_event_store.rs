@@ -0,0 +1,373 @@
+use crate::{
+    domain::{
+        chain,
+        checkpoint::Checkpoint,
+        data::DomainEvent,
+        events::{BookmarkEventPayload, DomainEventPayload, SealedEventPayload},
+        reconciliation::EventIds,
+    },
+    ports::{AggregateKey, EventStore, EventStoreError, KeyStore},
+};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use std::sync::{Arc, Mutex};
+
+// Decorates an event store so that bookmark events are sealed with their
+// bookmark's own key before reaching it, and unsealed when read back if the
+// key is known. Storing or importing a `Forgotten` event destroys the key,
+// which leaves nothing but ciphertext of that bookmark in the log. It goes
+// around the signed store, so that the sealed form is what gets signed.
+pub struct ShreddingEventStore {
+    inner: Arc<dyn EventStore>,
+    key_store: Arc<dyn KeyStore>,
+    write_lock: Mutex<()>,
+}
+
+impl ShreddingEventStore {
+    pub fn new(inner: Arc<dyn EventStore>, key_store: Arc<dyn KeyStore>) -> Self {
+        Self {
+            inner,
+            key_store,
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    fn seal(&self, event: DomainEvent) -> Result<DomainEvent, EventStoreError> {
+        match &event.payload {
+            DomainEventPayload::Bookmark(BookmarkEventPayload::Forgotten) => return Ok(event),
+            DomainEventPayload::Bookmark(_) => {}
+            _ => return Ok(event),
+        }
+
+        let aggregate_id = &event.meta.aggregate_id;
+        let key = match self.key_store.key(aggregate_id) {
+            Some(key) => key,
+            None => {
+                let key: AggregateKey = ChaCha20Poly1305::generate_key(&mut OsRng).into();
+                self.key_store
+                    .save_key(aggregate_id, key)
+                    .map_err(|_source| EventStoreError::Generic)?;
+                key
+            }
+        };
+
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&key))
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &serde_json::to_vec(&event.payload).unwrap(),
+                    aad: &serde_json::to_vec(&event.meta).unwrap(),
+                },
+            )
+            .map_err(|_source| EventStoreError::Generic)?;
+
+        Ok(DomainEvent {
+            payload: DomainEventPayload::Sealed(SealedEventPayload {
+                nonce: hex::encode(nonce),
+                ciphertext: hex::encode(ciphertext),
+            }),
+            sealed: None,
+            ..event
+        })
+    }
+
+    fn forget_if_forgotten(&self, event: &DomainEvent) -> Result<(), EventStoreError> {
+        if event.payload == DomainEventPayload::Bookmark(BookmarkEventPayload::Forgotten) {
+            self.key_store
+                .destroy_key(&event.meta.aggregate_id)
+                .map_err(|_source| EventStoreError::Generic)?;
+        }
+        Ok(())
+    }
+}
+
+// Events that were unsealed elsewhere are stored in their sealed form
+fn resealed(event: DomainEvent) -> DomainEvent {
+    match event.sealed {
+        Some(sealed) => DomainEvent {
+            payload: DomainEventPayload::Sealed(sealed),
+            sealed: None,
+            ..event
+        },
+        None => event,
+    }
+}
+
+// Events whose key is unknown, or destroyed, are passed through sealed.
+fn unseal(key_store: &dyn KeyStore, event: DomainEvent) -> DomainEvent {
+    let sealed = match &event.payload {
+        DomainEventPayload::Sealed(sealed) => sealed.clone(),
+        _ => return event,
+    };
+    let payload = key_store
+        .key(&event.meta.aggregate_id)
+        .and_then(|key| open(&key, &sealed, &event));
+
+    match payload {
+        Some(payload) => DomainEvent {
+            payload,
+            sealed: Some(sealed),
+            ..event
+        },
+        None => event,
+    }
+}
+
+fn open(
+    key: &AggregateKey,
+    sealed: &SealedEventPayload,
+    event: &DomainEvent,
+) -> Option<DomainEventPayload> {
+    let nonce = hex::decode(&sealed.nonce).ok()?;
+    if nonce.len() != 12 {
+        return None;
+    }
+    let plaintext = ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &hex::decode(&sealed.ciphertext).ok()?,
+                aad: &serde_json::to_vec(&event.meta).unwrap(),
+            },
+        )
+        .ok()?;
+    serde_json::from_slice(&plaintext).ok()
+}
+
+impl EventStore for ShreddingEventStore {
+    // Sealing changes what events hash to, so each event after the first is
+    // linked to the sealed form of the one before it.
+    fn store_events(
+        &self,
+        events: Vec<DomainEvent>,
+        expected_version: Option<u64>,
+    ) -> Result<(), EventStoreError> {
+        let _write_lock = self.write_lock.lock().unwrap();
+        let events = chain::link(events, || self.chain_head());
+        let mut sealed: Vec<DomainEvent> = vec![];
+        for mut event in events.iter().cloned() {
+            if let Some(previous) = sealed.last() {
+                event.meta.previous_hash = Some(chain::event_hash(previous));
+            }
+            sealed.push(self.seal(event)?);
+        }
+        self.inner.store_events(sealed, expected_version)?;
+        for event in &events {
+            self.forget_if_forgotten(event)?;
+        }
+        Ok(())
+    }
+
+    // Imported events are never sealed here, as that would change what
+    // they're identified and signed as.
+    fn import_event(&self, event: DomainEvent) -> Result<(), EventStoreError> {
+        let event = resealed(event);
+        self.inner.import_event(event.clone())?;
+        self.forget_if_forgotten(&event)
+    }
+
+    fn replace_event(&self, event: DomainEvent) -> Result<(), EventStoreError> {
+        self.inner.replace_event(resealed(event))
+    }
+
+    fn get_events_for_aggregate(&self, aggregate_id: &str) -> Vec<DomainEvent> {
+        self.inner
+            .get_events_for_aggregate(aggregate_id)
+            .into_iter()
+            .map(|e| unseal(self.key_store.as_ref(), e))
+            .collect()
+    }
+
+    fn aggregate_version(&self, aggregate_id: &str) -> u64 {
+        self.inner.aggregate_version(aggregate_id)
+    }
+
+    fn events_iter(&self) -> Box<dyn Iterator<Item = DomainEvent>> {
+        let key_store = self.key_store.clone();
+        Box::new(
+            self.inner
+                .events_iter()
+                .map(move |e| unseal(key_store.as_ref(), e)),
+        )
+    }
+
+    fn events_after(&self, checkpoint: &Checkpoint) -> Box<dyn Iterator<Item = DomainEvent>> {
+        let key_store = self.key_store.clone();
+        Box::new(
+            self.inner
+                .events_after(checkpoint)
+                .map(move |e| unseal(key_store.as_ref(), e)),
+        )
+    }
+
+    // Events are identified by their sealed form, which is what the inner
+    // store holds.
+    fn event_ids(&self) -> EventIds {
+        self.inner.event_ids()
+    }
+
+    fn chain_head(&self) -> Option<DomainEvent> {
+        self.inner
+            .chain_head()
+            .map(|e| unseal(self.key_store.as_ref(), e))
+    }
+
+    fn instance_id(&self) -> String {
+        self.inner.instance_id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        adapters::{
+            encrypted_event_store::EncryptedEventStore, file_event_store::FileSystemEventStore,
+            memory_event_store::MemoryEventStore, memory_key_store::MemoryKeyStore,
+            signed_event_store::SignedEventStore,
+        },
+        domain::{chain, data::DomainEventMeta},
+    };
+    use assert_fs::{
+        fixture::{PathChild, PathCreateDir},
+        TempDir,
+    };
+    use ed25519_dalek::SigningKey;
+    use rand_core::OsRng;
+    use std::{collections::HashMap, time::SystemTime};
+
+    fn event(sequence: u64, payload: BookmarkEventPayload) -> DomainEvent {
+        DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: "123".to_owned(),
+                created_at: SystemTime::UNIX_EPOCH,
+                instance_id: "laptop".to_owned(),
+                sequence,
+                previous_hash: None,
+            },
+            payload: DomainEventPayload::Bookmark(payload),
+            signature: None,
+            sealed: None,
+        }
+    }
+
+    fn bookmark_created_event() -> DomainEvent {
+        event(
+            1,
+            BookmarkEventPayload::Created {
+                url: "https://example.com/private".to_owned(),
+                title: "Private".to_owned(),
+            },
+        )
+    }
+
+    #[test]
+    fn test_sealed_events_are_identified_alike_on_every_instance() {
+        let laptop_inner = Arc::new(MemoryEventStore::with_instance_id("laptop"));
+        let laptop_key_store = Arc::new(MemoryKeyStore::new());
+        let laptop = ShreddingEventStore::new(laptop_inner.clone(), laptop_key_store.clone());
+        let phone_key_store = Arc::new(MemoryKeyStore::new());
+        let phone = ShreddingEventStore::new(
+            Arc::new(MemoryEventStore::with_instance_id("phone")),
+            phone_key_store.clone(),
+        );
+
+        laptop
+            .store_events(vec![bookmark_created_event()], None)
+            .unwrap();
+        let stored = laptop.events_iter().next().unwrap();
+        phone.import_event(stored.clone()).unwrap();
+
+        assert!(matches!(
+            laptop_inner.events_iter().next().unwrap().payload,
+            DomainEventPayload::Sealed(_)
+        ));
+        assert_eq!(stored.payload, bookmark_created_event().payload);
+        let received = phone.events_iter().next().unwrap();
+        assert!(matches!(received.payload, DomainEventPayload::Sealed(_)));
+        assert_eq!(chain::event_hash(&received), chain::event_hash(&stored));
+        assert_eq!(phone.event_ids(), laptop.event_ids());
+
+        phone_key_store
+            .save_key("123", laptop_key_store.key("123").unwrap())
+            .unwrap();
+        assert_eq!(phone.events_iter().next().unwrap(), stored);
+    }
+
+    #[test]
+    fn test_events_are_identified_alike_through_every_decorator() {
+        let temp = TempDir::new().unwrap();
+        let laptop_key = SigningKey::generate(&mut OsRng);
+        let stack = |instance_id: &str, signing_key: SigningKey, trusted_keys| {
+            let folder = temp.child(instance_id);
+            folder.create_dir_all().unwrap();
+            ShreddingEventStore::new(
+                Arc::new(SignedEventStore::new(
+                    Arc::new(EncryptedEventStore::new(
+                        Arc::new(FileSystemEventStore::new(folder.as_os_str(), instance_id)),
+                        "passphrase",
+                        b"library salt",
+                    )),
+                    signing_key,
+                    trusted_keys,
+                )),
+                Arc::new(MemoryKeyStore::new()),
+            )
+        };
+        let laptop = stack("laptop", laptop_key.clone(), HashMap::new());
+        let phone = stack(
+            "phone",
+            SigningKey::generate(&mut OsRng),
+            HashMap::from([("laptop".to_owned(), laptop_key.verifying_key())]),
+        );
+
+        laptop
+            .store_events(
+                vec![
+                    bookmark_created_event(),
+                    event(
+                        2,
+                        BookmarkEventPayload::TitleUpdated {
+                            title: "Public".to_owned(),
+                        },
+                    ),
+                ],
+                None,
+            )
+            .unwrap();
+        for event in laptop.events_iter() {
+            phone.import_event(event).unwrap();
+        }
+
+        let ids = EventIds::from_events(laptop.events_iter());
+        assert_eq!(laptop.event_ids(), ids);
+        assert_eq!(phone.event_ids(), ids);
+        assert!(ids.contains(&chain::event_hash(&laptop.events_iter().last().unwrap())));
+    }
+
+    #[test]
+    fn test_forgotten_bookmark_leaves_only_ciphertext() {
+        let key_store = Arc::new(MemoryKeyStore::new());
+        let event_store =
+            ShreddingEventStore::new(Arc::new(MemoryEventStore::new()), key_store.clone());
+        event_store
+            .store_events(vec![bookmark_created_event()], None)
+            .unwrap();
+
+        event_store
+            .store_events(vec![event(2, BookmarkEventPayload::Forgotten)], None)
+            .unwrap();
+
+        let events = event_store.get_events_for_aggregate("123");
+        assert!(matches!(events[0].payload, DomainEventPayload::Sealed(_)));
+        assert_eq!(
+            events[1].payload,
+            DomainEventPayload::Bookmark(BookmarkEventPayload::Forgotten)
+        );
+        assert_eq!(key_store.key("123"), None);
+        assert!(key_store.save_key("123", [0; 32]).is_err());
+    }
+}
@@ -0,0 +1,523 @@
+use crate::{
+    domain::{
+        chain,
+        checkpoint::Checkpoint,
+        data::DomainEvent,
+        events::{DomainEventPayload, InstanceEventPayload},
+        reconciliation::{self, EventIds},
+    },
+    ports::{EventStore, EventStoreError, PeerAuthenticator},
+};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand_core::OsRng;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+};
+
+// Decorates an event store so that every locally stored event is signed
+// with the instance's key, and only events signed by trusted instances, or
+// adopted from before events were signed, are imported or replayed. Events
+// that fail verification on import are kept aside in a quarantine instead of
+// being dropped, once each, until they're imported after all.
+pub struct SignedEventStore {
+    inner: Arc<dyn EventStore>,
+    signing_key: SigningKey,
+    trusted_keys: RwLock<TrustedKeys>,
+    quarantine: Mutex<BTreeMap<String, DomainEvent>>,
+    quarantine_path: Option<PathBuf>,
+    write_lock: Mutex<()>,
+}
+
+impl SignedEventStore {
+    pub fn new(
+        inner: Arc<dyn EventStore>,
+        signing_key: SigningKey,
+        trusted_keys: HashMap<String, VerifyingKey>,
+    ) -> Self {
+        let mut trusted_keys = TrustedKeys {
+            keys: trusted_keys,
+            adopted: HashSet::new(),
+        };
+        trusted_keys
+            .keys
+            .insert(inner.instance_id(), signing_key.verifying_key());
+
+        Self {
+            inner,
+            signing_key,
+            trusted_keys: RwLock::new(trusted_keys),
+            quarantine: Mutex::new(BTreeMap::new()),
+            quarantine_path: None,
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    // Keeps the quarantine in a file, so that it survives restarts
+    pub fn with_quarantine_file(mut self, path: &Path) -> Self {
+        let quarantined: Vec<DomainEvent> = fs::read_to_string(path)
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default();
+        self.quarantine = Mutex::new(
+            quarantined
+                .into_iter()
+                .map(|e| (reconciliation::event_id(&e), e))
+                .collect(),
+        );
+        self.quarantine_path = Some(path.to_owned());
+        self
+    }
+
+    // Events written before signing was introduced aren't signed. Those in
+    // the log the first time the store is opened with this file are adopted
+    // as trusted, by ID, and no unsigned event is ever adopted after that.
+    pub fn with_adopted_events_file(self, path: &Path) -> Self {
+        let adopted: HashSet<String> = match fs::read_to_string(path) {
+            Ok(adopted) => serde_json::from_str(&adopted).unwrap_or_default(),
+            Err(_) => {
+                let adopted = self
+                    .inner
+                    .events_iter()
+                    .filter(|e| e.signature.is_none() && e.meta.sequence == 0)
+                    .map(|e| reconciliation::event_id(&e))
+                    .collect();
+                fs::write(path, serde_json::to_string_pretty(&adopted).unwrap()).unwrap();
+                adopted
+            }
+        };
+        self.trusted_keys.write().unwrap().adopted = adopted;
+        self
+    }
+
+    pub fn public_key(&self) -> String {
+        encode_key(&self.signing_key.verifying_key())
+    }
+
+    pub fn trust(&self, instance_id: &str, key: VerifyingKey) {
+        self.trusted_keys
+            .write()
+            .unwrap()
+            .keys
+            .insert(instance_id.to_owned(), key);
+    }
+
+    pub fn quarantined_events(&self) -> Vec<DomainEvent> {
+        self.quarantine.lock().unwrap().values().cloned().collect()
+    }
+
+    fn update_quarantine(&self, update: impl FnOnce(&mut BTreeMap<String, DomainEvent>) -> bool) {
+        let mut quarantine = self.quarantine.lock().unwrap();
+        if !update(&mut quarantine) {
+            return;
+        }
+        if let Some(path) = &self.quarantine_path {
+            let events: Vec<&DomainEvent> = quarantine.values().collect();
+            // Losing the quarantine only loses events that weren't trusted
+            let _ = fs::write(path, serde_json::to_string_pretty(&events).unwrap());
+        }
+    }
+}
+
+impl EventStore for SignedEventStore {
+    // Events are linked before being signed, as the link is part of what's
+    // signed.
+    fn store_events(
+        &self,
+        events: Vec<DomainEvent>,
+        expected_version: Option<u64>,
+    ) -> Result<(), EventStoreError> {
+        let _write_lock = self.write_lock.lock().unwrap();
+        let mut events = chain::link(events, || self.chain_head());
+        for event in &mut events {
+            event.signature = Some(hex::encode(
+                self.signing_key.sign(&signed_bytes(event)).to_bytes(),
+            ));
+        }
+        self.inner.store_events(events, expected_version)
+    }
+
+    fn import_event(&self, event: DomainEvent) -> Result<(), EventStoreError> {
+        let id = reconciliation::event_id(&event);
+        if self.trusted_keys.read().unwrap().verify(&event) {
+            self.inner.import_event(event)?;
+            self.update_quarantine(|quarantine| quarantine.remove(&id).is_some());
+            Ok(())
+        } else {
+            self.update_quarantine(|quarantine| quarantine.insert(id, event).is_none());
+            Err(EventStoreError::InvalidSignature)
+        }
+    }
+
+    // Replacements of this instance's events are signed anew, others must
+    // come signed by the instance that wrote the original.
+    fn replace_event(&self, mut event: DomainEvent) -> Result<(), EventStoreError> {
+        if event.meta.instance_id == self.inner.instance_id() {
+            event.signature = Some(hex::encode(
+                self.signing_key.sign(&signed_bytes(&event)).to_bytes(),
+            ));
+        } else if !self.trusted_keys.read().unwrap().verify(&event) {
+            return Err(EventStoreError::InvalidSignature);
+        }
+        self.inner.replace_event(event)
+    }
+
+    fn get_events_for_aggregate(&self, aggregate_id: &str) -> Vec<DomainEvent> {
+        let trusted_keys = self.trusted_keys.read().unwrap();
+        self.inner
+            .get_events_for_aggregate(aggregate_id)
+            .into_iter()
+            .filter(|e| trusted_keys.verify(e))
+            .collect()
+    }
+
+    fn aggregate_version(&self, aggregate_id: &str) -> u64 {
+        self.inner.aggregate_version(aggregate_id)
+    }
+
+    fn events_iter(&self) -> Box<dyn Iterator<Item = DomainEvent>> {
+        let trusted_keys = self.trusted_keys.read().unwrap().clone();
+        Box::new(
+            self.inner
+                .events_iter()
+                .filter(move |e| trusted_keys.verify(e)),
+        )
+    }
+
+    fn events_after(&self, checkpoint: &Checkpoint) -> Box<dyn Iterator<Item = DomainEvent>> {
+        let trusted_keys = self.trusted_keys.read().unwrap().clone();
+        Box::new(
+            self.inner
+                .events_after(checkpoint)
+                .filter(move |e| trusted_keys.verify(e)),
+        )
+    }
+
+    // Signatures aren't part of an event's ID. Events that aren't trusted
+    // yet are held all the same, so peers needn't send them again.
+    fn event_ids(&self) -> EventIds {
+        self.inner.event_ids()
+    }
+
+    // Only this instance's own events are linked after, which it always
+    // trusts, unless they were planted in its name.
+    fn chain_head(&self) -> Option<DomainEvent> {
+        let head = self.inner.chain_head()?;
+        if self.trusted_keys.read().unwrap().verify(&head) {
+            Some(head)
+        } else {
+            chain::head(&self.instance_id(), self.events_iter())
+        }
+    }
+
+    fn instance_id(&self) -> String {
+        self.inner.instance_id()
+    }
+}
+
+// Sync requests are signed with the same key as events, and accepted from
+// the same instances.
+impl PeerAuthenticator for SignedEventStore {
+    fn sign(&self, message: &[u8]) -> (String, String) {
+        (
+            self.inner.instance_id(),
+            hex::encode(self.signing_key.sign(message).to_bytes()),
+        )
+    }
+
+    fn verify(&self, instance_id: &str, message: &[u8], signature: &str) -> bool {
+        let signature = match hex::decode(signature)
+            .ok()
+            .and_then(|s| Signature::from_slice(&s).ok())
+        {
+            Some(signature) => signature,
+            None => return false,
+        };
+        self.trusted_keys
+            .read()
+            .unwrap()
+            .keys
+            .get(instance_id)
+            .is_some_and(|key| key.verify(message, &signature).is_ok())
+    }
+}
+
+#[derive(Clone)]
+struct TrustedKeys {
+    keys: HashMap<String, VerifyingKey>,
+    adopted: HashSet<String>,
+}
+
+impl TrustedKeys {
+    fn verify(&self, event: &DomainEvent) -> bool {
+        let signature = match event
+            .signature
+            .as_ref()
+            .and_then(|s| hex::decode(s).ok())
+            .and_then(|s| Signature::from_slice(&s).ok())
+        {
+            Some(signature) => signature,
+            None if event.signature.is_none() => {
+                return self.adopted.contains(&reconciliation::event_id(event))
+            }
+            None => return false,
+        };
+
+        // Key announcements are self-signed and let through even when not
+        // trusted yet, so that users can discover keys and decide to trust
+        // them.
+        let key = match (&event.payload, self.keys.get(&event.meta.instance_id)) {
+            (_, Some(key)) => *key,
+            (
+                DomainEventPayload::Instance(InstanceEventPayload::KeyPublished { public_key }),
+                None,
+            ) if event.meta.aggregate_id == event.meta.instance_id => {
+                match decode_key(public_key) {
+                    Some(key) => key,
+                    None => return false,
+                }
+            }
+            _ => return false,
+        };
+
+        key.verify(&signed_bytes(event), &signature).is_ok()
+    }
+}
+
+fn signed_bytes(event: &DomainEvent) -> Vec<u8> {
+    serde_json::to_vec(&(&event.meta, &event.payload)).unwrap()
+}
+
+pub fn encode_key(key: &VerifyingKey) -> String {
+    hex::encode(key.as_bytes())
+}
+
+pub fn decode_key(key: &str) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(key).ok()?.try_into().ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+// Like the instance ID, the signing key is kept next to (not inside) the
+// synced log folder.
+pub fn load_or_create_signing_key(path: &Path) -> SigningKey {
+    let existing = fs::read_to_string(path)
+        .ok()
+        .and_then(|key| hex::decode(key.trim()).ok())
+        .and_then(|key| <[u8; 32]>::try_from(key).ok());
+
+    match existing {
+        Some(bytes) => SigningKey::from_bytes(&bytes),
+        None => {
+            let key = SigningKey::generate(&mut OsRng);
+            fs::write(path, hex::encode(key.to_bytes())).unwrap();
+            key
+        }
+    }
+}
+
+pub fn load_trusted_keys(path: &Path) -> HashMap<String, VerifyingKey> {
+    let encoded: HashMap<String, String> = fs::read_to_string(path)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default();
+
+    encoded
+        .iter()
+        .filter_map(|(instance_id, key)| Some((instance_id.clone(), decode_key(key)?)))
+        .collect()
+}
+
+pub fn save_trusted_keys(path: &Path, trusted_keys: &HashMap<String, VerifyingKey>) {
+    let encoded: HashMap<&String, String> = trusted_keys
+        .iter()
+        .map(|(instance_id, key)| (instance_id, encode_key(key)))
+        .collect();
+    fs::write(path, serde_json::to_string_pretty(&encoded).unwrap()).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        adapters::{
+            clock::FakeClock, file_event_store::FileSystemEventStore,
+            memory_event_store::MemoryEventStore, memory_key_store::MemoryKeyStore,
+            memory_read_model::MemoryReadModel, memory_snapshot_store::MemorySnapshotStore,
+            shredding_event_store::ShreddingEventStore,
+        },
+        app,
+        domain::{data::DomainEventMeta, events::BookmarkEventPayload},
+        ports::Clock,
+    };
+    use assert_fs::fixture::{FileWriteStr, PathChild, PathCreateDir};
+
+    fn bookmark_created_event(instance_id: &str) -> DomainEvent {
+        DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: "123".to_owned(),
+                created_at: FakeClock::new().now(),
+                instance_id: instance_id.to_owned(),
+                sequence: 0,
+                previous_hash: None,
+            },
+            payload: DomainEventPayload::Bookmark(BookmarkEventPayload::Created {
+                url: "https://example.com".to_owned(),
+                title: "Example".to_owned(),
+            }),
+            signature: None,
+            sealed: None,
+        }
+    }
+
+    #[test]
+    fn test_events_signed_by_trusted_instance_can_be_imported() {
+        let phone_key = SigningKey::generate(&mut OsRng);
+        let phone_event_store = SignedEventStore::new(
+            Arc::new(MemoryEventStore::with_instance_id("phone")),
+            phone_key.clone(),
+            HashMap::new(),
+        );
+        let event_store = SignedEventStore::new(
+            Arc::new(MemoryEventStore::with_instance_id("laptop")),
+            SigningKey::generate(&mut OsRng),
+            HashMap::from([("phone".to_owned(), phone_key.verifying_key())]),
+        );
+
+        phone_event_store
+            .store_events(vec![bookmark_created_event("phone")], None)
+            .unwrap();
+        for event in phone_event_store.events_iter() {
+            event_store.import_event(event).unwrap();
+        }
+
+        assert_eq!(event_store.get_events_for_aggregate("123").len(), 1);
+    }
+
+    #[test]
+    fn test_forged_and_unsigned_events_are_quarantined() {
+        let event_store = SignedEventStore::new(
+            Arc::new(MemoryEventStore::with_instance_id("laptop")),
+            SigningKey::generate(&mut OsRng),
+            HashMap::new(),
+        );
+        let mut forged = bookmark_created_event("laptop");
+        forged.signature = Some(hex::encode(
+            SigningKey::generate(&mut OsRng)
+                .sign(&signed_bytes(&forged))
+                .to_bytes(),
+        ));
+
+        assert_eq!(
+            event_store.import_event(forged),
+            Err(EventStoreError::InvalidSignature)
+        );
+        assert_eq!(
+            event_store.import_event(bookmark_created_event("phone")),
+            Err(EventStoreError::InvalidSignature)
+        );
+        assert_eq!(event_store.quarantined_events().len(), 2);
+        assert_eq!(event_store.events_iter().count(), 0);
+    }
+
+    #[test]
+    fn test_quarantine_is_kept_across_restarts_without_duplicates() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let path = temp.path().join("quarantine.json");
+        let event_store = |key| {
+            SignedEventStore::new(
+                Arc::new(MemoryEventStore::with_instance_id("laptop")),
+                SigningKey::generate(&mut OsRng),
+                HashMap::from([("phone".to_owned(), key)]),
+            )
+            .with_quarantine_file(&path)
+        };
+        let phone_key = SigningKey::generate(&mut OsRng);
+        let mut event = bookmark_created_event("phone");
+        event.signature = Some(hex::encode(
+            phone_key.sign(&signed_bytes(&event)).to_bytes(),
+        ));
+
+        let untrusting = event_store(SigningKey::generate(&mut OsRng).verifying_key());
+        for _ in 0..2 {
+            let _ = untrusting.import_event(event.clone());
+        }
+        assert_eq!(
+            event_store(phone_key.verifying_key())
+                .quarantined_events()
+                .len(),
+            1
+        );
+
+        let trusting = event_store(phone_key.verifying_key());
+        trusting.import_event(event).unwrap();
+        assert_eq!(trusting.quarantined_events(), vec![]);
+    }
+
+    #[test]
+    fn test_unsigned_events_in_underlying_store_are_not_replayed() {
+        let inner = Arc::new(MemoryEventStore::with_instance_id("laptop"));
+        let event_store = SignedEventStore::new(
+            inner.clone(),
+            SigningKey::generate(&mut OsRng),
+            HashMap::new(),
+        );
+
+        inner
+            .store_events(vec![bookmark_created_event("laptop")], None)
+            .unwrap();
+        event_store
+            .store_events(vec![bookmark_created_event("laptop")], None)
+            .unwrap();
+
+        assert_eq!(inner.events_iter().count(), 2);
+        assert_eq!(event_store.events_iter().count(), 1);
+    }
+
+    #[test]
+    fn test_log_from_before_signing_is_adopted_once() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let log_folder = temp.child("log");
+        log_folder.create_dir_all().unwrap();
+        log_folder
+            .child("10000.json")
+            .write_str(include_str!(
+                "../../fixtures/events/unversioned-original.json"
+            ))
+            .unwrap();
+        let adopted_path = temp.path().join("adopted-events.json");
+        let file_event_store = Arc::new(FileSystemEventStore::new(
+            log_folder.path().as_os_str(),
+            "laptop",
+        ));
+        let signed_event_store = |signing_key: SigningKey| {
+            Arc::new(
+                SignedEventStore::new(file_event_store.clone(), signing_key, HashMap::new())
+                    .with_adopted_events_file(&adopted_path),
+            )
+        };
+        let bookmarks = |signed_event_store: Arc<SignedEventStore>| {
+            let read_model = Arc::new(MemoryReadModel::new());
+            app::init(
+                Arc::new(ShreddingEventStore::new(
+                    signed_event_store,
+                    Arc::new(MemoryKeyStore::new()),
+                )),
+                Arc::new(MemorySnapshotStore::new()),
+                read_model.clone(),
+            )
+            .unwrap();
+            app::read_bookmarks(read_model).unwrap()
+        };
+
+        let signing_key = SigningKey::generate(&mut OsRng);
+        assert_eq!(bookmarks(signed_event_store(signing_key.clone())).len(), 1);
+
+        let mut unsigned = bookmark_created_event("laptop");
+        unsigned.meta.aggregate_id = "456".to_owned();
+        file_event_store.import_event(unsigned).unwrap();
+
+        assert_eq!(bookmarks(signed_event_store(signing_key)).len(), 1);
+    }
+}
@@ -1,144 +1,680 @@
 use crate::{
-    domain::aggregates::BookmarkAggregate,
-    domain::commands::BookmarkCommand,
+    adapters::memory_read_model::MemoryReadModel,
+    domain::aggregates::{BookmarkAggregate, InstanceAggregate},
+    domain::commands::{BookmarkCommand, InstanceCommand},
     domain::errors::DomainError,
     domain::{
-        data::{Aggregate, BookmarkData, DomainEvent, DomainEventMeta},
-        events::DomainEventPayload,
+        chain::{self, ChainIssue},
+        checkpoint::Checkpoint,
+        compaction::{self, CompactionPlan},
+        data::{
+            Aggregate, BookmarkData, BookmarkHistoryEntry, BookmarkSnapshot, DomainEvent,
+            DomainEventMeta, InstanceSelection, PeerSyncData, TrashedBookmarkData,
+        },
+        events::{BookmarkEventPayload, DomainEventPayload, InstanceEventPayload},
+        reconciliation::{self, RangeComparison, RangeFingerprint},
+        revocations::Revocations,
+        sync::{self, HighWaterMarks},
+    },
+    ports::{
+        AggregateKey, Clock, EventStore, EventStoreError, KeyStore, KeyStoreError, ReadModel,
+        ReadModelError, SnapshotStore,
     },
-    ports::{Clock, EventStore, ReadModel},
 };
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+// Brings the read model up to date with whatever was stored since it was
+// last saved. Events that fail to project are left for later catch-ups to
+// retry rather than keeping the instance from starting.
+pub fn init(
+    event_store: Arc<dyn EventStore>,
+    snapshot_store: Arc<dyn SnapshotStore>,
+    read_model: Arc<dyn ReadModel>,
+) -> Result<(), ReadModelError> {
+    catch_up(event_store, snapshot_store, read_model)
+}
 
-pub fn init(event_store: Arc<dyn EventStore>, read_model: Arc<dyn ReadModel>) {
-    for event in event_store.events_iter() {
-        read_model.update(&event).unwrap(); // XXX handle error
+// Projects every event the read model hasn't been brought up to date with.
+// Once one fails, later events of its aggregate are held back along with it,
+// to be retried in order next time. Learning about an event that changes
+// which events count at all, e.g. one that arrived through the synced folder,
+// invalidates what was projected so far.
+pub fn catch_up(
+    event_store: Arc<dyn EventStore>,
+    snapshot_store: Arc<dyn SnapshotStore>,
+    read_model: Arc<dyn ReadModel>,
+) -> Result<(), ReadModelError> {
+    let checkpoint = read_model.checkpoint();
+    let events = unprojected(&event_store, &checkpoint);
+    if events.iter().any(reinterprets_log) {
+        snapshot_store.clear();
+        if checkpoint != Checkpoint::default() {
+            return rebuild(event_store, snapshot_store, read_model);
+        }
     }
+    project_all(events, &event_store, &snapshot_store, &read_model)
 }
 
-pub fn read_bookmark(id: &str, read_model: Arc<dyn ReadModel>) -> Option<BookmarkData> {
-    read_model.read_bookmark(id)
+// Learning about a revocation can invalidate events that were already
+// projected, so projections are rebuilt from scratch.
+fn rebuild(
+    event_store: Arc<dyn EventStore>,
+    snapshot_store: Arc<dyn SnapshotStore>,
+    read_model: Arc<dyn ReadModel>,
+) -> Result<(), ReadModelError> {
+    read_model.clear()?;
+    let events = unprojected(&event_store, &Checkpoint::default());
+    project_all(events, &event_store, &snapshot_store, &read_model)
 }
 
-pub fn read_bookmarks(read_model: Arc<dyn ReadModel>) -> Option<Vec<BookmarkData>> {
-    read_model.read_bookmarks()
+fn unprojected(event_store: &Arc<dyn EventStore>, checkpoint: &Checkpoint) -> Vec<DomainEvent> {
+    event_store
+        .events_after(checkpoint)
+        .filter(|e| !checkpoint.covers(e))
+        .collect()
+}
+
+fn project_all(
+    events: Vec<DomainEvent>,
+    event_store: &Arc<dyn EventStore>,
+    snapshot_store: &Arc<dyn SnapshotStore>,
+    read_model: &Arc<dyn ReadModel>,
+) -> Result<(), ReadModelError> {
+    let revocations = revocations(event_store, snapshot_store);
+    let mut failed_aggregates = BTreeSet::new();
+    let mut result = Ok(());
+    for event in events {
+        if failed_aggregates.contains(&event.meta.aggregate_id) {
+            continue;
+        }
+        if !revocations.allows(&event) {
+            read_model.skip(&event);
+        } else if let Err(err) = read_model.update(&event) {
+            failed_aggregates.insert(event.meta.aggregate_id.clone());
+            result = Err(err);
+        }
+    }
+    read_model.save()?;
+    result
+}
+
+// Worked out from the whole log, so it's only done again once the log has
+// been reinterpreted.
+fn revocations(
+    event_store: &Arc<dyn EventStore>,
+    snapshot_store: &Arc<dyn SnapshotStore>,
+) -> Revocations {
+    snapshot_store.revocations().unwrap_or_else(|| {
+        let revocations = Revocations::from_events(event_store.events_iter());
+        snapshot_store.save_revocations(revocations.clone());
+        revocations
+    })
+}
+
+// Number of events to pile up on top of a bookmark's snapshot before taking
+// a new one.
+const SNAPSHOT_INTERVAL: usize = 20;
+
+fn fold<A: Aggregate>(aggregate: A, events: &[DomainEvent]) -> A {
+    events.iter().fold(aggregate, |aggr, evt| {
+        match A::unwrap_payload(&evt.payload) {
+            Some(payload) => aggr.apply_event(payload, &evt.meta),
+            None if compaction::is_compacted(evt) => aggr.apply_compacted(&evt.meta),
+            None => aggr,
+        }
+    })
+}
+
+// How an aggregate is loaded to check commands against. By default it's
+// folded from all of its events.
+pub trait Repository: Aggregate {
+    fn load(
+        id: &str,
+        event_store: Arc<dyn EventStore>,
+        _snapshot_store: Arc<dyn SnapshotStore>,
+    ) -> Self {
+        fold(Self::new(id), &event_store.get_events_for_aggregate(id))
+    }
+}
+
+impl Repository for InstanceAggregate {}
+
+// Bookmarks are folded from their latest snapshot, if any, applying only the
+// events that came after it.
+impl Repository for BookmarkAggregate {
+    fn load(
+        id: &str,
+        event_store: Arc<dyn EventStore>,
+        snapshot_store: Arc<dyn SnapshotStore>,
+    ) -> Self {
+        let revocations = revocations(&event_store, &snapshot_store);
+        let events: Vec<DomainEvent> = event_store
+            .get_events_for_aggregate(id)
+            .into_iter()
+            .filter(|evt| revocations.allows(evt))
+            .collect();
+
+        // Events landing before what a snapshot covers shift the event it
+        // ends with, as does compacting that event.
+        let (bookmark, covered_events) = match snapshot_store.load(id) {
+            Some(snapshot)
+                if snapshot.covered_events > 0
+                    && events
+                        .get(snapshot.covered_events - 1)
+                        .is_some_and(|e| reconciliation::event_id(e) == snapshot.last_event_id) =>
+            {
+                (snapshot.aggregate, snapshot.covered_events)
+            }
+            _ => (BookmarkAggregate::new(id), 0),
+        };
+        let bookmark = fold(bookmark, &events[covered_events..]);
+
+        if let Some(last) = events.last() {
+            if events.len() - covered_events >= SNAPSHOT_INTERVAL {
+                snapshot_store.save(BookmarkSnapshot {
+                    aggregate: bookmark.clone(),
+                    covered_events: events.len(),
+                    last_event_id: reconciliation::event_id(last),
+                });
+            }
+        }
+
+        bookmark
+    }
+}
+
+// Events resulting from a single command, to be linked into this instance's
+// chain by the event store.
+fn new_events(
+    aggregate_id: &str,
+    payloads: Vec<DomainEventPayload>,
+    event_store: &Arc<dyn EventStore>,
+    clock: &Arc<dyn Clock>,
+) -> Vec<DomainEvent> {
+    let instance_id = event_store.instance_id();
+    let created_at = clock.now();
+    payloads
+        .into_iter()
+        .map(|payload| DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: aggregate_id.to_owned(),
+                created_at,
+                instance_id: instance_id.clone(),
+                sequence: 0,
+                previous_hash: None,
+            },
+            payload,
+            signature: None,
+            sealed: None,
+        })
+        .collect()
+}
+
+// Revocations and keys decide which events count at all, whereas
+// acknowledgements are only bookkeeping.
+fn reinterprets_log(event: &DomainEvent) -> bool {
+    matches!(
+        event.payload,
+        DomainEventPayload::Instance(
+            InstanceEventPayload::Revoked { .. } | InstanceEventPayload::KeyPublished { .. }
+        )
+    )
+}
+
+pub fn import_event(
+    event: DomainEvent,
+    event_store: Arc<dyn EventStore>,
+    snapshot_store: Arc<dyn SnapshotStore>,
+    read_model: Arc<dyn ReadModel>,
+) -> Result<(), DomainError> {
+    import_events(vec![event], event_store, snapshot_store, read_model)
+        .pop()
+        .unwrap()
+}
+
+// Imports a batch of events, e.g. as received from a peer, and projects them
+// all at once. Returns how importing each of them went.
+pub fn import_events(
+    events: Vec<DomainEvent>,
+    event_store: Arc<dyn EventStore>,
+    snapshot_store: Arc<dyn SnapshotStore>,
+    read_model: Arc<dyn ReadModel>,
+) -> Vec<Result<(), DomainError>> {
+    let mut held = event_store.event_ids();
+    let mut imported = vec![];
+    let mut results = vec![];
+    for event in events {
+        // Peers may offer events that were already received through another
+        // route, or what's left of them once their owner compacted them.
+        let hash = chain::event_hash(&event);
+        if event.meta.sequence > 0 && held.contains(&hash) {
+            results.push(replace_with_compacted(
+                event,
+                &hash,
+                &event_store,
+                &snapshot_store,
+            ));
+            continue;
+        }
+
+        let result = event_store
+            .import_event(event.clone())
+            .map_err(|_source| DomainError::PortError);
+        if result.is_ok() {
+            held.insert(&event);
+            imported.push(event);
+        }
+        results.push(result);
+    }
+    // Once stored, events are caught up with later if projecting them fails
+    if !imported.is_empty() {
+        let _ = project(&imported, event_store, snapshot_store, read_model);
+    }
+    results
+}
+
+fn replace_with_compacted(
+    event: DomainEvent,
+    hash: &str,
+    event_store: &Arc<dyn EventStore>,
+    snapshot_store: &Arc<dyn SnapshotStore>,
+) -> Result<(), DomainError> {
+    if !compaction::is_compacted(&event) {
+        return Ok(());
+    }
+    let held_uncompacted = event_store
+        .get_events_for_aggregate(&event.meta.aggregate_id)
+        .iter()
+        .any(|e| !compaction::is_compacted(e) && chain::event_hash(e) == hash);
+    if held_uncompacted {
+        event_store
+            .replace_event(event.clone())
+            .map_err(|_source| DomainError::PortError)?;
+        snapshot_store.invalidate(&event.meta.aggregate_id);
+    }
+    Ok(())
+}
+
+// Brings snapshots and the read model up to date with newly stored events.
+fn project(
+    events: &[DomainEvent],
+    event_store: Arc<dyn EventStore>,
+    snapshot_store: Arc<dyn SnapshotStore>,
+    read_model: Arc<dyn ReadModel>,
+) -> Result<(), ReadModelError> {
+    // Snapshots no longer hold for a forgotten bookmark, nor for any once an
+    // event changes which events are interpreted at all, which catching up
+    // takes care of. Those an event lands before are caught on loading.
+    for event in events {
+        if event.payload == DomainEventPayload::Bookmark(BookmarkEventPayload::Forgotten) {
+            snapshot_store.invalidate(&event.meta.aggregate_id);
+        }
+    }
+
+    catch_up(event_store, snapshot_store, read_model)
 }
 
-pub fn delete_bookmark(
+// Attempts at a command before giving up on an aggregate that keeps being
+// changed concurrently.
+const COMMAND_ATTEMPTS: usize = 3;
+
+// Every command goes through here: the aggregate is loaded, the command is
+// checked against it, and the resulting events are stored and projected.
+// They're only stored if the aggregate hasn't changed since it was loaded,
+// or since `expected_version` if given, in which case it's the caller's view
+// that's outdated and the command isn't retried.
+pub fn dispatch<A: Repository>(
     id: &str,
+    command: A::Command,
+    expected_version: Option<u64>,
     event_store: Arc<dyn EventStore>,
+    snapshot_store: Arc<dyn SnapshotStore>,
     read_model: Arc<dyn ReadModel>,
     clock: Arc<dyn Clock>,
 ) -> Result<(), DomainError> {
-    let bookmark = event_store.get_events_for_aggregate(&id).iter().fold(
-        BookmarkAggregate::new(&id),
-        |aggr, ref evt| match &evt.payload {
-            DomainEventPayload::Bookmark(payload) => aggr.apply_event(&payload, &evt.meta),
-            _ => aggr,
-        },
-    );
+    let mut attempts = 0;
+    let events = loop {
+        attempts += 1;
+        let version = expected_version.unwrap_or_else(|| event_store.aggregate_version(id));
+        let aggregate = A::load(id, event_store.clone(), snapshot_store.clone());
 
-    let event_payload = bookmark.handle_command(&BookmarkCommand::Delete)?;
+        let event_payloads = aggregate.handle_command(&command)?;
 
-    let event = DomainEvent {
-        meta: DomainEventMeta {
-            aggregate_id: id.to_owned(),
-            created_at: clock.now(),
-        },
-        payload: DomainEventPayload::Bookmark(event_payload),
-    };
+        let events = new_events(
+            id,
+            event_payloads.into_iter().map(A::wrap_payload).collect(),
+            &event_store,
+            &clock,
+        );
 
-    event_store
-        .store_event(event.clone())
-        .map_err(|_source| DomainError::PortError)?;
-    read_model
-        .update(&event)
-        .map_err(|_source| DomainError::PortError)?;
+        match event_store.store_events(events.clone(), Some(version)) {
+            Ok(()) => break events,
+            Err(EventStoreError::Conflict)
+                if expected_version.is_none() && attempts < COMMAND_ATTEMPTS => {}
+            Err(EventStoreError::Conflict) => return Err(DomainError::Conflict),
+            Err(_) => return Err(DomainError::PortError),
+        }
+    };
 
+    // Once stored, the command has succeeded: events that fail to be
+    // projected are caught up with later.
+    let _ = project(&events, event_store, snapshot_store, read_model);
     Ok(())
 }
 
-pub fn create_bookmark(
+// Changes whenever the bookmark does, for clients to tell whether what
+// they've read is still current.
+pub fn read_bookmark_version(id: &str, event_store: Arc<dyn EventStore>) -> u64 {
+    event_store.aggregate_version(id)
+}
+
+pub fn read_bookmark(id: &str, read_model: Arc<dyn ReadModel>) -> Option<BookmarkData> {
+    read_model.read_bookmark(id)
+}
+
+// Whether the bookmark was created for this page, whatever happened to it
+// since, as told by the log rather than the read model.
+pub fn was_bookmarked_as(
     id: &str,
     url: &str,
     title: &str,
     event_store: Arc<dyn EventStore>,
-    read_model: Arc<dyn ReadModel>,
-    clock: Arc<dyn Clock>,
-) -> Result<(), DomainError> {
-    let bookmark =
-        event_store
-            .get_events_for_aggregate(&id)
-            .iter()
-            .fold(BookmarkAggregate::new(&id), {
-                |aggr, ref evt| match &evt.payload {
-                    DomainEventPayload::Bookmark(payload) => aggr.apply_event(&payload, &evt.meta),
-                    _ => aggr,
-                }
-            });
-
-    let command = BookmarkCommand::BookmarkPage {
+) -> bool {
+    let created = DomainEventPayload::Bookmark(BookmarkEventPayload::Created {
         url: url.to_owned(),
         title: title.to_owned(),
-    };
-    let event_payload = bookmark.handle_command(&command)?;
+    });
+    event_store
+        .get_events_for_aggregate(id)
+        .iter()
+        .any(|e| e.payload == created)
+}
 
-    let event = DomainEvent {
-        meta: DomainEventMeta {
-            aggregate_id: id.to_owned(),
-            created_at: clock.now(),
-        },
-        payload: DomainEventPayload::Bookmark(event_payload),
-    };
+pub fn read_bookmarks(read_model: Arc<dyn ReadModel>) -> Option<Vec<BookmarkData>> {
+    read_model.read_bookmarks()
+}
 
-    event_store
-        .store_event(event.clone())
-        .map_err(|_source| DomainError::PortError)?;
-    read_model
-        .update(&event)
-        .map_err(|_source| DomainError::PortError)?;
+// Alternative interpretations of history are computed by replaying a
+// subset of the log into a throwaway read model, leaving the real one
+// untouched.
+fn read_bookmarks_interpreting(
+    event_store: Arc<dyn EventStore>,
+    include: impl Fn(&DomainEvent) -> bool,
+) -> Option<Vec<BookmarkData>> {
+    let events: Vec<DomainEvent> = event_store.events_iter().filter(include).collect();
+    let revocations = Revocations::from_events(events.iter().cloned());
+    let read_model = MemoryReadModel::new();
+    for event in events.iter().filter(|e| revocations.allows(e)) {
+        read_model.update(event).ok()?;
+    }
+    read_model.read_bookmarks()
+}
 
-    Ok(())
+pub fn read_bookmarks_as_of(
+    as_of: SystemTime,
+    event_store: Arc<dyn EventStore>,
+) -> Option<Vec<BookmarkData>> {
+    read_bookmarks_interpreting(event_store, |e| e.meta.created_at <= as_of)
+}
+
+pub fn read_bookmarks_from_instances(
+    selection: &InstanceSelection,
+    event_store: Arc<dyn EventStore>,
+) -> Option<Vec<BookmarkData>> {
+    read_bookmarks_interpreting(event_store, |e| selection.includes(&e.meta.instance_id))
 }
 
-pub fn update_bookmark_title(
+pub fn read_bookmark_history(
     id: &str,
-    title: &str,
     event_store: Arc<dyn EventStore>,
+) -> Option<Vec<BookmarkHistoryEntry>> {
+    let events = event_store.get_events_for_aggregate(id);
+    if events.is_empty() {
+        return None;
+    }
+
+    let mut bookmark = BookmarkAggregate::new(id);
+    let mut history = vec![];
+    for evt in events {
+        if let DomainEventPayload::Bookmark(payload) = &evt.payload {
+            history.push(BookmarkHistoryEntry {
+                created_at: evt.meta.created_at,
+                instance_id: evt.meta.instance_id.clone(),
+                change: bookmark.describe_event(payload),
+            });
+            bookmark = bookmark.apply_event(payload, &evt.meta);
+        }
+    }
+
+    Some(history)
+}
+
+pub fn verify_chains(event_store: Arc<dyn EventStore>) -> Vec<ChainIssue> {
+    chain::verify_chains(event_store.events_iter())
+}
+
+// Other instances only, as this one trivially has all of its own events.
+pub fn read_sync_peers(
+    event_store: Arc<dyn EventStore>,
+    read_model: Arc<dyn ReadModel>,
+) -> Option<Vec<PeerSyncData>> {
+    let instance_id = event_store.instance_id();
+    let peers = read_model.read_peers()?;
+    Some(
+        peers
+            .into_iter()
+            .filter(|p| p.instance_id != instance_id)
+            .collect(),
+    )
+}
+
+pub fn read_sync_summary(event_store: Arc<dyn EventStore>) -> HighWaterMarks {
+    sync::high_water_marks(event_store.events_iter())
+}
+
+pub fn read_events_missing_from(
+    high_water_marks: &HighWaterMarks,
+    event_store: Arc<dyn EventStore>,
+) -> Vec<DomainEvent> {
+    sync::events_missing_from(high_water_marks, event_store.events_iter())
+}
+
+pub fn read_event_ranges(
+    prefixes: &[String],
+    event_store: Arc<dyn EventStore>,
+) -> Vec<RangeFingerprint> {
+    let event_ids = event_store.event_ids();
+    prefixes.iter().map(|p| event_ids.fingerprint(p)).collect()
+}
+
+pub fn compare_event_ranges(
+    remote: &[RangeFingerprint],
+    event_store: Arc<dyn EventStore>,
+) -> RangeComparison {
+    event_store.event_ids().compare(remote)
+}
+
+pub fn read_events_by_id(ids: &[String], event_store: Arc<dyn EventStore>) -> Vec<DomainEvent> {
+    event_store
+        .events_iter()
+        .filter(|e| ids.contains(&reconciliation::event_id(e)))
+        .collect()
+}
+
+// Bookmarks with events this instance can't unseal, because it hasn't
+// received their key yet or because they were forgotten.
+pub fn read_sealed_aggregates(event_store: Arc<dyn EventStore>) -> Vec<String> {
+    let aggregate_ids: BTreeSet<String> = event_store
+        .events_iter()
+        .filter(|e| matches!(e.payload, DomainEventPayload::Sealed(_)))
+        .map(|e| e.meta.aggregate_id)
+        .collect();
+    aggregate_ids.into_iter().collect()
+}
+
+pub fn export_keys(
+    aggregate_ids: &[String],
+    key_store: Arc<dyn KeyStore>,
+) -> BTreeMap<String, String> {
+    aggregate_ids
+        .iter()
+        .filter_map(|id| Some((id.clone(), hex::encode(key_store.key(id)?))))
+        .collect()
+}
+
+// Keys of forgotten bookmarks are turned down. Returns the number of keys
+// imported.
+pub fn import_keys(
+    keys: &BTreeMap<String, String>,
+    event_store: Arc<dyn EventStore>,
+    snapshot_store: Arc<dyn SnapshotStore>,
+    key_store: Arc<dyn KeyStore>,
+    read_model: Arc<dyn ReadModel>,
+) -> Result<usize, DomainError> {
+    let mut imported = 0;
+    for (aggregate_id, key) in keys {
+        if key_store.key(aggregate_id).is_some() {
+            continue;
+        }
+        let key: AggregateKey = match hex::decode(key).ok().and_then(|k| k.try_into().ok()) {
+            Some(key) => key,
+            None => continue,
+        };
+        match key_store.save_key(aggregate_id, key) {
+            Ok(()) => imported += 1,
+            Err(KeyStoreError::Destroyed) => continue,
+            Err(_) => return Err(DomainError::PortError),
+        }
+        snapshot_store.invalidate(aggregate_id);
+    }
+
+    // Events that couldn't be read so far now can
+    if imported > 0 {
+        rebuild(event_store, snapshot_store, read_model)
+            .map_err(|_source| DomainError::PortError)?;
+    }
+    Ok(imported)
+}
+
+pub fn read_trash(read_model: Arc<dyn ReadModel>) -> Option<Vec<TrashedBookmarkData>> {
+    read_model.read_trash()
+}
+
+// Purging is recorded in the log, so that purged bookmarks stay out of the
+// trash after a restart and can no longer be restored.
+pub fn purge_trash(
+    retention: Duration,
+    event_store: Arc<dyn EventStore>,
+    snapshot_store: Arc<dyn SnapshotStore>,
     read_model: Arc<dyn ReadModel>,
     clock: Arc<dyn Clock>,
 ) -> Result<(), DomainError> {
-    let bookmark = event_store.get_events_for_aggregate(&id).iter().fold(
-        BookmarkAggregate::new(&id),
-        |aggr, ref evt| match &evt.payload {
-            DomainEventPayload::Bookmark(payload) => aggr.apply_event(&payload, &evt.meta),
-            _ => aggr,
-        },
-    );
+    let deleted_before = clock
+        .now()
+        .checked_sub(retention)
+        .ok_or(DomainError::PortError)?;
 
-    let command = BookmarkCommand::UpdateTitle {
-        title: title.to_owned(),
-    };
-    let event_payload = bookmark.handle_command(&command)?;
+    let trash = read_model.read_trash().ok_or(DomainError::PortError)?;
+    for trashed in trash.iter().filter(|t| t.deleted_at < deleted_before) {
+        dispatch::<BookmarkAggregate>(
+            &trashed.bookmark.id,
+            BookmarkCommand::Purge,
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )?;
+    }
+    Ok(())
+}
 
-    let event = DomainEvent {
-        meta: DomainEventMeta {
-            aggregate_id: id.to_owned(),
-            created_at: clock.now(),
-        },
-        payload: DomainEventPayload::Bookmark(event_payload),
+// Publishes this instance's high-water marks if it has seen deletions it
+// hasn't acknowledged yet, so that other instances can tell when it's safe
+// to compact them. Returns whether anything was published.
+pub fn acknowledge_deletions(
+    event_store: Arc<dyn EventStore>,
+    snapshot_store: Arc<dyn SnapshotStore>,
+    read_model: Arc<dyn ReadModel>,
+    clock: Arc<dyn Clock>,
+) -> Result<bool, DomainError> {
+    let instance_id = event_store.instance_id();
+    let events: Vec<DomainEvent> = event_store.events_iter().collect();
+    let high_water_marks = match compaction::pending_acknowledgement(&events, &instance_id) {
+        Some(high_water_marks) => high_water_marks,
+        None => return Ok(false),
     };
 
-    // TODO no error should be returned here since command has been
-    // already validated
-    event_store
-        .store_event(event.clone())
-        .map_err(|_source| DomainError::PortError)?;
-    read_model
-        .update(&event)
-        .map_err(|_source| DomainError::PortError)?;
+    dispatch::<InstanceAggregate>(
+        &instance_id,
+        InstanceCommand::Acknowledge { high_water_marks },
+        None,
+        event_store,
+        snapshot_store,
+        read_model,
+        clock,
+    )?;
+    Ok(true)
+}
 
-    Ok(())
+// Compacts the history of bookmarks deleted more than `retention` ago,
+// once every known instance has acknowledged it. Only this instance's own
+// events are rewritten, leaving stubs that peers pick up when syncing.
+pub fn compact(
+    retention: Duration,
+    event_store: Arc<dyn EventStore>,
+    snapshot_store: Arc<dyn SnapshotStore>,
+    read_model: Arc<dyn ReadModel>,
+    clock: Arc<dyn Clock>,
+) -> Result<CompactionPlan, DomainError> {
+    let deleted_before = clock
+        .now()
+        .checked_sub(retention)
+        .ok_or(DomainError::PortError)?;
+    let instance_id = event_store.instance_id();
+    let events: Vec<DomainEvent> = event_store.events_iter().collect();
+    let plan = compaction::plan_compaction(&events, &instance_id, deleted_before);
+
+    for aggregate_id in &plan.compactable {
+        for event in events.iter().filter(|e| {
+            e.meta.aggregate_id == *aggregate_id
+                && e.meta.instance_id == instance_id
+                && !compaction::is_compacted(e)
+        }) {
+            event_store
+                .replace_event(compaction::compacted(event))
+                .map_err(|_source| DomainError::PortError)?;
+        }
+        snapshot_store.invalidate(aggregate_id);
+    }
+
+    if !plan.compactable.is_empty() {
+        rebuild(event_store, snapshot_store, read_model)
+            .map_err(|_source| DomainError::PortError)?;
+    }
+    Ok(plan)
+}
+
+// Every distinct key published in an instance's name. Announcements are let
+// through before their key is trusted, so anyone can publish one in another
+// instance's name; more than one key means some of them can't be told apart
+// from an impostor's.
+pub fn read_published_keys(instance_id: &str, event_store: Arc<dyn EventStore>) -> Vec<String> {
+    let mut keys: Vec<String> = vec![];
+    for event in event_store.get_events_for_aggregate(instance_id) {
+        if let DomainEventPayload::Instance(InstanceEventPayload::KeyPublished { public_key }) =
+            event.payload
+        {
+            if !keys.contains(&public_key) {
+                keys.push(public_key);
+            }
+        }
+    }
+    keys
 }
 
 #[cfg(test)]
@@ -147,22 +683,79 @@ mod tests {
     use crate::{
         adapters::{
             clock::FakeClock, memory_event_store::MemoryEventStore,
-            memory_read_model::MemoryReadModel,
+            memory_key_store::MemoryKeyStore, memory_read_model::MemoryReadModel,
+            memory_snapshot_store::MemorySnapshotStore, shredding_event_store::ShreddingEventStore,
         },
         domain::data::BookmarkData,
     };
+    use std::sync::Mutex;
+    use uuid::Uuid;
+
+    // Fails to project the first `failures` events it's given.
+    struct FlakyReadModel {
+        inner: MemoryReadModel,
+        failures: Mutex<usize>,
+    }
+
+    impl ReadModel for FlakyReadModel {
+        fn update(&self, event: &DomainEvent) -> Result<(), ReadModelError> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(ReadModelError::Generic);
+            }
+            self.inner.update(event)
+        }
+
+        fn skip(&self, event: &DomainEvent) {
+            self.inner.skip(event)
+        }
+
+        fn checkpoint(&self) -> Checkpoint {
+            self.inner.checkpoint()
+        }
+
+        fn save(&self) -> Result<(), ReadModelError> {
+            self.inner.save()
+        }
+
+        fn read_bookmark(&self, id: &str) -> Option<BookmarkData> {
+            self.inner.read_bookmark(id)
+        }
+
+        fn read_bookmarks(&self) -> Option<Vec<BookmarkData>> {
+            self.inner.read_bookmarks()
+        }
+
+        fn read_trash(&self) -> Option<Vec<TrashedBookmarkData>> {
+            self.inner.read_trash()
+        }
+
+        fn read_peers(&self) -> Option<Vec<PeerSyncData>> {
+            self.inner.read_peers()
+        }
+
+        fn clear(&self) -> Result<(), ReadModelError> {
+            self.inner.clear()
+        }
+    }
 
     #[test]
     fn test_created_bookmark_can_be_retrieved() {
         let event_store = Arc::new(MemoryEventStore::new());
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
         let read_model = Arc::new(MemoryReadModel::new());
         let clock = Arc::new(FakeClock::new());
 
-        create_bookmark(
+        dispatch::<BookmarkAggregate>(
             "123",
-            "http://bar",
-            "bar",
+            BookmarkCommand::BookmarkPage {
+                url: "http://bar".to_owned(),
+                title: "bar".to_owned(),
+            },
+            None,
             event_store.clone(),
+            snapshot_store.clone(),
             read_model.clone(),
             clock.clone(),
         )
@@ -176,40 +769,127 @@ mod tests {
                 id: "123".to_owned(),
                 url: "http://bar".to_owned(),
                 title: "bar".to_owned(),
+                previous_urls: vec![],
+                note: "".to_owned(),
             }
         )
     }
 
+    #[test]
+    fn test_events_of_a_command_are_chained_and_projected_in_order() {
+        let event_store: Arc<dyn EventStore> = Arc::new(ShreddingEventStore::new(
+            Arc::new(MemoryEventStore::new()),
+            Arc::new(MemoryKeyStore::new()),
+        ));
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let read_model = Arc::new(MemoryReadModel::new());
+        let clock = Arc::new(FakeClock::new());
+
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::Import {
+                url: "http://bar".to_owned(),
+                title: "bar".to_owned(),
+                note: "read later".to_owned(),
+                edit_id: "edit".to_owned(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+
+        assert_eq!(event_store.events_iter().count(), 2);
+        assert_eq!(verify_chains(event_store.clone()), vec![]);
+        assert_eq!(read_bookmark("123", read_model).unwrap().note, "read later");
+    }
+
+    #[test]
+    fn test_concurrent_commands_extend_the_chain_without_forking_it() {
+        let event_store: Arc<dyn EventStore> = Arc::new(ShreddingEventStore::new(
+            Arc::new(MemoryEventStore::new()),
+            Arc::new(MemoryKeyStore::new()),
+        ));
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let read_model = Arc::new(MemoryReadModel::new());
+        let clock = Arc::new(FakeClock::new());
+
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let event_store = event_store.clone();
+                let snapshot_store = snapshot_store.clone();
+                let read_model = read_model.clone();
+                let clock = clock.clone();
+                scope.spawn(move || {
+                    for i in 0..10 {
+                        dispatch::<BookmarkAggregate>(
+                            &format!("{}-{}", thread, i),
+                            BookmarkCommand::BookmarkPage {
+                                url: "http://bar".to_owned(),
+                                title: "bar".to_owned(),
+                            },
+                            None,
+                            event_store.clone(),
+                            snapshot_store.clone(),
+                            read_model.clone(),
+                            clock.clone(),
+                        )
+                        .unwrap();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(event_store.events_iter().count(), 40);
+        assert_eq!(verify_chains(event_store.clone()), vec![]);
+        assert_eq!(read_bookmarks(read_model).unwrap().len(), 40);
+    }
+
     #[test]
     fn test_bookmark_list_can_be_retrieved() {
         let event_store = Arc::new(MemoryEventStore::new());
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
         let read_model = Arc::new(MemoryReadModel::new());
         let clock = Arc::new(FakeClock::new());
 
-        create_bookmark(
+        dispatch::<BookmarkAggregate>(
             "123",
-            "http://bar",
-            "bar",
+            BookmarkCommand::BookmarkPage {
+                url: "http://bar".to_owned(),
+                title: "bar".to_owned(),
+            },
+            None,
             event_store.clone(),
+            snapshot_store.clone(),
             read_model.clone(),
             clock.clone(),
         )
         .unwrap();
 
-        create_bookmark(
+        dispatch::<BookmarkAggregate>(
             "456",
-            "http://foo",
-            "foo",
+            BookmarkCommand::BookmarkPage {
+                url: "http://foo".to_owned(),
+                title: "foo".to_owned(),
+            },
+            None,
             event_store.clone(),
+            snapshot_store.clone(),
             read_model.clone(),
             clock.clone(),
         )
         .unwrap();
 
-        update_bookmark_title(
+        dispatch::<BookmarkAggregate>(
             "456",
-            "foobar",
+            BookmarkCommand::UpdateTitle {
+                title: "foobar".to_owned(),
+            },
+            None,
             event_store.clone(),
+            snapshot_store.clone(),
             read_model.clone(),
             clock.clone(),
         )
@@ -224,78 +904,1168 @@ mod tests {
     }
 
     #[test]
-    fn test_bookmark_title_can_be_updated() {
+    fn test_bookmark_list_can_be_retrieved_as_of_past_instant() {
         let event_store = Arc::new(MemoryEventStore::new());
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
         let read_model = Arc::new(MemoryReadModel::new());
         let clock = Arc::new(FakeClock::new());
 
-        create_bookmark(
+        dispatch::<BookmarkAggregate>(
             "123",
-            "http://bar",
-            "bar",
+            BookmarkCommand::BookmarkPage {
+                url: "http://bar".to_owned(),
+                title: "bar".to_owned(),
+            },
+            None,
             event_store.clone(),
+            snapshot_store.clone(),
             read_model.clone(),
             clock.clone(),
         )
         .unwrap();
-
-        update_bookmark_title(
+        clock.advance(Duration::from_secs(10));
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::UpdateTitle {
+                title: "foo".to_owned(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+        clock.advance(Duration::from_secs(10));
+        dispatch::<BookmarkAggregate>(
             "123",
-            "foo",
+            BookmarkCommand::Delete,
+            None,
             event_store.clone(),
+            snapshot_store.clone(),
             read_model.clone(),
             clock.clone(),
         )
         .unwrap();
 
-        let bookmark = read_bookmark("123", read_model.clone()).unwrap();
+        let bookmarks = read_bookmarks_as_of(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(15),
+            event_store.clone(),
+        )
+        .unwrap();
 
-        assert_eq!(bookmark.title, "foo");
+        assert_eq!(bookmarks.len(), 1);
+        assert_eq!(bookmarks[0].title, "foo");
+        assert!(read_bookmarks(read_model.clone()).unwrap().is_empty());
     }
 
     #[test]
-    fn test_deleted_bookmark_cannot_be_retrieved() {
-        let event_store = Arc::new(MemoryEventStore::new());
+    fn test_bookmark_list_can_be_interpreted_without_an_instance() {
+        let event_store = Arc::new(MemoryEventStore::with_instance_id("laptop"));
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let phone_event_store = Arc::new(MemoryEventStore::with_instance_id("phone"));
         let read_model = Arc::new(MemoryReadModel::new());
         let clock = Arc::new(FakeClock::new());
 
-        create_bookmark(
+        dispatch::<BookmarkAggregate>(
             "123",
-            "http://bar",
-            "bar",
+            BookmarkCommand::BookmarkPage {
+                url: "http://bar".to_owned(),
+                title: "bar".to_owned(),
+            },
+            None,
             event_store.clone(),
+            snapshot_store.clone(),
             read_model.clone(),
             clock.clone(),
         )
         .unwrap();
-
-        delete_bookmark(
+        clock.advance(Duration::from_secs(10));
+        phone_event_store
+            .import_event(event_store.get_events_for_aggregate("123")[0].clone())
+            .unwrap();
+        dispatch::<BookmarkAggregate>(
             "123",
-            event_store.clone(),
-            read_model.clone(),
+            BookmarkCommand::UpdateTitle {
+                title: "spam".to_owned(),
+            },
+            None,
+            phone_event_store.clone(),
+            Arc::new(MemorySnapshotStore::new()),
+            Arc::new(MemoryReadModel::new()),
+            clock.clone(),
+        )
+        .unwrap();
+        dispatch::<BookmarkAggregate>(
+            "456",
+            BookmarkCommand::BookmarkPage {
+                url: "http://spam".to_owned(),
+                title: "spam".to_owned(),
+            },
+            None,
+            phone_event_store.clone(),
+            Arc::new(MemorySnapshotStore::new()),
+            Arc::new(MemoryReadModel::new()),
             clock.clone(),
         )
         .unwrap();
+        for event in phone_event_store.events_iter().skip(1) {
+            event_store.import_event(event.clone()).unwrap();
+            read_model.update(&event).unwrap();
+        }
 
-        let bookmark = read_bookmark("123", read_model.clone());
+        let without_phone = read_bookmarks_from_instances(
+            &InstanceSelection::Excluding(vec!["phone".to_owned()]),
+            event_store.clone(),
+        )
+        .unwrap();
+        let only_phone = read_bookmarks_from_instances(
+            &InstanceSelection::Only(vec!["phone".to_owned()]),
+            event_store.clone(),
+        )
+        .unwrap();
 
-        assert_eq!(bookmark, None)
+        assert_eq!(without_phone.len(), 1);
+        assert_eq!(without_phone[0].title, "bar");
+        assert_eq!(only_phone.len(), 1);
+        assert_eq!(only_phone[0].id, "456");
+        assert_eq!(read_bookmarks(read_model.clone()).unwrap().len(), 2);
     }
 
     #[test]
-    fn test_deleting_non_existent_bookmark_is_rejected() {
+    fn test_failed_projection_is_caught_up_with_later() {
         let event_store = Arc::new(MemoryEventStore::new());
-        let read_model = Arc::new(MemoryReadModel::new());
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let read_model = Arc::new(FlakyReadModel {
+            inner: MemoryReadModel::new(),
+            failures: Mutex::new(1),
+        });
         let clock = Arc::new(FakeClock::new());
 
-        let err = delete_bookmark(
-            "123",
-            event_store.clone(),
-            read_model.clone(),
-            clock.clone(),
-        )
-        .unwrap_err();
+        for (id, title) in [("123", "bar"), ("456", "foo")] {
+            dispatch::<BookmarkAggregate>(
+                id,
+                BookmarkCommand::BookmarkPage {
+                    url: "http://bar".to_owned(),
+                    title: title.to_owned(),
+                },
+                None,
+                event_store.clone(),
+                snapshot_store.clone(),
+                read_model.clone(),
+                clock.clone(),
+            )
+            .unwrap();
+
+            if id == "123" {
+                assert_eq!(read_bookmark("123", read_model.clone()), None);
+            }
+        }
+
+        let bookmarks = read_bookmarks(read_model).unwrap();
 
+        assert_eq!(bookmarks.len(), 2);
+        assert_eq!(bookmarks[0].title, "bar");
+    }
+
+    #[test]
+    fn test_events_after_a_failed_projection_wait_for_it() {
+        let event_store = Arc::new(MemoryEventStore::new());
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let read_model = Arc::new(FlakyReadModel {
+            inner: MemoryReadModel::new(),
+            failures: Mutex::new(2),
+        });
+        let clock = Arc::new(FakeClock::new());
+
+        for command in [
+            BookmarkCommand::BookmarkPage {
+                url: "http://bar".to_owned(),
+                title: "bar".to_owned(),
+            },
+            BookmarkCommand::UpdateTitle {
+                title: "foo".to_owned(),
+            },
+        ] {
+            dispatch::<BookmarkAggregate>(
+                "123",
+                command,
+                None,
+                event_store.clone(),
+                snapshot_store.clone(),
+                read_model.clone(),
+                clock.clone(),
+            )
+            .unwrap();
+        }
+        assert_eq!(read_bookmark("123", read_model.clone()), None);
+
+        catch_up(event_store, snapshot_store, read_model.clone()).unwrap();
+
+        assert_eq!(read_bookmark("123", read_model).unwrap().title, "foo");
+    }
+
+    #[test]
+    fn test_instances_creating_the_same_id_agree_on_the_first() {
+        let laptop_event_store = Arc::new(MemoryEventStore::with_instance_id("laptop"));
+        let phone_event_store = Arc::new(MemoryEventStore::with_instance_id("phone"));
+        let clock = Arc::new(FakeClock::new());
+        let mut instances = vec![];
+
+        // Clients choosing the same ID on different instances
+        for store in [&laptop_event_store, &phone_event_store] {
+            let snapshot_store = Arc::new(MemorySnapshotStore::new());
+            let read_model = Arc::new(MemoryReadModel::new());
+            clock.advance(Duration::from_secs(1));
+            dispatch::<BookmarkAggregate>(
+                "123",
+                BookmarkCommand::BookmarkPage {
+                    url: format!("http://{}", store.instance_id()),
+                    title: "bar".to_owned(),
+                },
+                None,
+                store.clone(),
+                snapshot_store.clone(),
+                read_model.clone(),
+                clock.clone(),
+            )
+            .unwrap();
+            instances.push((store.clone(), snapshot_store, read_model));
+        }
+        let laptop_events: Vec<DomainEvent> = laptop_event_store.events_iter().collect();
+        let phone_events: Vec<DomainEvent> = phone_event_store.events_iter().collect();
+        for ((event_store, snapshot_store, read_model), events) in
+            instances.iter().zip([phone_events, laptop_events])
+        {
+            for event in events {
+                import_event(
+                    event,
+                    event_store.clone(),
+                    snapshot_store.clone(),
+                    read_model.clone(),
+                )
+                .unwrap();
+            }
+        }
+
+        for (event_store, snapshot_store, read_model) in instances {
+            assert_eq!(
+                read_bookmark("123", read_model.clone()).unwrap().url,
+                "http://laptop"
+            );
+            // Nothing to change from the aggregate's point of view either
+            clock.advance(Duration::from_secs(1));
+            dispatch::<BookmarkAggregate>(
+                "123",
+                BookmarkCommand::UpdateUrl {
+                    url: "http://laptop".to_owned(),
+                },
+                None,
+                event_store.clone(),
+                snapshot_store,
+                read_model.clone(),
+                clock.clone(),
+            )
+            .unwrap();
+            assert_eq!(event_store.events_iter().count(), 2);
+            assert!(read_bookmark("123", read_model)
+                .unwrap()
+                .previous_urls
+                .is_empty());
+        }
+    }
+
+    #[test]
+    fn test_bookmark_title_can_be_updated() {
+        let event_store = Arc::new(MemoryEventStore::new());
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let read_model = Arc::new(MemoryReadModel::new());
+        let clock = Arc::new(FakeClock::new());
+
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::BookmarkPage {
+                url: "http://bar".to_owned(),
+                title: "bar".to_owned(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::UpdateTitle {
+                title: "foo".to_owned(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+
+        let bookmark = read_bookmark("123", read_model.clone()).unwrap();
+
+        assert_eq!(bookmark.title, "foo");
+    }
+
+    #[test]
+    fn test_command_against_outdated_version_is_rejected() {
+        let event_store = Arc::new(MemoryEventStore::new());
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let read_model = Arc::new(MemoryReadModel::new());
+        let clock = Arc::new(FakeClock::new());
+
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::BookmarkPage {
+                url: "http://bar".to_owned(),
+                title: "bar".to_owned(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+        let seen_version = read_bookmark_version("123", event_store.clone());
+
+        for (title, expected) in [("foo", Ok(())), ("baz", Err(DomainError::Conflict))] {
+            let result = dispatch::<BookmarkAggregate>(
+                "123",
+                BookmarkCommand::UpdateTitle {
+                    title: title.to_owned(),
+                },
+                Some(seen_version),
+                event_store.clone(),
+                snapshot_store.clone(),
+                read_model.clone(),
+                clock.clone(),
+            );
+            assert_eq!(result, expected);
+        }
+
+        assert_eq!(read_bookmark("123", read_model).unwrap().title, "foo");
+        assert_eq!(read_bookmark_version("123", event_store), 2);
+    }
+
+    #[test]
+    fn test_bookmark_url_can_be_updated_keeping_history() {
+        let event_store = Arc::new(MemoryEventStore::new());
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let read_model = Arc::new(MemoryReadModel::new());
+        let clock = Arc::new(FakeClock::new());
+
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::BookmarkPage {
+                url: "http://bar".to_owned(),
+                title: "bar".to_owned(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::UpdateUrl {
+                url: "http://foo".to_owned(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+
+        let bookmark = read_bookmark("123", read_model.clone()).unwrap();
+
+        assert_eq!(bookmark.url, "http://foo");
+        assert_eq!(bookmark.previous_urls, vec!["http://bar".to_owned()]);
+    }
+
+    #[test]
+    fn test_concurrent_note_edits_are_merged() {
+        let event_store = Arc::new(MemoryEventStore::new());
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let read_model = Arc::new(MemoryReadModel::new());
+        let clock = Arc::new(FakeClock::new());
+
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::BookmarkPage {
+                url: "http://bar".to_owned(),
+                title: "bar".to_owned(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::EditNote {
+                note: "read later".to_owned(),
+                edit_id: Uuid::new_v4().to_string(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+
+        let remote_event_store = Arc::new(MemoryEventStore::new());
+        let remote_read_model = Arc::new(MemoryReadModel::new());
+        for event in event_store.get_events_for_aggregate("123") {
+            remote_event_store.import_event(event.clone()).unwrap();
+            remote_read_model.update(&event).unwrap();
+        }
+
+        clock.advance(Duration::from_secs(1));
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::EditNote {
+                note: "maybe read later".to_owned(),
+                edit_id: Uuid::new_v4().to_string(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+        clock.advance(Duration::from_secs(1));
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::EditNote {
+                note: "read later, twice".to_owned(),
+                edit_id: Uuid::new_v4().to_string(),
+            },
+            None,
+            remote_event_store.clone(),
+            Arc::new(MemorySnapshotStore::new()),
+            remote_read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+
+        let remote_event = remote_event_store
+            .get_events_for_aggregate("123")
+            .pop()
+            .unwrap();
+        event_store.import_event(remote_event.clone()).unwrap();
+        read_model.update(&remote_event).unwrap();
+
+        let bookmark = read_bookmark("123", read_model.clone()).unwrap();
+
+        assert_eq!(bookmark.note, "maybe read later, twice");
+    }
+
+    #[test]
+    fn test_bookmark_history_lists_changes_with_origin() {
+        let event_store = Arc::new(MemoryEventStore::with_instance_id("laptop"));
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let read_model = Arc::new(MemoryReadModel::new());
+        let clock = Arc::new(FakeClock::new());
+
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::BookmarkPage {
+                url: "http://bar".to_owned(),
+                title: "bar".to_owned(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+        clock.advance(Duration::from_secs(1));
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::UpdateTitle {
+                title: "foo".to_owned(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+
+        let history = read_bookmark_history("123", event_store.clone()).unwrap();
+
+        assert_eq!(
+            history,
+            vec![
+                BookmarkHistoryEntry {
+                    created_at: SystemTime::UNIX_EPOCH,
+                    instance_id: "laptop".to_owned(),
+                    change: "Created \"bar\" (http://bar)".to_owned(),
+                },
+                BookmarkHistoryEntry {
+                    created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1),
+                    instance_id: "laptop".to_owned(),
+                    change: "Title: \"bar\" → \"foo\"".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_revoked_instance_events_after_cutoff_are_ignored() {
+        let event_store = Arc::new(MemoryEventStore::with_instance_id("laptop"));
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let phone_event_store = Arc::new(MemoryEventStore::with_instance_id("phone"));
+        let read_model = Arc::new(MemoryReadModel::new());
+        let clock = Arc::new(FakeClock::new());
+
+        for (id, title) in [("123", "before loss"), ("456", "after loss")] {
+            dispatch::<BookmarkAggregate>(
+                id,
+                BookmarkCommand::BookmarkPage {
+                    url: "http://bar".to_owned(),
+                    title: title.to_owned(),
+                },
+                None,
+                phone_event_store.clone(),
+                Arc::new(MemorySnapshotStore::new()),
+                Arc::new(MemoryReadModel::new()),
+                clock.clone(),
+            )
+            .unwrap();
+            clock.advance(Duration::from_secs(10));
+        }
+        for event in phone_event_store.events_iter() {
+            import_event(
+                event,
+                event_store.clone(),
+                snapshot_store.clone(),
+                read_model.clone(),
+            )
+            .unwrap();
+        }
+        assert_eq!(read_bookmarks(read_model.clone()).unwrap().len(), 2);
+
+        dispatch::<InstanceAggregate>(
+            "phone",
+            InstanceCommand::Revoke {
+                revoked_after: SystemTime::UNIX_EPOCH + Duration::from_secs(5),
+                revoked_by: event_store.instance_id(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+
+        let bookmarks = read_bookmarks(read_model.clone()).unwrap();
+        assert_eq!(bookmarks.len(), 1);
+        assert_eq!(bookmarks[0].id, "123");
+
+        let err = dispatch::<BookmarkAggregate>(
+            "456",
+            BookmarkCommand::UpdateTitle {
+                title: "foo".to_owned(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap_err();
+        assert_eq!(err, DomainError::NoSuchBookmark);
+    }
+
+    #[test]
+    fn test_learning_of_revocation_rebuilds_projections() {
+        let event_store = Arc::new(MemoryEventStore::with_instance_id("laptop"));
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let phone_event_store = Arc::new(MemoryEventStore::with_instance_id("phone"));
+        let tablet_event_store = Arc::new(MemoryEventStore::with_instance_id("tablet"));
+        let tablet_read_model = Arc::new(MemoryReadModel::new());
+        let clock = Arc::new(FakeClock::new());
+
+        clock.advance(Duration::from_secs(10));
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::BookmarkPage {
+                url: "http://bar".to_owned(),
+                title: "bar".to_owned(),
+            },
+            None,
+            phone_event_store.clone(),
+            Arc::new(MemorySnapshotStore::new()),
+            Arc::new(MemoryReadModel::new()),
+            clock.clone(),
+        )
+        .unwrap();
+        dispatch::<InstanceAggregate>(
+            "phone",
+            InstanceCommand::Revoke {
+                revoked_after: SystemTime::UNIX_EPOCH,
+                revoked_by: event_store.instance_id(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            Arc::new(MemoryReadModel::new()),
+            clock.clone(),
+        )
+        .unwrap();
+
+        for event in phone_event_store
+            .events_iter()
+            .chain(event_store.events_iter())
+        {
+            import_event(
+                event,
+                tablet_event_store.clone(),
+                Arc::new(MemorySnapshotStore::new()),
+                tablet_read_model.clone(),
+            )
+            .unwrap();
+        }
+
+        assert!(read_bookmarks(tablet_read_model.clone())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_events_missing_from_imported_chain_are_detected() {
+        let event_store = Arc::new(MemoryEventStore::with_instance_id("laptop"));
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let phone_event_store = Arc::new(MemoryEventStore::with_instance_id("phone"));
+        let read_model = Arc::new(MemoryReadModel::new());
+        let clock = Arc::new(FakeClock::new());
+
+        for id in ["123", "456", "789"] {
+            dispatch::<BookmarkAggregate>(
+                id,
+                BookmarkCommand::BookmarkPage {
+                    url: "http://bar".to_owned(),
+                    title: "bar".to_owned(),
+                },
+                None,
+                phone_event_store.clone(),
+                Arc::new(MemorySnapshotStore::new()),
+                Arc::new(MemoryReadModel::new()),
+                clock.clone(),
+            )
+            .unwrap();
+        }
+        dispatch::<BookmarkAggregate>(
+            "abc",
+            BookmarkCommand::BookmarkPage {
+                url: "http://foo".to_owned(),
+                title: "foo".to_owned(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+
+        for event in phone_event_store.events_iter().skip(1) {
+            import_event(
+                event,
+                event_store.clone(),
+                snapshot_store.clone(),
+                read_model.clone(),
+            )
+            .unwrap();
+        }
+
+        assert_eq!(
+            verify_chains(event_store.clone()),
+            vec![ChainIssue::Gap {
+                instance_id: "phone".to_owned(),
+                from: 1,
+                to: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn test_deleted_bookmark_cannot_be_retrieved() {
+        let event_store = Arc::new(MemoryEventStore::new());
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let read_model = Arc::new(MemoryReadModel::new());
+        let clock = Arc::new(FakeClock::new());
+
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::BookmarkPage {
+                url: "http://bar".to_owned(),
+                title: "bar".to_owned(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::Delete,
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+
+        let bookmark = read_bookmark("123", read_model.clone());
+
+        assert_eq!(bookmark, None)
+    }
+
+    #[test]
+    fn test_deleted_bookmark_can_be_restored_from_trash() {
+        let event_store = Arc::new(MemoryEventStore::new());
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let read_model = Arc::new(MemoryReadModel::new());
+        let clock = Arc::new(FakeClock::new());
+
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::BookmarkPage {
+                url: "http://bar".to_owned(),
+                title: "bar".to_owned(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::EditNote {
+                note: "to read".to_owned(),
+                edit_id: Uuid::new_v4().to_string(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::Delete,
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+
+        let trash = read_trash(read_model.clone()).unwrap();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0].bookmark.id, "123");
+
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::Restore,
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+
+        let bookmark = read_bookmark("123", read_model.clone()).unwrap();
+        assert_eq!(bookmark.note, "to read");
+        assert!(read_trash(read_model.clone()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_trash_is_purged_after_retention_period() {
+        let event_store = Arc::new(MemoryEventStore::new());
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let read_model = Arc::new(MemoryReadModel::new());
+        let clock = Arc::new(FakeClock::new());
+        let retention = Duration::from_secs(60);
+
+        clock.advance(Duration::from_secs(3600));
+        for id in ["123", "456"] {
+            dispatch::<BookmarkAggregate>(
+                id,
+                BookmarkCommand::BookmarkPage {
+                    url: "http://bar".to_owned(),
+                    title: "bar".to_owned(),
+                },
+                None,
+                event_store.clone(),
+                snapshot_store.clone(),
+                read_model.clone(),
+                clock.clone(),
+            )
+            .unwrap();
+            dispatch::<BookmarkAggregate>(
+                id,
+                BookmarkCommand::Delete,
+                None,
+                event_store.clone(),
+                snapshot_store.clone(),
+                read_model.clone(),
+                clock.clone(),
+            )
+            .unwrap();
+            clock.advance(Duration::from_secs(30));
+        }
+
+        clock.advance(Duration::from_secs(15));
+        purge_trash(
+            retention,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+
+        let trash = read_trash(read_model.clone()).unwrap();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0].bookmark.id, "456");
+
+        let err = dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::Restore,
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap_err();
+        assert_eq!(err, DomainError::NoSuchBookmark);
+
+        rebuild(event_store, snapshot_store, read_model.clone()).unwrap();
+        assert_eq!(read_trash(read_model).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_deleting_non_existent_bookmark_is_rejected() {
+        let event_store = Arc::new(MemoryEventStore::new());
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let read_model = Arc::new(MemoryReadModel::new());
+        let clock = Arc::new(FakeClock::new());
+
+        let err = dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::Delete,
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap_err();
+
+        assert_eq!(err, DomainError::NoSuchBookmark);
+    }
+
+    #[test]
+    fn test_snapshot_is_not_used_once_an_older_event_is_imported() {
+        let event_store = Arc::new(MemoryEventStore::with_instance_id("laptop"));
+        let snapshot_store = Arc::new(MemorySnapshotStore::new());
+        let phone_event_store = Arc::new(MemoryEventStore::with_instance_id("phone"));
+        let read_model = Arc::new(MemoryReadModel::new());
+        let clock = Arc::new(FakeClock::new());
+
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::BookmarkPage {
+                url: "http://bar".to_owned(),
+                title: "bar".to_owned(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+        for event in event_store.events_iter() {
+            phone_event_store.import_event(event).unwrap();
+        }
+        clock.advance(Duration::from_secs(1));
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::Delete,
+            None,
+            phone_event_store.clone(),
+            Arc::new(MemorySnapshotStore::new()),
+            Arc::new(MemoryReadModel::new()),
+            clock.clone(),
+        )
+        .unwrap();
+        for i in 0..SNAPSHOT_INTERVAL {
+            clock.advance(Duration::from_secs(1));
+            dispatch::<BookmarkAggregate>(
+                "123",
+                BookmarkCommand::UpdateTitle {
+                    title: format!("bar {}", i),
+                },
+                None,
+                event_store.clone(),
+                snapshot_store.clone(),
+                read_model.clone(),
+                clock.clone(),
+            )
+            .unwrap();
+        }
+        assert!(snapshot_store.load("123").is_some());
+
+        for event in phone_event_store
+            .events_iter()
+            .filter(|e| e.meta.instance_id == "phone")
+        {
+            import_event(
+                event,
+                event_store.clone(),
+                snapshot_store.clone(),
+                read_model.clone(),
+            )
+            .unwrap();
+        }
+
+        let err = dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::UpdateTitle {
+                title: "foo".to_owned(),
+            },
+            None,
+            event_store.clone(),
+            snapshot_store.clone(),
+            read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap_err();
+        assert_eq!(err, DomainError::NoSuchBookmark);
+    }
+
+    #[test]
+    fn test_events_received_twice_in_a_batch_are_stored_once() {
+        let event_store = Arc::new(MemoryEventStore::with_instance_id("laptop"));
+        let read_model = Arc::new(MemoryReadModel::new());
+        let phone_event_store = Arc::new(MemoryEventStore::with_instance_id("phone"));
+        let clock = Arc::new(FakeClock::new());
+        for command in [
+            BookmarkCommand::BookmarkPage {
+                url: "http://bar".to_owned(),
+                title: "bar".to_owned(),
+            },
+            BookmarkCommand::UpdateTitle {
+                title: "foo".to_owned(),
+            },
+        ] {
+            dispatch::<BookmarkAggregate>(
+                "123",
+                command,
+                None,
+                phone_event_store.clone(),
+                Arc::new(MemorySnapshotStore::new()),
+                Arc::new(MemoryReadModel::new()),
+                clock.clone(),
+            )
+            .unwrap();
+        }
+        let events: Vec<DomainEvent> = phone_event_store.events_iter().collect();
+
+        let results = import_events(
+            [events.clone(), events].concat(),
+            event_store.clone(),
+            Arc::new(MemorySnapshotStore::new()),
+            read_model.clone(),
+        );
+
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert_eq!(event_store.events_iter().count(), 2);
+        assert_eq!(read_bookmark("123", read_model).unwrap().title, "foo");
+    }
+
+    #[test]
+    fn test_keys_published_in_the_same_name_are_all_read() {
+        let event_store = Arc::new(MemoryEventStore::with_instance_id("laptop"));
+        let clock = Arc::new(FakeClock::new());
+
+        // An impostor claiming to be the phone
+        for public_key in ["phone key", "impostor key"] {
+            let phone_event_store = Arc::new(MemoryEventStore::with_instance_id("phone"));
+            dispatch::<InstanceAggregate>(
+                "phone",
+                InstanceCommand::PublishKey {
+                    public_key: public_key.to_owned(),
+                },
+                None,
+                phone_event_store.clone(),
+                Arc::new(MemorySnapshotStore::new()),
+                Arc::new(MemoryReadModel::new()),
+                clock.clone(),
+            )
+            .unwrap();
+            import_events(
+                phone_event_store.events_iter().collect(),
+                event_store.clone(),
+                Arc::new(MemorySnapshotStore::new()),
+                Arc::new(MemoryReadModel::new()),
+            );
+        }
+
+        assert_eq!(
+            read_published_keys("phone", event_store.clone()),
+            vec!["phone key".to_owned(), "impostor key".to_owned()]
+        );
+        assert!(read_published_keys("tablet", event_store).is_empty());
+    }
+
+    #[test]
+    fn test_deleted_bookmark_is_compacted_once_acknowledged() {
+        let clock = Arc::new(FakeClock::new());
+        let laptop_event_store = Arc::new(MemoryEventStore::with_instance_id("laptop"));
+        let laptop_read_model = Arc::new(MemoryReadModel::new());
+        let phone_event_store = Arc::new(MemoryEventStore::with_instance_id("phone"));
+        let phone_read_model = Arc::new(MemoryReadModel::new());
+        let sync = |from: &Arc<MemoryEventStore>,
+                    to: &Arc<MemoryEventStore>,
+                    read_model: &Arc<MemoryReadModel>| {
+            for event in from.events_iter() {
+                import_event(
+                    event,
+                    to.clone(),
+                    Arc::new(MemorySnapshotStore::new()),
+                    read_model.clone(),
+                )
+                .unwrap();
+            }
+        };
+
+        dispatch::<InstanceAggregate>(
+            "phone",
+            InstanceCommand::PublishKey {
+                public_key: "phone key".to_owned(),
+            },
+            None,
+            phone_event_store.clone(),
+            Arc::new(MemorySnapshotStore::new()),
+            phone_read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+        sync(&phone_event_store, &laptop_event_store, &laptop_read_model);
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::BookmarkPage {
+                url: "http://bar".to_owned(),
+                title: "bar".to_owned(),
+            },
+            None,
+            laptop_event_store.clone(),
+            Arc::new(MemorySnapshotStore::new()),
+            laptop_read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+        sync(&laptop_event_store, &phone_event_store, &phone_read_model);
+        dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::Delete,
+            None,
+            laptop_event_store.clone(),
+            Arc::new(MemorySnapshotStore::new()),
+            laptop_read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+        clock.advance(Duration::from_secs(60));
+
+        // The phone hasn't seen the deletion yet
+        let plan = compact(
+            Duration::from_secs(30),
+            laptop_event_store.clone(),
+            Arc::new(MemorySnapshotStore::new()),
+            laptop_read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+        assert_eq!(plan.refused, vec!["123".to_owned()]);
+
+        sync(&laptop_event_store, &phone_event_store, &phone_read_model);
+        assert!(acknowledge_deletions(
+            phone_event_store.clone(),
+            Arc::new(MemorySnapshotStore::new()),
+            phone_read_model.clone(),
+            clock.clone()
+        )
+        .unwrap());
+        sync(&phone_event_store, &laptop_event_store, &laptop_read_model);
+
+        let plan = compact(
+            Duration::from_secs(30),
+            laptop_event_store.clone(),
+            Arc::new(MemorySnapshotStore::new()),
+            laptop_read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap();
+        assert_eq!(plan.compactable, vec!["123".to_owned()]);
+
+        sync(&laptop_event_store, &phone_event_store, &phone_read_model);
+        let err = dispatch::<BookmarkAggregate>(
+            "123",
+            BookmarkCommand::BookmarkPage {
+                url: "http://bar".to_owned(),
+                title: "bar".to_owned(),
+            },
+            None,
+            laptop_event_store.clone(),
+            Arc::new(MemorySnapshotStore::new()),
+            laptop_read_model.clone(),
+            clock.clone(),
+        )
+        .unwrap_err();
         assert_eq!(err, DomainError::NoSuchBookmark);
+        for event_store in [laptop_event_store, phone_event_store] {
+            assert!(event_store
+                .get_events_for_aggregate("123")
+                .iter()
+                .all(compaction::is_compacted));
+            assert_eq!(verify_chains(event_store), vec![]);
+        }
+        assert_eq!(read_trash(laptop_read_model).unwrap(), vec![]);
     }
 }
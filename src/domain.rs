@@ -1,5 +1,12 @@
 pub mod aggregates;
+pub mod chain;
+pub mod checkpoint;
 pub mod commands;
+pub mod compaction;
 pub mod data;
 pub mod errors;
 pub mod events;
+pub mod note;
+pub mod reconciliation;
+pub mod revocations;
+pub mod sync;
@@ -1,59 +1,184 @@
 use super::{
-    commands::BookmarkCommand,
-    data::{Aggregate, DomainEventMeta},
+    commands::{BookmarkCommand, InstanceCommand},
+    data::{Aggregate, DomainEventMeta, LogPosition},
     errors::DomainError,
-    events::BookmarkEventPayload,
+    events::{BookmarkEventPayload, DomainEventPayload, InstanceEventPayload},
+    note::Note,
 };
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
 
+#[derive(Clone, Serialize, Deserialize)]
 enum State {
     Nonexistent,
     Created,
     Deleted,
+    Purged,
+    Forgotten,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct BookmarkAggregate {
     pub id: String,
     pub title: String,
     pub url: String,
+    pub note: Note,
     state: State,
+    // Only histories of bookmarks gone for good are compacted, so any
+    // compacted event rules out the ID being used again.
+    compacted: bool,
+    // The creation that won, if there were several with this ID. Unknown
+    // for snapshots taken before it was kept.
+    #[serde(default)]
+    created_by: Option<LogPosition>,
 }
 
 impl BookmarkAggregate {
-    pub fn new(id: &str) -> Self {
+    // Human-readable summary of what applying `payload` would change.
+    pub fn describe_event(&self, payload: &BookmarkEventPayload) -> String {
+        match payload {
+            BookmarkEventPayload::Created { url, title } => {
+                format!("Created \"{}\" ({})", title, url)
+            }
+            BookmarkEventPayload::Deleted => "Moved to trash".to_owned(),
+            BookmarkEventPayload::Restored => "Restored from trash".to_owned(),
+            BookmarkEventPayload::TitleUpdated { title } => {
+                format!("Title: \"{}\" → \"{}\"", self.title, title)
+            }
+            BookmarkEventPayload::UrlUpdated { url } => format!("URL: {} → {}", self.url, url),
+            BookmarkEventPayload::NoteEdited { ops } => {
+                let mut note = self.note.clone();
+                note.apply(ops);
+                format!("Note: \"{}\" → \"{}\"", self.note.text(), note.text())
+            }
+            BookmarkEventPayload::Forgotten => "Forgotten".to_owned(),
+            BookmarkEventPayload::Purged => "Purged from trash".to_owned(),
+        }
+    }
+}
+
+impl Aggregate for BookmarkAggregate {
+    type Command = BookmarkCommand;
+    type EventPayload = BookmarkEventPayload;
+
+    fn new(id: &str) -> Self {
         Self {
             id: id.to_owned(),
             state: State::Nonexistent,
             title: "".to_owned(),
             url: "".to_owned(),
+            note: Note::new(),
+            compacted: false,
+            created_by: None,
         }
     }
-}
 
-impl Aggregate for BookmarkAggregate {
-    type Command = BookmarkCommand;
-    type EventPayload = BookmarkEventPayload;
+    fn wrap_payload(payload: BookmarkEventPayload) -> DomainEventPayload {
+        DomainEventPayload::Bookmark(payload)
+    }
 
-    fn handle_command(&self, command: &Self::Command) -> Result<Self::EventPayload, DomainError> {
+    fn unwrap_payload(payload: &DomainEventPayload) -> Option<&BookmarkEventPayload> {
+        match payload {
+            DomainEventPayload::Bookmark(payload) => Some(payload),
+            _ => None,
+        }
+    }
+
+    fn handle_command(
+        &self,
+        command: &Self::Command,
+    ) -> Result<Vec<Self::EventPayload>, DomainError> {
+        if self.compacted {
+            return Err(DomainError::NoSuchBookmark);
+        }
         match command {
             BookmarkCommand::BookmarkPage { url, title } => match self.state {
-                State::Deleted => Err(DomainError::NoSuchBookmark),
+                State::Deleted | State::Purged | State::Forgotten => {
+                    Err(DomainError::NoSuchBookmark)
+                }
                 State::Created => Err(DomainError::BookmarkAlreadyExists),
-                State::Nonexistent => Ok(BookmarkEventPayload::Created {
+                State::Nonexistent => Ok(vec![BookmarkEventPayload::Created {
                     url: url.clone(),
                     title: title.clone(),
-                }),
+                }]),
+            },
+            // The note is written on top of the creation, so that it's
+            // merged with concurrent edits like any other.
+            BookmarkCommand::Import {
+                url,
+                title,
+                note,
+                edit_id,
+            } => match self.state {
+                State::Deleted | State::Purged | State::Forgotten => {
+                    Err(DomainError::NoSuchBookmark)
+                }
+                State::Created => Err(DomainError::BookmarkAlreadyExists),
+                State::Nonexistent => {
+                    let mut events = vec![BookmarkEventPayload::Created {
+                        url: url.clone(),
+                        title: title.clone(),
+                    }];
+                    if !note.is_empty() {
+                        events.push(BookmarkEventPayload::NoteEdited {
+                            ops: self.note.diff(note, edit_id),
+                        });
+                    }
+                    Ok(events)
+                }
             },
             BookmarkCommand::Delete => match self.state {
                 State::Deleted => Err(DomainError::NoSuchBookmark),
-                State::Nonexistent => Err(DomainError::NoSuchBookmark),
-                State::Created => Ok(BookmarkEventPayload::Deleted),
+                State::Nonexistent | State::Purged | State::Forgotten => {
+                    Err(DomainError::NoSuchBookmark)
+                }
+                State::Created => Ok(vec![BookmarkEventPayload::Deleted]),
+            },
+            BookmarkCommand::Restore => match self.state {
+                State::Deleted => Ok(vec![BookmarkEventPayload::Restored]),
+                State::Nonexistent | State::Purged | State::Forgotten => {
+                    Err(DomainError::NoSuchBookmark)
+                }
+                State::Created => Err(DomainError::BookmarkNotInTrash),
             },
             BookmarkCommand::UpdateTitle { title } => match self.state {
                 State::Deleted => Err(DomainError::NoSuchBookmark),
-                State::Nonexistent => Err(DomainError::NoSuchBookmark),
-                State::Created => Ok(BookmarkEventPayload::TitleUpdated {
+                State::Nonexistent | State::Purged | State::Forgotten => {
+                    Err(DomainError::NoSuchBookmark)
+                }
+                State::Created => Ok(vec![BookmarkEventPayload::TitleUpdated {
                     title: title.clone(),
-                }),
+                }]),
+            },
+            BookmarkCommand::UpdateUrl { url } => match self.state {
+                State::Deleted => Err(DomainError::NoSuchBookmark),
+                State::Nonexistent | State::Purged | State::Forgotten => {
+                    Err(DomainError::NoSuchBookmark)
+                }
+                State::Created if *url == self.url => Ok(vec![]),
+                State::Created => Ok(vec![BookmarkEventPayload::UrlUpdated { url: url.clone() }]),
+            },
+            BookmarkCommand::EditNote { note, edit_id } => match self.state {
+                State::Deleted => Err(DomainError::NoSuchBookmark),
+                State::Nonexistent | State::Purged | State::Forgotten => {
+                    Err(DomainError::NoSuchBookmark)
+                }
+                State::Created => Ok(vec![BookmarkEventPayload::NoteEdited {
+                    ops: self.note.diff(note, edit_id),
+                }]),
+            },
+            BookmarkCommand::Purge => match self.state {
+                State::Deleted => Ok(vec![BookmarkEventPayload::Purged]),
+                State::Nonexistent | State::Purged | State::Forgotten => {
+                    Err(DomainError::NoSuchBookmark)
+                }
+                State::Created => Err(DomainError::BookmarkNotInTrash),
+            },
+            BookmarkCommand::Forget => match self.state {
+                State::Nonexistent | State::Forgotten => Err(DomainError::NoSuchBookmark),
+                State::Created | State::Deleted | State::Purged => {
+                    Ok(vec![BookmarkEventPayload::Forgotten])
+                }
             },
         }
     }
@@ -64,11 +189,24 @@ impl Aggregate for BookmarkAggregate {
         meta: &DomainEventMeta,
     ) -> BookmarkAggregate {
         match &payload {
+            // Instances may pick the same ID for different bookmarks; the
+            // first creation in the log wins, in whatever order they come.
             BookmarkEventPayload::Created { url, title } => {
-                if *meta.aggregate_id == self.id {
-                    self.state = State::Created;
-                    self.title = title.clone();
-                    self.url = url.clone();
+                let position = meta.log_position();
+                let first = match (&self.created_by, &self.state) {
+                    (Some(created_by), _) => position < *created_by,
+                    (None, State::Nonexistent) => true,
+                    (None, _) => false,
+                };
+                if *meta.aggregate_id == self.id && first {
+                    self.created_by = Some(position);
+                    if matches!(self.state, State::Nonexistent) {
+                        self.state = State::Created;
+                    }
+                    if !matches!(self.state, State::Forgotten) {
+                        self.title = title.clone();
+                        self.url = url.clone();
+                    }
                 }
             }
             BookmarkEventPayload::Deleted => {
@@ -76,11 +214,137 @@ impl Aggregate for BookmarkAggregate {
                     self.state = State::Deleted;
                 }
             }
+            BookmarkEventPayload::Restored => {
+                if *meta.aggregate_id == self.id {
+                    self.state = State::Created;
+                }
+            }
             BookmarkEventPayload::TitleUpdated { title } => {
                 if *meta.aggregate_id == self.id {
                     self.title = title.clone();
                 }
             }
+            BookmarkEventPayload::UrlUpdated { url } => {
+                if *meta.aggregate_id == self.id {
+                    self.url = url.clone();
+                }
+            }
+            BookmarkEventPayload::NoteEdited { ops } => {
+                if *meta.aggregate_id == self.id {
+                    self.note.apply(ops);
+                }
+            }
+            // A restore on another instance may have won the race
+            BookmarkEventPayload::Purged => {
+                if *meta.aggregate_id == self.id && matches!(self.state, State::Deleted) {
+                    self.state = State::Purged;
+                }
+            }
+            BookmarkEventPayload::Forgotten => {
+                if *meta.aggregate_id == self.id {
+                    self.state = State::Forgotten;
+                    self.title.clear();
+                    self.url.clear();
+                    self.note = Note::new();
+                }
+            }
+        }
+        self
+    }
+
+    fn apply_compacted(mut self, meta: &DomainEventMeta) -> BookmarkAggregate {
+        if *meta.aggregate_id == self.id {
+            self.compacted = true;
+        }
+        self
+    }
+}
+
+pub struct InstanceAggregate {
+    pub id: String,
+    pub revoked_after: Option<SystemTime>,
+    pub public_key: Option<String>,
+}
+
+impl Aggregate for InstanceAggregate {
+    type Command = InstanceCommand;
+    type EventPayload = InstanceEventPayload;
+
+    fn new(id: &str) -> Self {
+        Self {
+            id: id.to_owned(),
+            revoked_after: None,
+            public_key: None,
+        }
+    }
+
+    fn wrap_payload(payload: InstanceEventPayload) -> DomainEventPayload {
+        DomainEventPayload::Instance(payload)
+    }
+
+    fn unwrap_payload(payload: &DomainEventPayload) -> Option<&InstanceEventPayload> {
+        match payload {
+            DomainEventPayload::Instance(payload) => Some(payload),
+            _ => None,
+        }
+    }
+
+    fn handle_command(
+        &self,
+        command: &Self::Command,
+    ) -> Result<Vec<Self::EventPayload>, DomainError> {
+        match command {
+            InstanceCommand::Revoke {
+                revoked_after,
+                revoked_by,
+            } => {
+                if *revoked_by == self.id {
+                    Err(DomainError::CannotRevokeOwnInstance)
+                } else if self.revoked_after.is_some_and(|t| t <= *revoked_after) {
+                    Err(DomainError::InstanceAlreadyRevoked)
+                } else {
+                    Ok(vec![InstanceEventPayload::Revoked {
+                        revoked_after: *revoked_after,
+                    }])
+                }
+            }
+            InstanceCommand::PublishKey { public_key } => {
+                if self.public_key.as_ref() == Some(public_key) {
+                    Err(DomainError::KeyAlreadyPublished)
+                } else {
+                    Ok(vec![InstanceEventPayload::KeyPublished {
+                        public_key: public_key.clone(),
+                    }])
+                }
+            }
+            InstanceCommand::Acknowledge { high_water_marks } => {
+                Ok(vec![InstanceEventPayload::Acknowledged {
+                    high_water_marks: high_water_marks.clone(),
+                }])
+            }
+        }
+    }
+
+    fn apply_event(
+        mut self,
+        payload: &InstanceEventPayload,
+        meta: &DomainEventMeta,
+    ) -> InstanceAggregate {
+        match &payload {
+            InstanceEventPayload::Revoked { revoked_after } => {
+                if *meta.aggregate_id == self.id {
+                    self.revoked_after = Some(
+                        self.revoked_after
+                            .map_or(*revoked_after, |t| t.min(*revoked_after)),
+                    );
+                }
+            }
+            InstanceEventPayload::KeyPublished { public_key } => {
+                if *meta.aggregate_id == self.id {
+                    self.public_key = Some(public_key.clone());
+                }
+            }
+            InstanceEventPayload::Acknowledged { .. } => {}
         }
         self
     }
@@ -103,6 +367,9 @@ mod tests {
             &DomainEventMeta {
                 aggregate_id: "123456".to_owned(),
                 created_at: clock.now(),
+                instance_id: "laptop".to_owned(),
+                sequence: 0,
+                previous_hash: None,
             },
         );
         let bookmark = bookmark.apply_event(
@@ -110,6 +377,9 @@ mod tests {
             &DomainEventMeta {
                 aggregate_id: "123456".to_owned(),
                 created_at: clock.now(),
+                instance_id: "laptop".to_owned(),
+                sequence: 0,
+                previous_hash: None,
             },
         );
 
@@ -134,6 +404,9 @@ mod tests {
             &DomainEventMeta {
                 aggregate_id: "123456".to_owned(),
                 created_at: clock.now(),
+                instance_id: "laptop".to_owned(),
+                sequence: 0,
+                previous_hash: None,
             },
         );
 
@@ -151,7 +424,7 @@ mod tests {
     fn test_bookmarking_page_generates_create_event() {
         let bookmark = BookmarkAggregate::new("123456");
 
-        let event_payload = bookmark
+        let event_payloads = bookmark
             .handle_command(&BookmarkCommand::BookmarkPage {
                 url: "https://example.com".to_owned(),
                 title: "Example".to_owned(),
@@ -159,11 +432,151 @@ mod tests {
             .unwrap();
 
         assert_eq!(
-            event_payload,
-            BookmarkEventPayload::Created {
+            event_payloads,
+            vec![BookmarkEventPayload::Created {
                 url: "https://example.com".to_owned(),
                 title: "Example".to_owned(),
-            }
+            }]
         )
     }
+
+    #[test]
+    fn test_importing_page_with_note_generates_create_and_note_events() {
+        let clock = FakeClock::new();
+        let meta = DomainEventMeta {
+            aggregate_id: "123456".to_owned(),
+            created_at: clock.now(),
+            instance_id: "laptop".to_owned(),
+            sequence: 0,
+            previous_hash: None,
+        };
+        let bookmark = BookmarkAggregate::new("123456");
+
+        let event_payloads = bookmark
+            .handle_command(&BookmarkCommand::Import {
+                url: "https://example.com".to_owned(),
+                title: "Example".to_owned(),
+                note: "to read".to_owned(),
+                edit_id: "edit".to_owned(),
+            })
+            .unwrap();
+        let bookmark = event_payloads
+            .iter()
+            .fold(bookmark, |aggr, payload| aggr.apply_event(payload, &meta));
+
+        assert!(matches!(
+            event_payloads.as_slice(),
+            [
+                BookmarkEventPayload::Created { .. },
+                BookmarkEventPayload::NoteEdited { .. }
+            ]
+        ));
+        assert_eq!(bookmark.note.text(), "to read");
+    }
+
+    #[test]
+    fn test_updating_url_generates_url_updated_event() {
+        let clock = FakeClock::new();
+        let bookmark = BookmarkAggregate::new("123456");
+        let bookmark = bookmark.apply_event(
+            &BookmarkEventPayload::Created {
+                url: "https://example.com".to_owned(),
+                title: "Example".to_owned(),
+            },
+            &DomainEventMeta {
+                aggregate_id: "123456".to_owned(),
+                created_at: clock.now(),
+                instance_id: "laptop".to_owned(),
+                sequence: 0,
+                previous_hash: None,
+            },
+        );
+
+        let event_payloads = bookmark
+            .handle_command(&BookmarkCommand::UpdateUrl {
+                url: "https://example.org".to_owned(),
+            })
+            .unwrap();
+
+        assert_eq!(
+            event_payloads,
+            vec![BookmarkEventPayload::UrlUpdated {
+                url: "https://example.org".to_owned(),
+            }]
+        )
+    }
+
+    #[test]
+    fn test_updating_url_to_the_same_url_generates_nothing() {
+        let clock = FakeClock::new();
+        let bookmark = BookmarkAggregate::new("123456");
+        let bookmark = bookmark.apply_event(
+            &BookmarkEventPayload::Created {
+                url: "https://example.com".to_owned(),
+                title: "Example".to_owned(),
+            },
+            &DomainEventMeta {
+                aggregate_id: "123456".to_owned(),
+                created_at: clock.now(),
+                instance_id: "laptop".to_owned(),
+                sequence: 0,
+                previous_hash: None,
+            },
+        );
+
+        let event_payloads = bookmark
+            .handle_command(&BookmarkCommand::UpdateUrl {
+                url: "https://example.com".to_owned(),
+            })
+            .unwrap();
+
+        assert_eq!(event_payloads, vec![])
+    }
+
+    #[test]
+    fn test_restored_bookmark_keeps_its_data() {
+        let clock = FakeClock::new();
+        let meta = DomainEventMeta {
+            aggregate_id: "123456".to_owned(),
+            created_at: clock.now(),
+            instance_id: "laptop".to_owned(),
+            sequence: 0,
+            previous_hash: None,
+        };
+        let bookmark = BookmarkAggregate::new("123456")
+            .apply_event(
+                &BookmarkEventPayload::Created {
+                    url: "https://example.com".to_owned(),
+                    title: "Example".to_owned(),
+                },
+                &meta,
+            )
+            .apply_event(&BookmarkEventPayload::Deleted, &meta);
+
+        let event_payloads = bookmark.handle_command(&BookmarkCommand::Restore).unwrap();
+        let bookmark = bookmark.apply_event(&event_payloads[0], &meta);
+
+        assert_eq!(event_payloads, vec![BookmarkEventPayload::Restored]);
+        assert_eq!(bookmark.title, "Example");
+        assert!(bookmark
+            .handle_command(&BookmarkCommand::UpdateTitle {
+                title: "Foobar".to_owned(),
+            })
+            .is_ok());
+    }
+
+    #[test]
+    fn test_instance_cannot_revoke_itself() {
+        let clock = FakeClock::new();
+        let instance = InstanceAggregate::new("laptop");
+
+        let err = instance
+            .handle_command(&InstanceCommand::Revoke {
+                revoked_after: clock.now(),
+                revoked_by: "laptop".to_owned(),
+            })
+            .unwrap_err();
+
+        assert_eq!(err, DomainError::CannotRevokeOwnInstance);
+    }
 }
@@ -0,0 +1,236 @@
+use super::{data::DomainEvent, events::DomainEventPayload};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, fmt};
+
+// Every instance links the events it writes into a chain, each one carrying
+// a sequence number and the hash of its predecessor, so that missing,
+// duplicated or altered events can be told apart from a legitimately short
+// log.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ChainIssue {
+    Gap {
+        instance_id: String,
+        from: u64,
+        to: u64,
+    },
+    Fork {
+        instance_id: String,
+        sequence: u64,
+    },
+    Modified {
+        instance_id: String,
+        sequence: u64,
+    },
+}
+
+impl fmt::Display for ChainIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainIssue::Gap {
+                instance_id,
+                from,
+                to,
+            } => write!(f, "{instance_id}: events {from} to {to} are missing"),
+            ChainIssue::Fork {
+                instance_id,
+                sequence,
+            } => write!(f, "{instance_id}: event {sequence} exists more than once"),
+            ChainIssue::Modified {
+                instance_id,
+                sequence,
+            } => write!(
+                f,
+                "{instance_id}: event {sequence} doesn't match the hash of its predecessor"
+            ),
+        }
+    }
+}
+
+// The signature is left out, as it's computed over the same content and
+// added by the event store after the chain link has been made.
+pub fn event_hash(event: &DomainEvent) -> String {
+    if let DomainEventPayload::Compacted(compacted) = &event.payload {
+        return compacted.hash.clone();
+    }
+    let bytes = match &event.sealed {
+        Some(sealed) => {
+            serde_json::to_vec(&(&event.meta, DomainEventPayload::Sealed(sealed.clone())))
+        }
+        None => serde_json::to_vec(&(&event.meta, &event.payload)),
+    }
+    .unwrap();
+    hex::encode(Sha256::digest(bytes))
+}
+
+// The latest event in `instance_id`'s own chain, which the next event it
+// writes is linked after.
+pub fn head(instance_id: &str, events: impl Iterator<Item = DomainEvent>) -> Option<DomainEvent> {
+    events
+        .filter(|e| e.meta.instance_id == instance_id && e.meta.sequence > 0)
+        .max_by_key(|e| e.meta.sequence)
+}
+
+// Returns the sequence number and previous hash for the next event written
+// by `instance_id`.
+pub fn next_link(
+    instance_id: &str,
+    events: impl Iterator<Item = DomainEvent>,
+) -> (u64, Option<String>) {
+    link_after(head(instance_id, events).as_ref())
+}
+
+fn link_after(head: Option<&DomainEvent>) -> (u64, Option<String>) {
+    head.map(|head| (head.meta.sequence + 1, Some(event_hash(head))))
+        .unwrap_or((1, None))
+}
+
+// Links events that aren't linked yet into the chain after `head`, which is
+// only looked up if there are any, so that stores further down a stack of
+// decorators needn't look again. Stores do this while holding their write
+// lock, so that no two events get the same link.
+pub fn link(
+    mut events: Vec<DomainEvent>,
+    head: impl FnOnce() -> Option<DomainEvent>,
+) -> Vec<DomainEvent> {
+    if events.iter().all(|e| e.meta.sequence > 0) {
+        return events;
+    }
+    let (mut sequence, mut previous_hash) = link_after(head().as_ref());
+    for event in &mut events {
+        event.meta.sequence = sequence;
+        event.meta.previous_hash = previous_hash;
+        sequence += 1;
+        previous_hash = Some(event_hash(event));
+    }
+    events
+}
+
+pub fn verify_chains(events: impl Iterator<Item = DomainEvent>) -> Vec<ChainIssue> {
+    let mut events_by_instance: HashMap<String, Vec<DomainEvent>> = HashMap::new();
+    for event in events.filter(|e| e.meta.sequence > 0) {
+        events_by_instance
+            .entry(event.meta.instance_id.clone())
+            .or_default()
+            .push(event);
+    }
+
+    let mut instance_ids: Vec<&String> = events_by_instance.keys().collect();
+    instance_ids.sort();
+
+    let mut issues = vec![];
+    for instance_id in instance_ids {
+        let mut chain = events_by_instance[instance_id].clone();
+        chain.sort_by_key(|e| e.meta.sequence);
+
+        let mut previous: Option<&DomainEvent> = None;
+        for event in &chain {
+            let sequence = event.meta.sequence;
+            let expected = previous.map_or(1, |p| p.meta.sequence + 1);
+            if sequence < expected {
+                // The same event received twice, e.g. through two peers
+                if previous.map(event_hash) == Some(event_hash(event)) {
+                    continue;
+                }
+                issues.push(ChainIssue::Fork {
+                    instance_id: instance_id.clone(),
+                    sequence,
+                });
+                continue;
+            }
+            if sequence > expected {
+                issues.push(ChainIssue::Gap {
+                    instance_id: instance_id.clone(),
+                    from: expected,
+                    to: sequence - 1,
+                });
+            } else if event.meta.previous_hash != previous.map(event_hash) {
+                issues.push(ChainIssue::Modified {
+                    instance_id: instance_id.clone(),
+                    sequence,
+                });
+            }
+            previous = Some(event);
+        }
+    }
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        data::DomainEventMeta,
+        events::{BookmarkEventPayload, DomainEventPayload},
+    };
+    use std::time::{Duration, SystemTime};
+
+    fn chain(instance_id: &str, titles: &[&str]) -> Vec<DomainEvent> {
+        let mut events: Vec<DomainEvent> = vec![];
+        for title in titles {
+            let (sequence, previous_hash) = next_link(instance_id, events.iter().cloned());
+            events.push(DomainEvent {
+                meta: DomainEventMeta {
+                    aggregate_id: "123".to_owned(),
+                    created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(sequence),
+                    instance_id: instance_id.to_owned(),
+                    sequence,
+                    previous_hash,
+                },
+                payload: DomainEventPayload::Bookmark(BookmarkEventPayload::TitleUpdated {
+                    title: title.to_string(),
+                }),
+                signature: None,
+                sealed: None,
+            });
+        }
+        events
+    }
+
+    #[test]
+    fn test_intact_chains_have_no_issues() {
+        let mut events = chain("laptop", &["a", "b", "c"]);
+        events.extend(chain("phone", &["d"]));
+
+        assert_eq!(verify_chains(events.into_iter()), vec![]);
+    }
+
+    #[test]
+    fn test_missing_events_are_reported_as_gap() {
+        let mut events = chain("laptop", &["a", "b", "c", "d"]);
+        events.drain(1..3);
+
+        assert_eq!(
+            verify_chains(events.into_iter()),
+            vec![ChainIssue::Gap {
+                instance_id: "laptop".to_owned(),
+                from: 2,
+                to: 3
+            }]
+        );
+    }
+
+    #[test]
+    fn test_altered_and_duplicated_events_are_reported() {
+        let mut events = chain("laptop", &["a", "b", "c"]);
+        events[0].payload = DomainEventPayload::Bookmark(BookmarkEventPayload::TitleUpdated {
+            title: "forged".to_owned(),
+        });
+        let mut duplicate = events[2].clone();
+        duplicate.meta.created_at += Duration::from_secs(1);
+        events.push(duplicate);
+
+        assert_eq!(
+            verify_chains(events.into_iter()),
+            vec![
+                ChainIssue::Modified {
+                    instance_id: "laptop".to_owned(),
+                    sequence: 2
+                },
+                ChainIssue::Fork {
+                    instance_id: "laptop".to_owned(),
+                    sequence: 3
+                }
+            ]
+        );
+    }
+}
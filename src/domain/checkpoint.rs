@@ -0,0 +1,130 @@
+use super::{chain, data::DomainEvent, sync::HighWaterMarks};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+// How far a projection has been brought up to date with the log: up to a
+// high-water mark for each instance, plus whichever of its events past a gap
+// were projected already, until the gap is filled. Events are told apart by
+// instance and sequence number, which stay the same when they're sealed or
+// compacted. Events written before sequence numbers were introduced are told
+// apart by their hash instead; no more of them are ever written.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    high_water_marks: HighWaterMarks,
+    past_gaps: BTreeMap<String, BTreeSet<u64>>,
+    unsequenced: BTreeSet<String>,
+}
+
+impl Checkpoint {
+    pub fn advance(&mut self, event: &DomainEvent) {
+        if event.meta.sequence == 0 {
+            self.unsequenced.insert(chain::event_hash(event));
+            return;
+        }
+
+        let instance_id = &event.meta.instance_id;
+        let mark = self
+            .high_water_marks
+            .entry(instance_id.clone())
+            .or_default();
+        if event.meta.sequence <= *mark {
+            return;
+        }
+        let past_gap = self.past_gaps.entry(instance_id.clone()).or_default();
+        past_gap.insert(event.meta.sequence);
+        while past_gap.remove(&(*mark + 1)) {
+            *mark += 1;
+        }
+        if past_gap.is_empty() {
+            self.past_gaps.remove(instance_id);
+        }
+    }
+
+    pub fn covers(&self, event: &DomainEvent) -> bool {
+        if event.meta.sequence > 0 {
+            self.covers_sequence(&event.meta.instance_id, event.meta.sequence)
+        } else {
+            self.unsequenced.contains(&chain::event_hash(event))
+        }
+    }
+
+    // Whether the event with this place in an instance's chain is covered,
+    // for stores that can tell without reading it.
+    pub fn covers_sequence(&self, instance_id: &str, sequence: u64) -> bool {
+        if sequence == 0 {
+            return false;
+        }
+        self.high_water_marks
+            .get(instance_id)
+            .is_some_and(|mark| sequence <= *mark)
+            || self
+                .past_gaps
+                .get(instance_id)
+                .is_some_and(|sequences| sequences.contains(&sequence))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        compaction,
+        data::DomainEventMeta,
+        events::{BookmarkEventPayload, DomainEventPayload},
+    };
+    use std::time::SystemTime;
+
+    fn event(instance_id: &str, sequence: u64, title: &str) -> DomainEvent {
+        DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: "123".to_owned(),
+                created_at: SystemTime::UNIX_EPOCH,
+                instance_id: instance_id.to_owned(),
+                sequence,
+                previous_hash: None,
+            },
+            payload: DomainEventPayload::Bookmark(BookmarkEventPayload::TitleUpdated {
+                title: title.to_owned(),
+            }),
+            signature: None,
+            sealed: None,
+        }
+    }
+
+    #[test]
+    fn test_checkpoint_covers_events_it_was_advanced_past() {
+        let mut checkpoint = Checkpoint::default();
+
+        checkpoint.advance(&event("laptop", 1, "foo"));
+        checkpoint.advance(&event("laptop", 0, "bar"));
+
+        assert!(checkpoint.covers(&event("laptop", 1, "foo")));
+        assert!(checkpoint.covers(&compaction::compacted(&event("laptop", 1, "foo"))));
+        assert!(checkpoint.covers(&event("laptop", 0, "bar")));
+        assert!(!checkpoint.covers(&event("laptop", 0, "baz")));
+        assert!(!checkpoint.covers(&event("laptop", 2, "foo")));
+        assert!(!checkpoint.covers(&event("phone", 1, "foo")));
+    }
+
+    #[test]
+    fn test_checkpoint_keeps_only_a_mark_once_gaps_are_filled() {
+        let mut checkpoint = Checkpoint::default();
+
+        checkpoint.advance(&event("laptop", 1, "foo"));
+        checkpoint.advance(&event("laptop", 3, "foo"));
+        checkpoint.advance(&event("laptop", 4, "foo"));
+
+        assert!(checkpoint.covers_sequence("laptop", 4));
+        assert!(!checkpoint.covers_sequence("laptop", 2));
+
+        checkpoint.advance(&event("laptop", 2, "foo"));
+
+        assert_eq!(
+            checkpoint,
+            Checkpoint {
+                high_water_marks: HighWaterMarks::from([("laptop".to_owned(), 4)]),
+                ..Default::default()
+            }
+        );
+    }
+}
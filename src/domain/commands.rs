@@ -1,6 +1,48 @@
+use super::sync::HighWaterMarks;
+use std::time::SystemTime;
+
 #[derive(std::fmt::Debug)]
 pub enum BookmarkCommand {
-    BookmarkPage { url: String, title: String },
-    UpdateTitle { title: String },
+    BookmarkPage {
+        url: String,
+        title: String,
+    },
+    Import {
+        url: String,
+        title: String,
+        note: String,
+        edit_id: String,
+    },
+    UpdateTitle {
+        title: String,
+    },
+    UpdateUrl {
+        url: String,
+    },
+    EditNote {
+        note: String,
+        edit_id: String,
+    },
     Delete,
+    Restore,
+    // Empties the bookmark out of the trash for good
+    Purge,
+    // The event store is expected to destroy the bookmark's key on storing
+    // the event, making its history unreadable, and peers do the same on
+    // importing it.
+    Forget,
+}
+
+#[derive(std::fmt::Debug)]
+pub enum InstanceCommand {
+    Revoke {
+        revoked_after: SystemTime,
+        revoked_by: String,
+    },
+    PublishKey {
+        public_key: String,
+    },
+    Acknowledge {
+        high_water_marks: HighWaterMarks,
+    },
 }
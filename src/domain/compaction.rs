@@ -0,0 +1,320 @@
+use super::{
+    chain,
+    data::DomainEvent,
+    events::{
+        BookmarkEventPayload, CompactedEventPayload, DomainEventPayload, InstanceEventPayload,
+    },
+    revocations::Revocations,
+    sync::{self, HighWaterMarks},
+};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::SystemTime,
+};
+
+// A deleted bookmark's history may only be compacted once it's causally
+// stable, i.e. every known instance has acknowledged, through the
+// high-water marks it publishes, that it has seen all of it. Until then a
+// lagging peer could still need those events, e.g. to be told about the
+// deletion at all.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CompactionPlan {
+    pub compactable: Vec<String>,
+    pub refused: Vec<String>,
+}
+
+pub fn compacted(event: &DomainEvent) -> DomainEvent {
+    DomainEvent {
+        meta: event.meta.clone(),
+        payload: DomainEventPayload::Compacted(CompactedEventPayload {
+            hash: chain::event_hash(event),
+        }),
+        signature: None,
+        sealed: None,
+    }
+}
+
+pub fn is_compacted(event: &DomainEvent) -> bool {
+    matches!(event.payload, DomainEventPayload::Compacted(_))
+}
+
+// Plans which of the bookmarks deleted before `deleted_before` can have
+// `instance_id`'s events compacted. Instances only rewrite their own
+// events; the others follow once their owners compact them too.
+pub fn plan_compaction(
+    events: &[DomainEvent],
+    instance_id: &str,
+    deleted_before: SystemTime,
+) -> CompactionPlan {
+    // Instances are known once they've published their key, which every
+    // version able to acknowledge does on startup. Events predating instance
+    // IDs, or written by versions that never published a key, come from
+    // instances that can't acknowledge anything. Revoked ones won't be heard
+    // from again. Neither is waited for.
+    let revocations = Revocations::from_events(events.iter().cloned());
+    let known_instances: BTreeSet<&str> = events
+        .iter()
+        .filter(|e| {
+            matches!(e.payload, DomainEventPayload::Instance(_))
+                && e.meta.aggregate_id == e.meta.instance_id
+        })
+        .map(|e| e.meta.instance_id.as_str())
+        .filter(|id| !id.is_empty() && !revocations.is_revoked(id))
+        .collect();
+
+    let mut acknowledgements = acknowledgements(events);
+    acknowledgements.insert(
+        instance_id.to_owned(),
+        sync::high_water_marks(events.iter().cloned()),
+    );
+
+    let mut plan = CompactionPlan::default();
+    for (aggregate_id, history) in tombstoned_histories(events, Some(deleted_before)) {
+        if !history
+            .iter()
+            .any(|e| e.meta.instance_id == instance_id && !is_compacted(e))
+        {
+            continue;
+        }
+
+        let stable = history.iter().all(|event| {
+            known_instances.iter().all(|id| {
+                acknowledgements
+                    .get(*id)
+                    .is_some_and(|marks| covers(marks, event))
+            })
+        });
+        if stable {
+            plan.compactable.push(aggregate_id.to_owned());
+        } else {
+            plan.refused.push(aggregate_id.to_owned());
+        }
+    }
+    plan
+}
+
+// Returns the high-water marks `instance_id` should publish, if it has seen
+// events of deleted bookmarks that it hasn't acknowledged yet.
+pub fn pending_acknowledgement(
+    events: &[DomainEvent],
+    instance_id: &str,
+) -> Option<HighWaterMarks> {
+    let marks = sync::high_water_marks(events.iter().cloned());
+    let acknowledged = acknowledgements(events)
+        .remove(instance_id)
+        .unwrap_or_default();
+
+    tombstoned_histories(events, None)
+        .values()
+        .flatten()
+        .any(|e| covers(&marks, e) && !covers(&acknowledged, e))
+        .then_some(marks)
+}
+
+// The latest high-water marks published by each instance
+fn acknowledgements(events: &[DomainEvent]) -> HashMap<String, HighWaterMarks> {
+    let mut latest: HashMap<String, (u64, HighWaterMarks)> = HashMap::new();
+    for event in events {
+        if let DomainEventPayload::Instance(InstanceEventPayload::Acknowledged {
+            high_water_marks,
+        }) = &event.payload
+        {
+            if event.meta.aggregate_id != event.meta.instance_id {
+                continue;
+            }
+            let instance_id = &event.meta.instance_id;
+            if latest
+                .get(instance_id)
+                .is_none_or(|(sequence, _)| *sequence < event.meta.sequence)
+            {
+                latest.insert(
+                    instance_id.clone(),
+                    (event.meta.sequence, high_water_marks.clone()),
+                );
+            }
+        }
+    }
+    latest
+        .into_iter()
+        .map(|(instance_id, (_, marks))| (instance_id, marks))
+        .collect()
+}
+
+// Histories of bookmarks that were last deleted or forgotten, before `deleted_before` if
+// given, or that an instance has already started compacting.
+fn tombstoned_histories(
+    events: &[DomainEvent],
+    deleted_before: Option<SystemTime>,
+) -> BTreeMap<&str, Vec<&DomainEvent>> {
+    let mut histories: BTreeMap<&str, Vec<&DomainEvent>> = BTreeMap::new();
+    for event in events {
+        if let DomainEventPayload::Bookmark(_) | DomainEventPayload::Compacted(_) = event.payload {
+            histories
+                .entry(event.meta.aggregate_id.as_str())
+                .or_default()
+                .push(event);
+        }
+    }
+
+    histories.retain(|_, history| {
+        let deleted = history
+            .iter()
+            .rev()
+            .find(|e| matches!(e.payload, DomainEventPayload::Bookmark(_)))
+            .is_some_and(|e| {
+                matches!(
+                    e.payload,
+                    DomainEventPayload::Bookmark(
+                        BookmarkEventPayload::Deleted
+                            | BookmarkEventPayload::Forgotten
+                            | BookmarkEventPayload::Purged
+                    )
+                ) && deleted_before.is_none_or(|t| e.meta.created_at < t)
+            });
+        deleted || history.iter().any(|e| is_compacted(e))
+    });
+    histories
+}
+
+// Events written before sequence numbers can't be acknowledged, so they're
+// never compacted.
+fn covers(marks: &HighWaterMarks, event: &DomainEvent) -> bool {
+    event.meta.sequence > 0
+        && marks
+            .get(&event.meta.instance_id)
+            .is_some_and(|sequence| *sequence >= event.meta.sequence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::data::DomainEventMeta;
+    use std::time::Duration;
+
+    fn event(instance_id: &str, sequence: u64, payload: DomainEventPayload) -> DomainEvent {
+        DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: "123".to_owned(),
+                created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(sequence),
+                instance_id: instance_id.to_owned(),
+                sequence,
+                previous_hash: None,
+            },
+            payload,
+            signature: None,
+            sealed: None,
+        }
+    }
+
+    fn acknowledged(instance_id: &str, sequence: u64, marks: &[(&str, u64)]) -> DomainEvent {
+        let mut event = event(
+            instance_id,
+            sequence,
+            DomainEventPayload::Instance(InstanceEventPayload::Acknowledged {
+                high_water_marks: marks.iter().map(|(id, s)| (id.to_string(), *s)).collect(),
+            }),
+        );
+        event.meta.aggregate_id = instance_id.to_owned();
+        event
+    }
+
+    fn deleted_bookmark() -> Vec<DomainEvent> {
+        vec![
+            event(
+                "laptop",
+                1,
+                DomainEventPayload::Bookmark(BookmarkEventPayload::Created {
+                    url: "https://example.com".to_owned(),
+                    title: "Example".to_owned(),
+                }),
+            ),
+            event(
+                "phone",
+                1,
+                DomainEventPayload::Bookmark(BookmarkEventPayload::TitleUpdated {
+                    title: "Edited offline".to_owned(),
+                }),
+            ),
+            event(
+                "laptop",
+                2,
+                DomainEventPayload::Bookmark(BookmarkEventPayload::Deleted),
+            ),
+        ]
+    }
+
+    fn later() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(3600)
+    }
+
+    #[test]
+    fn test_compaction_waits_for_every_instance_to_acknowledge() {
+        let mut events = deleted_bookmark();
+        events.push(acknowledged("phone", 2, &[("laptop", 1), ("phone", 1)]));
+
+        let plan = plan_compaction(&events, "laptop", later());
+        assert_eq!(plan.compactable, Vec::<String>::new());
+        assert_eq!(plan.refused, vec!["123".to_owned()]);
+
+        events.push(acknowledged("phone", 3, &[("laptop", 2), ("phone", 2)]));
+
+        let plan = plan_compaction(&events, "laptop", later());
+        assert_eq!(plan.compactable, vec!["123".to_owned()]);
+    }
+
+    #[test]
+    fn test_instances_that_cannot_acknowledge_are_not_waited_for() {
+        let mut events = deleted_bookmark();
+        for (instance_id, sequence) in [("", 0), ("tablet", 1)] {
+            let mut other_bookmark = event(
+                instance_id,
+                sequence,
+                DomainEventPayload::Bookmark(BookmarkEventPayload::Created {
+                    url: "https://example.org".to_owned(),
+                    title: "Other".to_owned(),
+                }),
+            );
+            other_bookmark.meta.aggregate_id = "456".to_owned();
+            events.insert(0, other_bookmark);
+        }
+        events.push(acknowledged("phone", 2, &[("laptop", 2), ("phone", 1)]));
+
+        let plan = plan_compaction(&events, "laptop", later());
+
+        assert_eq!(plan.compactable, vec!["123".to_owned()]);
+    }
+
+    #[test]
+    fn test_recent_deletions_are_not_compacted() {
+        let mut events = deleted_bookmark();
+        events.push(acknowledged("phone", 2, &[("laptop", 2), ("phone", 1)]));
+
+        let plan = plan_compaction(&events, "laptop", SystemTime::UNIX_EPOCH);
+
+        assert_eq!(plan, CompactionPlan::default());
+    }
+
+    #[test]
+    fn test_deletions_are_acknowledged_once() {
+        let mut events = deleted_bookmark();
+
+        let marks = pending_acknowledgement(&events, "phone").unwrap();
+        assert_eq!(
+            marks,
+            HighWaterMarks::from([("laptop".to_owned(), 2), ("phone".to_owned(), 1)])
+        );
+
+        events.push(acknowledged("phone", 2, &[("laptop", 2), ("phone", 1)]));
+        assert_eq!(pending_acknowledgement(&events, "phone"), None);
+    }
+
+    #[test]
+    fn test_compacted_event_keeps_its_place_in_the_chain() {
+        let events = deleted_bookmark();
+
+        let stub = compacted(&events[0]);
+
+        assert_eq!(chain::event_hash(&stub), chain::event_hash(&events[0]));
+        assert_eq!(stub.meta, events[0].meta);
+    }
+}
@@ -1,12 +1,75 @@
-use super::{errors::DomainError, events::DomainEventPayload};
+use super::{
+    aggregates::BookmarkAggregate,
+    errors::DomainError,
+    events::{DomainEventPayload, SealedEventPayload},
+};
 use serde::{Deserialize, Serialize};
 use std::time::SystemTime;
 
-#[derive(std::fmt::Debug, PartialEq, Eq, Clone)]
+#[derive(std::fmt::Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct BookmarkData {
     pub id: String,
     pub url: String,
     pub title: String,
+    pub previous_urls: Vec<String>,
+    pub note: String,
+}
+
+#[derive(std::fmt::Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct TrashedBookmarkData {
+    pub bookmark: BookmarkData,
+    pub deleted_at: SystemTime,
+}
+
+#[derive(std::fmt::Debug, PartialEq, Eq, Clone)]
+pub struct BookmarkHistoryEntry {
+    pub created_at: SystemTime,
+    pub instance_id: String,
+    pub change: String,
+}
+
+// How much of another instance's log has been received, judging from the
+// sequence numbers of its events. Events past the highest one seen can't be
+// known to be missing. Events of types only newer versions know about are
+// received all the same, and counted.
+#[derive(std::fmt::Debug, PartialEq, Eq, Clone)]
+pub struct PeerSyncData {
+    pub instance_id: String,
+    pub up_to_sequence: u64,
+    pub gaps: Vec<(u64, u64)>,
+    pub last_seen_at: SystemTime,
+    pub unknown_events: u64,
+}
+
+impl PeerSyncData {
+    pub fn missing(&self) -> u64 {
+        self.gaps.iter().map(|(from, to)| to - from + 1).sum()
+    }
+}
+
+#[derive(std::fmt::Debug, PartialEq, Eq, Clone)]
+pub enum InstanceSelection {
+    Excluding(Vec<String>),
+    Only(Vec<String>),
+}
+
+impl InstanceSelection {
+    pub fn includes(&self, instance_id: &str) -> bool {
+        match self {
+            InstanceSelection::Excluding(ids) => !ids.iter().any(|id| id == instance_id),
+            InstanceSelection::Only(ids) => ids.iter().any(|id| id == instance_id),
+        }
+    }
+}
+
+// Aggregate state after the first `covered_events` events of its log, the
+// last of which has the ID `last_event_id`. It only holds while that event is
+// still at that position.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BookmarkSnapshot {
+    pub aggregate: BookmarkAggregate,
+    pub covered_events: usize,
+    pub last_event_id: String,
 }
 
 #[derive(std::fmt::Debug)]
@@ -18,18 +81,62 @@ pub struct BookmarkQuery {
 pub struct DomainEvent {
     pub meta: DomainEventMeta,
     pub payload: DomainEventPayload,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    // Set on events unsealed on their way out of the event store, as the
+    // event is still identified by its sealed form.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<SealedEventPayload>,
 }
 
 #[derive(std::fmt::Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct DomainEventMeta {
     pub aggregate_id: String,
     pub created_at: SystemTime,
+    pub instance_id: String,
+    // Position in the instance's own hash chain, starting at 1. Events
+    // written before chaining was introduced have 0.
+    pub sequence: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_hash: Option<String>,
+}
+
+impl DomainEventMeta {
+    pub fn log_position(&self) -> LogPosition {
+        LogPosition {
+            created_at: self.created_at,
+            instance_id: self.instance_id.clone(),
+            sequence: self.sequence,
+        }
+    }
 }
 
-pub trait Aggregate {
+// Where an event falls in the order the log is read back in, which decides
+// between events that can't both hold, such as two instances creating a
+// bookmark with the same ID, wherever they are received first.
+#[derive(std::fmt::Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
+pub struct LogPosition {
+    created_at: SystemTime,
+    instance_id: String,
+    sequence: u64,
+}
+
+pub trait Aggregate: Sized {
     type Command;
     type EventPayload;
 
+    fn new(id: &str) -> Self;
     fn apply_event(self, event: &Self::EventPayload, meta: &DomainEventMeta) -> Self;
-    fn handle_command(&self, command: &Self::Command) -> Result<Self::EventPayload, DomainError>;
+    // Applies what's left of one of this aggregate's events once compacted
+    fn apply_compacted(self, _meta: &DomainEventMeta) -> Self {
+        self
+    }
+    fn handle_command(
+        &self,
+        command: &Self::Command,
+    ) -> Result<Vec<Self::EventPayload>, DomainError>;
+
+    // Where this type of aggregate's events sit among all others in the log
+    fn wrap_payload(payload: Self::EventPayload) -> DomainEventPayload;
+    fn unwrap_payload(payload: &DomainEventPayload) -> Option<&Self::EventPayload>;
 }
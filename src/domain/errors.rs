@@ -4,6 +4,16 @@ pub enum DomainError {
     NoSuchBookmark,
     #[error("Bookmark already exists")]
     BookmarkAlreadyExists,
+    #[error("Bookmark is not in trash")]
+    BookmarkNotInTrash,
+    #[error("An instance cannot revoke itself")]
+    CannotRevokeOwnInstance,
+    #[error("Instance already revoked")]
+    InstanceAlreadyRevoked,
+    #[error("Key already published")]
+    KeyAlreadyPublished,
+    #[error("Changed concurrently, try again")]
+    Conflict,
     #[error("Error interfacing with external system")]
     PortError,
 }
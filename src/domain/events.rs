@@ -1,10 +1,40 @@
-use serde::{Deserialize, Serialize};
+use super::{note::NoteOp, sync::HighWaterMarks};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use std::time::SystemTime;
 
+// (De)serialized by hand below, so that payloads this version can't make
+// sense of end up as `Unknown` instead of failing the whole event.
 #[derive(std::fmt::Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
+#[serde(remote = "Self", tag = "type", rename_all = "snake_case")]
 pub enum DomainEventPayload {
     Bookmark(BookmarkEventPayload),
+    Instance(InstanceEventPayload),
+    Encrypted(EncryptedEventPayload),
+    Sealed(SealedEventPayload),
+    Compacted(CompactedEventPayload),
     Other(OtherEventPayload),
+    #[serde(skip)]
+    Unknown(UnknownEventPayload),
+}
+
+impl Serialize for DomainEventPayload {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            DomainEventPayload::Unknown(unknown) => unknown.0.serialize(serializer),
+            _ => DomainEventPayload::serialize(self, serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for DomainEventPayload {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let payload = Value::deserialize(deserializer)?;
+        DomainEventPayload::deserialize(&payload).or_else(|_| match payload {
+            Value::Object(unknown) => Ok(DomainEventPayload::Unknown(UnknownEventPayload(unknown))),
+            _ => Err(de::Error::custom("event payload isn't an object")),
+        })
+    }
 }
 
 #[derive(std::fmt::Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
@@ -12,8 +42,53 @@ pub enum DomainEventPayload {
 pub enum BookmarkEventPayload {
     Created { url: String, title: String },
     Deleted,
+    Restored,
     TitleUpdated { title: String },
+    UrlUpdated { url: String },
+    NoteEdited { ops: Vec<NoteOp> },
+    Forgotten,
+    Purged,
+}
+
+#[derive(std::fmt::Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum InstanceEventPayload {
+    Revoked { revoked_after: SystemTime },
+    KeyPublished { public_key: String },
+    Acknowledged { high_water_marks: HighWaterMarks },
+}
+
+// Opaque payload of an event encrypted at rest. Metadata stays in clear so
+// that events can still be named, sorted and grouped by aggregate.
+#[derive(std::fmt::Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct EncryptedEventPayload {
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+// A bookmark event's payload encrypted with a key of its own, kept outside
+// the log. Unlike library encryption, it's the sealed form that is hashed,
+// signed and replicated, so that destroying the key leaves the same opaque
+// ciphertext in every copy of the log.
+#[derive(std::fmt::Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct SealedEventPayload {
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+// What's left of an event once its aggregate has been compacted. The hash
+// of the original event is kept, so that it still links its chain and is
+// still recognised by peers.
+#[derive(std::fmt::Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct CompactedEventPayload {
+    pub hash: String,
 }
 
 #[derive(std::fmt::Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub enum OtherEventPayload {}
+
+// Payload of an event written by a newer version, e.g. of a type added
+// since. It's kept verbatim, field order included, so that it's hashed,
+// verified and synced exactly like on the instance that wrote it.
+#[derive(std::fmt::Debug, PartialEq, Eq, Clone)]
+pub struct UnknownEventPayload(pub Map<String, Value>);
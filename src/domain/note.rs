@@ -0,0 +1,225 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+// Free-text note implemented as a replicated growable array (RGA), so
+// that concurrent edits made offline on different instances merge at
+// character level once their events are replayed together.
+
+#[derive(std::fmt::Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct NoteCharId {
+    pub clock: u64,
+    pub edit_id: String,
+}
+
+impl PartialOrd for NoteCharId {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NoteCharId {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.clock
+            .cmp(&other.clock)
+            .then_with(|| self.edit_id.cmp(&other.edit_id))
+    }
+}
+
+#[derive(std::fmt::Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum NoteOp {
+    // Inserts `text` after `after` (or at the start). The n-th character
+    // gets id `{ clock: id.clock + n, edit_id: id.edit_id }`.
+    Insert {
+        id: NoteCharId,
+        after: Option<NoteCharId>,
+        text: String,
+    },
+    Delete {
+        ids: Vec<NoteCharId>,
+    },
+}
+
+#[derive(std::fmt::Debug, Clone, Serialize, Deserialize)]
+struct NoteChar {
+    id: NoteCharId,
+    ch: char,
+    deleted: bool,
+}
+
+#[derive(std::fmt::Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Note {
+    chars: Vec<NoteChar>,
+    max_clock: u64,
+}
+
+impl Note {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn text(&self) -> String {
+        self.chars
+            .iter()
+            .filter(|c| !c.deleted)
+            .map(|c| c.ch)
+            .collect()
+    }
+
+    pub fn apply(&mut self, ops: &[NoteOp]) {
+        for op in ops {
+            match op {
+                NoteOp::Insert { id, after, text } => {
+                    let mut after = after.clone();
+                    for (n, ch) in text.chars().enumerate() {
+                        let char_id = NoteCharId {
+                            clock: id.clock + n as u64,
+                            edit_id: id.edit_id.clone(),
+                        };
+                        self.insert_char(char_id.clone(), after.as_ref(), ch);
+                        after = Some(char_id);
+                    }
+                }
+                NoteOp::Delete { ids } => {
+                    for c in self.chars.iter_mut().filter(|c| ids.contains(&c.id)) {
+                        c.deleted = true;
+                    }
+                }
+            }
+        }
+    }
+
+    // Computes the operations turning the current text into `text`. Only
+    // the span between the common prefix and suffix is touched, so edits
+    // to different parts of the note made elsewhere are preserved.
+    pub fn diff(&self, text: &str, edit_id: &str) -> Vec<NoteOp> {
+        let visible: Vec<&NoteChar> = self.chars.iter().filter(|c| !c.deleted).collect();
+        let new_chars: Vec<char> = text.chars().collect();
+
+        let prefix_len = visible
+            .iter()
+            .zip(new_chars.iter())
+            .take_while(|(c, n)| c.ch == **n)
+            .count();
+        let suffix_len = visible[prefix_len..]
+            .iter()
+            .rev()
+            .zip(new_chars[prefix_len..].iter().rev())
+            .take_while(|(c, n)| c.ch == **n)
+            .count();
+
+        let mut ops = vec![];
+
+        let deleted: Vec<NoteCharId> = visible[prefix_len..visible.len() - suffix_len]
+            .iter()
+            .map(|c| c.id.clone())
+            .collect();
+        if !deleted.is_empty() {
+            ops.push(NoteOp::Delete { ids: deleted });
+        }
+
+        let inserted: String = new_chars[prefix_len..new_chars.len() - suffix_len]
+            .iter()
+            .collect();
+        if !inserted.is_empty() {
+            ops.push(NoteOp::Insert {
+                id: NoteCharId {
+                    clock: self.max_clock + 1,
+                    edit_id: edit_id.to_owned(),
+                },
+                after: prefix_len.checked_sub(1).map(|i| visible[i].id.clone()),
+                text: inserted,
+            });
+        }
+
+        ops
+    }
+
+    fn insert_char(&mut self, id: NoteCharId, after: Option<&NoteCharId>, ch: char) {
+        if self.chars.iter().any(|c| c.id == id) {
+            return;
+        }
+
+        // A reference to an unknown character means the event introducing
+        // it hasn't been seen yet; appending keeps the text rather than
+        // dropping it.
+        let mut pos = match after {
+            None => 0,
+            Some(after) => match self.chars.iter().position(|c| &c.id == after) {
+                Some(i) => i + 1,
+                None => self.chars.len(),
+            },
+        };
+
+        // Concurrent inserts at the same position are ordered by id, so
+        // every instance ends up with the same sequence.
+        while pos < self.chars.len() && self.chars[pos].id > id {
+            pos += 1;
+        }
+
+        self.max_clock = self.max_clock.max(id.clock);
+        self.chars.insert(
+            pos,
+            NoteChar {
+                id,
+                ch,
+                deleted: false,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_reproduces_edited_text() {
+        let mut note = Note::new();
+        note.apply(&note.diff("hello world", "a"));
+        note.apply(&note.diff("hello brave world", "b"));
+        note.apply(&note.diff("hello brave new world!", "c"));
+
+        assert_eq!(note.text(), "hello brave new world!");
+    }
+
+    #[test]
+    fn test_concurrent_edits_merge_at_character_level() {
+        let mut base = Note::new();
+        base.apply(&base.diff("The quick fox", "base"));
+
+        let laptop_ops = base.diff("The quick brown fox", "laptop");
+        let phone_ops = base.diff("The quick fox jumps", "phone");
+
+        let mut laptop = base.clone();
+        laptop.apply(&laptop_ops);
+        laptop.apply(&phone_ops);
+
+        let mut phone = base.clone();
+        phone.apply(&phone_ops);
+        phone.apply(&laptop_ops);
+
+        assert_eq!(laptop.text(), "The quick brown fox jumps");
+        assert_eq!(phone.text(), laptop.text());
+    }
+
+    #[test]
+    fn test_concurrent_inserts_at_same_position_converge() {
+        let mut base = Note::new();
+        base.apply(&base.diff("ab", "base"));
+
+        let first_ops = base.diff("a1b", "first");
+        let second_ops = base.diff("a2b", "second");
+
+        let mut first = base.clone();
+        first.apply(&first_ops);
+        first.apply(&second_ops);
+
+        let mut second = base.clone();
+        second.apply(&second_ops);
+        second.apply(&first_ops);
+
+        assert_eq!(first.text(), second.text());
+        assert_eq!(first.text().len(), 4);
+    }
+}
@@ -0,0 +1,180 @@
+use super::{chain, data::DomainEvent};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashSet};
+
+// Ranges with at most this many events are listed in full instead of being
+// split further.
+const LEAF_SIZE: usize = 16;
+
+// Events are identified by the hash of their content, which is also what
+// chains them, so IDs are the same on every instance and don't depend on
+// how an event was received.
+pub fn event_id(event: &DomainEvent) -> String {
+    chain::event_hash(event)
+}
+
+// The sorted set of event IDs in a log. Logs are compared by hashing the
+// IDs that share a hex prefix, and only descending into the prefixes whose
+// hashes differ, so that the events missing on either side are found in a
+// few round trips even with a long history.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EventIds {
+    ids: BTreeSet<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct RangeFingerprint {
+    pub prefix: String,
+    pub count: usize,
+    pub hash: String,
+    // Present for small ranges, so that they needn't be split further
+    pub ids: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RangeComparison {
+    pub prefixes_to_split: Vec<String>,
+    pub missing_locally: Vec<String>,
+    pub missing_remotely: Vec<String>,
+}
+
+impl EventIds {
+    pub fn from_events(events: impl Iterator<Item = DomainEvent>) -> Self {
+        let mut ids = Self::default();
+        for event in events {
+            ids.insert(&event);
+        }
+        ids
+    }
+
+    pub fn insert(&mut self, event: &DomainEvent) {
+        self.insert_id(&event_id(event));
+    }
+
+    pub fn insert_id(&mut self, id: &str) {
+        self.ids.insert(id.to_owned());
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+
+    pub fn fingerprint(&self, prefix: &str) -> RangeFingerprint {
+        let ids: Vec<&String> = self.with_prefix(prefix).collect();
+        let mut hasher = Sha256::new();
+        for id in &ids {
+            hasher.update(id.as_bytes());
+        }
+        RangeFingerprint {
+            prefix: prefix.to_owned(),
+            count: ids.len(),
+            hash: hex::encode(hasher.finalize()),
+            ids: (ids.len() <= LEAF_SIZE).then(|| ids.into_iter().cloned().collect()),
+        }
+    }
+
+    // Compares the peer's fingerprints of some ranges against this log.
+    pub fn compare(&self, remote: &[RangeFingerprint]) -> RangeComparison {
+        let mut comparison = RangeComparison::default();
+        for range in remote {
+            let local = self.fingerprint(&range.prefix);
+            if local.count == range.count && local.hash == range.hash {
+                continue;
+            }
+
+            match (&local.ids, &range.ids) {
+                (Some(local_ids), Some(remote_ids)) => {
+                    let local_ids: HashSet<&String> = local_ids.iter().collect();
+                    let remote_ids: HashSet<&String> = remote_ids.iter().collect();
+                    comparison
+                        .missing_locally
+                        .extend(remote_ids.difference(&local_ids).map(|id| id.to_string()));
+                    comparison
+                        .missing_remotely
+                        .extend(local_ids.difference(&remote_ids).map(|id| id.to_string()));
+                }
+                _ => comparison.prefixes_to_split.extend(
+                    "0123456789abcdef"
+                        .chars()
+                        .map(|digit| format!("{}{}", range.prefix, digit)),
+                ),
+            }
+        }
+        comparison.missing_locally.sort();
+        comparison.missing_remotely.sort();
+        comparison
+    }
+
+    fn with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a String> {
+        self.ids
+            .range(prefix.to_owned()..)
+            .take_while(move |id| id.starts_with(prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        data::DomainEventMeta,
+        events::{DomainEventPayload, InstanceEventPayload},
+    };
+    use std::time::SystemTime;
+
+    fn event(sequence: u64) -> DomainEvent {
+        DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: "laptop".to_owned(),
+                created_at: SystemTime::UNIX_EPOCH,
+                instance_id: "laptop".to_owned(),
+                sequence,
+                previous_hash: None,
+            },
+            payload: DomainEventPayload::Instance(InstanceEventPayload::KeyPublished {
+                public_key: "".to_owned(),
+            }),
+            signature: None,
+            sealed: None,
+        }
+    }
+
+    // Plays both sides of the exchange, returning the number of round trips
+    fn reconcile(local: &EventIds, remote: &EventIds) -> (RangeComparison, usize) {
+        let mut result = RangeComparison::default();
+        let mut prefixes = vec!["".to_owned()];
+        let mut round_trips = 0;
+        while !prefixes.is_empty() {
+            round_trips += 1;
+            let fingerprints: Vec<RangeFingerprint> =
+                prefixes.iter().map(|p| remote.fingerprint(p)).collect();
+            let comparison = local.compare(&fingerprints);
+            result.missing_locally.extend(comparison.missing_locally);
+            result.missing_remotely.extend(comparison.missing_remotely);
+            prefixes = comparison.prefixes_to_split;
+        }
+        (result, round_trips)
+    }
+
+    #[test]
+    fn test_identical_logs_are_reconciled_in_one_round_trip() {
+        let ids = EventIds::from_events((1..1000).map(event));
+
+        let (comparison, round_trips) = reconcile(&ids, &ids.clone());
+
+        assert_eq!(comparison, RangeComparison::default());
+        assert_eq!(round_trips, 1);
+    }
+
+    #[test]
+    fn test_events_missing_on_either_side_are_found() {
+        let local = EventIds::from_events((1..1000).filter(|s| *s != 500).map(event));
+        let remote = EventIds::from_events((1..1000).filter(|s| *s != 10).map(event));
+
+        let (comparison, round_trips) = reconcile(&local, &remote);
+
+        assert_eq!(comparison.missing_locally, vec![event_id(&event(500))]);
+        assert_eq!(comparison.missing_remotely, vec![event_id(&event(10))]);
+        assert!(round_trips <= 3);
+    }
+}
@@ -0,0 +1,154 @@
+use super::{
+    data::DomainEvent,
+    events::{DomainEventPayload, InstanceEventPayload},
+};
+use std::{collections::HashMap, time::SystemTime};
+
+// Cut-offs after which events from revoked instances are ignored. A
+// revocation only counts if its issuer wasn't itself revoked by then, so a
+// lost device can't revoke the ones it was lost from. Of two instances
+// revoking each other, the one that did so first wins.
+#[derive(Clone)]
+pub struct Revocations {
+    revoked_after_by_instance: HashMap<String, SystemTime>,
+}
+
+struct Issued {
+    issuer: String,
+    target: String,
+    issued_at: SystemTime,
+    revoked_after: SystemTime,
+}
+
+impl Issued {
+    fn cuts_off(&self, other: &Issued) -> bool {
+        self.target == other.issuer && self.revoked_after < other.issued_at
+    }
+
+    fn overrides(&self, other: &Issued) -> bool {
+        self.cuts_off(other) && !(other.cuts_off(self) && other.issued_at < self.issued_at)
+    }
+}
+
+impl Revocations {
+    pub fn from_events(events: impl Iterator<Item = DomainEvent>) -> Self {
+        let issued: Vec<Issued> = events
+            .filter_map(|e| match e.payload {
+                DomainEventPayload::Instance(InstanceEventPayload::Revoked { revoked_after }) => {
+                    Some(Issued {
+                        issuer: e.meta.instance_id,
+                        target: e.meta.aggregate_id,
+                        issued_at: e.meta.created_at,
+                        revoked_after,
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+
+        // A revocation counts once everything overriding it is known not to,
+        // and doesn't once anything overriding it does.
+        let mut counts: Vec<Option<bool>> = vec![None; issued.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in 0..issued.len() {
+                if counts[i].is_some() {
+                    continue;
+                }
+                let mut overriders = (0..issued.len()).filter(|j| issued[*j].overrides(&issued[i]));
+                if overriders.clone().any(|j| counts[j] == Some(true)) {
+                    counts[i] = Some(false);
+                    changed = true;
+                } else if overriders.all(|j| counts[j] == Some(false)) {
+                    counts[i] = Some(true);
+                    changed = true;
+                }
+            }
+        }
+
+        Self::with_cutoffs(
+            issued
+                .iter()
+                .zip(counts)
+                .filter(|(_, counts)| *counts == Some(true))
+                .map(|(issued, _)| (issued.target.clone(), issued.revoked_after)),
+        )
+    }
+
+    pub fn allows(&self, event: &DomainEvent) -> bool {
+        match self.revoked_after_by_instance.get(&event.meta.instance_id) {
+            Some(revoked_after) => event.meta.created_at <= *revoked_after,
+            None => true,
+        }
+    }
+
+    pub fn is_revoked(&self, instance_id: &str) -> bool {
+        self.revoked_after_by_instance.contains_key(instance_id)
+    }
+
+    fn with_cutoffs(cutoffs: impl Iterator<Item = (String, SystemTime)>) -> Self {
+        let mut revoked_after_by_instance: HashMap<String, SystemTime> = HashMap::new();
+        for (instance_id, revoked_after) in cutoffs {
+            revoked_after_by_instance
+                .entry(instance_id)
+                .and_modify(|t| *t = (*t).min(revoked_after))
+                .or_insert(revoked_after);
+        }
+        Self {
+            revoked_after_by_instance,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::data::DomainEventMeta;
+    use std::time::Duration;
+
+    fn revocation(issuer: &str, target: &str, issued_at: u64, revoked_after: u64) -> DomainEvent {
+        DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: target.to_owned(),
+                created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(issued_at),
+                instance_id: issuer.to_owned(),
+                sequence: 0,
+                previous_hash: None,
+            },
+            payload: DomainEventPayload::Instance(InstanceEventPayload::Revoked {
+                revoked_after: SystemTime::UNIX_EPOCH + Duration::from_secs(revoked_after),
+            }),
+            signature: None,
+            sealed: None,
+        }
+    }
+
+    #[test]
+    fn test_first_of_mutual_revocations_wins() {
+        let revocations = Revocations::from_events(
+            vec![
+                revocation("laptop", "phone", 100, 50),
+                revocation("phone", "laptop", 200, 0),
+            ]
+            .into_iter(),
+        );
+
+        assert!(revocations.is_revoked("phone"));
+        assert!(!revocations.is_revoked("laptop"));
+    }
+
+    #[test]
+    fn test_revocations_issued_after_issuer_was_lost_are_ignored() {
+        let revocations = Revocations::from_events(
+            vec![
+                revocation("phone", "laptop", 5, 4),
+                revocation("tablet", "phone", 10, 3),
+            ]
+            .into_iter(),
+        );
+
+        assert!(revocations.is_revoked("phone"));
+        assert!(!revocations.is_revoked("laptop"));
+    }
+}
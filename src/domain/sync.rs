@@ -0,0 +1,97 @@
+use super::data::DomainEvent;
+use std::collections::{BTreeMap, BTreeSet};
+
+// What a log contains, summarised as the highest sequence number received
+// without gaps from each instance. Events written before sequence numbers
+// were introduced aren't covered.
+pub type HighWaterMarks = BTreeMap<String, u64>;
+
+pub fn high_water_marks(events: impl Iterator<Item = DomainEvent>) -> HighWaterMarks {
+    let mut sequences_by_instance: BTreeMap<String, BTreeSet<u64>> = BTreeMap::new();
+    for event in events.filter(|e| e.meta.sequence > 0) {
+        sequences_by_instance
+            .entry(event.meta.instance_id)
+            .or_default()
+            .insert(event.meta.sequence);
+    }
+
+    sequences_by_instance
+        .into_iter()
+        .map(|(instance_id, sequences)| {
+            let contiguous = sequences
+                .iter()
+                .zip(1..)
+                .take_while(|(sequence, expected)| **sequence == *expected)
+                .count();
+            (instance_id, contiguous as u64)
+        })
+        .collect()
+}
+
+// Events that a log summarised by `high_water_marks` is known not to have
+// seen yet. It may still have some of them, past a gap.
+pub fn events_missing_from(
+    high_water_marks: &HighWaterMarks,
+    events: impl Iterator<Item = DomainEvent>,
+) -> Vec<DomainEvent> {
+    events
+        .filter(|e| {
+            e.meta.sequence > 0
+                && e.meta.sequence > *high_water_marks.get(&e.meta.instance_id).unwrap_or(&0)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{
+        data::DomainEventMeta,
+        events::{DomainEventPayload, InstanceEventPayload},
+    };
+    use std::time::SystemTime;
+
+    fn event(instance_id: &str, sequence: u64) -> DomainEvent {
+        DomainEvent {
+            meta: DomainEventMeta {
+                aggregate_id: instance_id.to_owned(),
+                created_at: SystemTime::UNIX_EPOCH,
+                instance_id: instance_id.to_owned(),
+                sequence,
+                previous_hash: None,
+            },
+            payload: DomainEventPayload::Instance(InstanceEventPayload::KeyPublished {
+                public_key: "".to_owned(),
+            }),
+            signature: None,
+            sealed: None,
+        }
+    }
+
+    #[test]
+    fn test_high_water_marks_stop_at_first_gap() {
+        let events = vec![
+            event("laptop", 1),
+            event("laptop", 2),
+            event("laptop", 4),
+            event("phone", 2),
+            event("tablet", 0),
+        ];
+
+        assert_eq!(
+            high_water_marks(events.into_iter()),
+            HighWaterMarks::from([("laptop".to_owned(), 2), ("phone".to_owned(), 0)])
+        );
+    }
+
+    #[test]
+    fn test_events_past_high_water_marks_are_missing() {
+        let events = vec![event("laptop", 1), event("laptop", 2), event("phone", 1)];
+        let marks = HighWaterMarks::from([("laptop".to_owned(), 1)]);
+
+        assert_eq!(
+            events_missing_from(&marks, events.into_iter()),
+            vec![event("laptop", 2), event("phone", 1)]
+        );
+    }
+}
@@ -1,36 +1,653 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use decentrasync::{
     adapters::{
-        clock::SystemClock, file_event_store::FileSystemEventStore, http_api_axum,
+        bundle_file::{read_bundle, write_bundle, Bundle},
+        clock::SystemClock,
+        encrypted_event_store::{
+            load_or_create_library_salt, EncryptedEventStore, LIBRARY_SALT_FILE,
+        },
+        file_event_store::FileSystemEventStore,
+        file_key_store::FileKeyStore,
+        file_snapshot_store::FileSnapshotStore,
+        http_api_axum,
+        http_sync_client::{self, HttpSyncClient},
         memory_read_model::MemoryReadModel,
+        shredding_event_store::ShreddingEventStore,
+        signed_event_store::{
+            decode_key, load_or_create_signing_key, load_trusted_keys, save_trusted_keys,
+            SignedEventStore,
+        },
     },
     app,
+    domain::{
+        aggregates::{BookmarkAggregate, InstanceAggregate},
+        commands::{BookmarkCommand, InstanceCommand},
+        data::DomainEvent,
+        errors::DomainError,
+        events::DomainEventPayload,
+        sync::HighWaterMarks,
+    },
+    ports::{EventStore, ReadModel, SnapshotStore},
+};
+use std::{
+    env, fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
-use std::{env, net::SocketAddr, path::Path, sync::Arc};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use uuid::Uuid;
 
 #[derive(Parser, Debug)]
 #[command(about)]
 struct Args {
+    /// Port of the local UI and API, which are only served to this device
     #[arg(short, long, default_value_t = 9111)]
     port: u16,
+    /// Port of the sync API other instances reach with `sync --peer`
+    #[arg(long, default_value_t = 9112)]
+    peer_port: u16,
+    /// Address to serve the sync API on; use 0.0.0.0 for peers on the LAN
+    /// to reach this instance. Only the sync API is served there
+    #[arg(long, default_value = "127.0.0.1")]
+    peer_bind: IpAddr,
+    #[arg(long, default_value_t = 30)]
+    trash_retention_days: u64,
+    /// Roll this instance's events older than this into pack files
+    #[arg(long, default_value_t = 7)]
+    pack_after_days: u64,
+    #[arg(long)]
+    instance_id: Option<String>,
+    /// Encrypt the event log with a key derived from this passphrase. Files
+    /// kept on this device only, such as the read model, aren't encrypted.
+    #[arg(long, env = "DECENTRASYNC_PASSPHRASE", hide_env_values = true)]
+    passphrase: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print bookmarks, optionally as they were at a past instant
+    List {
+        /// RFC3339 timestamp, e.g. 2023-01-31T10:00:00Z
+        #[arg(long)]
+        as_of: Option<String>,
+    },
+    /// Print this instance's ID and public key
+    Identity,
+    /// Trust events signed with the key another instance published in the log
+    Trust {
+        instance_id: String,
+        /// The key to trust, as printed by `identity` on the other instance.
+        /// Required when more than one key was published in its name
+        #[arg(long)]
+        key: Option<String>,
+    },
+    /// Print events refused for not being signed by a trusted instance
+    Quarantine,
+    /// Check every instance's hash chain for missing, forked or altered events
+    Verify,
+    /// Ignore another instance's events created after a point in time, e.g.
+    /// when the device it runs on is lost. Recorded as revoked by this one
+    Revoke {
+        instance_id: String,
+        /// RFC3339 timestamp, e.g. 2023-01-31T10:00:00Z
+        #[arg(long)]
+        after: String,
+    },
+    /// Exchange events with another running instance until both logs match.
+    /// Both instances must trust each other, see `trust`
+    Sync {
+        /// Base URL of the peer's sync API, e.g. http://192.168.1.12:9112
+        #[arg(long)]
+        peer: String,
+    },
+    /// Print which events this instance has, for a peer to export a bundle
+    Summary,
+    /// Write events into a single file that can be carried to another device.
+    /// Bookmark events are written sealed, as they're stored
+    ExportBundle {
+        path: PathBuf,
+        /// File with the output of `summary` on the receiving device; only
+        /// events it hasn't seen are exported. Exports everything if omitted
+        #[arg(long)]
+        known: Option<PathBuf>,
+        /// Also write the keys that unseal the exported bookmarks, which makes
+        /// them readable by anyone holding the file, so keep it private
+        #[arg(long)]
+        include_keys: bool,
+    },
+    /// Import the events of a bundle written by `export-bundle`
+    ImportBundle { path: PathBuf },
+    /// Make a bookmark's history unreadable on every instance, for good
+    Forget { id: String },
+    /// Drop the history of bookmarks deleted longer ago than the trash
+    /// retention, once every known instance has acknowledged the deletion
+    Compact,
+}
+
+// The instance ID must survive restarts but must not be shared with other
+// instances, so it's kept next to (not inside) the synced log folder.
+// Files only this device uses, kept next to the synced log folder rather than
+// in it. Some of them hold what the log does in plaintext, e.g. the read
+// model, so they must never be synced, even when the log is encrypted.
+fn local_file(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("decentrasync-{}", name))
+}
+
+fn load_or_create_instance_id(path: PathBuf) -> String {
+    match fs::read_to_string(&path) {
+        Ok(instance_id) => instance_id.trim().to_owned(),
+        Err(_) => {
+            let instance_id = Uuid::new_v4().to_string();
+            fs::write(&path, &instance_id).unwrap();
+            instance_id
+        }
+    }
+}
+
+// The adapters wired up in `main`, for commands to pick from
+struct Services {
+    signed_event_store: Arc<SignedEventStore>,
+    event_store: Arc<ShreddingEventStore>,
+    snapshot_store: Arc<FileSnapshotStore>,
+    key_store: Arc<FileKeyStore>,
+    read_model: Arc<MemoryReadModel>,
+    clock: Arc<SystemClock>,
+}
+
+// Events of types this version doesn't know are kept and synced, but
+// otherwise ignored until it's upgraded.
+// Projections that fail are retried by later catch-ups, and the ones that
+// went through are still served in the meantime.
+fn init_read_model(
+    event_store: Arc<dyn EventStore>,
+    snapshot_store: Arc<dyn SnapshotStore>,
+    read_model: Arc<dyn ReadModel>,
+) {
+    if let Err(err) = app::init(event_store, snapshot_store, read_model) {
+        eprintln!("Failed to bring the read model up to date: {}", err);
+    }
+}
+
+fn warn_about_newer_versions(event_store: Arc<dyn EventStore>, read_model: Arc<dyn ReadModel>) {
+    for peer in app::read_sync_peers(event_store, read_model).unwrap_or_default() {
+        if peer.unknown_events > 0 {
+            eprintln!(
+                "{} wrote {} events only a newer version understands, consider upgrading",
+                peer.instance_id, peer.unknown_events
+            );
+        }
+    }
+}
+
+// Events refused on import are kept aside, for users to trust their
+// instances and sync again if they turn out to be legitimate.
+fn warn_about_quarantine(signed_event_store: &SignedEventStore) {
+    let quarantined = signed_event_store.quarantined_events().len();
+    if quarantined > 0 {
+        eprintln!(
+            "{} events from untrusted instances are quarantined, see `quarantine`",
+            quarantined
+        );
+    }
+}
+
+// Files in the log folder that can't be read, e.g. conflict copies made by
+// the tool syncing it, or events written by a newer version, are skipped.
+fn warn_about_unreadable_files(file_event_store: &FileSystemEventStore) {
+    for path in file_event_store.unreadable_files() {
+        eprintln!(
+            "Skipping {}: not an event this version can read",
+            path.display()
+        );
+    }
+}
+
+async fn run_command(
+    command: Command,
+    services: Services,
+    trash_retention: Duration,
+    trusted_keys_path: &Path,
+) {
+    let Services {
+        signed_event_store,
+        event_store,
+        snapshot_store,
+        key_store,
+        read_model,
+        clock,
+    } = services;
+
+    match command {
+        Command::List { as_of } => {
+            let as_of = match as_of {
+                Some(as_of) => OffsetDateTime::parse(&as_of, &Rfc3339)
+                    .expect("invalid --as-of timestamp")
+                    .into(),
+                None => SystemTime::now(),
+            };
+            for bookmark in app::read_bookmarks_as_of(as_of, event_store).unwrap() {
+                println!("{}\t{}\t{}", bookmark.id, bookmark.title, bookmark.url);
+            }
+        }
+        Command::Identity => {
+            println!(
+                "{}\t{}",
+                event_store.instance_id(),
+                signed_event_store.public_key()
+            );
+        }
+        Command::Trust { instance_id, key } => {
+            let published_keys = app::read_published_keys(&instance_id, event_store);
+            let public_key = match (key, published_keys.as_slice()) {
+                (Some(key), _) if published_keys.contains(&key) => key,
+                (Some(key), _) => {
+                    eprintln!("{} hasn't published the key {}", instance_id, key);
+                    process::exit(1);
+                }
+                (None, [key]) => key.clone(),
+                (None, []) => {
+                    eprintln!("No key published by {}", instance_id);
+                    process::exit(1);
+                }
+                (None, keys) => {
+                    eprintln!(
+                        "Several keys were published in the name of {}, pass the one `identity` \
+                         prints there with --key:",
+                        instance_id
+                    );
+                    for key in keys {
+                        eprintln!("{}", key);
+                    }
+                    process::exit(1);
+                }
+            };
+            let mut trusted_keys = load_trusted_keys(trusted_keys_path);
+            trusted_keys.insert(instance_id, decode_key(&public_key).unwrap());
+            save_trusted_keys(trusted_keys_path, &trusted_keys);
+            println!("Trusted {}", public_key);
+        }
+        Command::Quarantine => {
+            for event in signed_event_store.quarantined_events() {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    OffsetDateTime::from(event.meta.created_at)
+                        .format(&Rfc3339)
+                        .unwrap(),
+                    event.meta.instance_id,
+                    event.meta.sequence,
+                    event.meta.aggregate_id
+                );
+            }
+        }
+        Command::Verify => {
+            let issues = app::verify_chains(event_store);
+            if issues.is_empty() {
+                println!("All instance chains are intact");
+            }
+            for issue in issues {
+                println!("{}", issue);
+            }
+        }
+        Command::Revoke { instance_id, after } => {
+            let revoked_after = OffsetDateTime::parse(&after, &Rfc3339)
+                .expect("invalid --after timestamp")
+                .into();
+            init_read_model(
+                event_store.clone(),
+                snapshot_store.clone(),
+                read_model.clone(),
+            );
+            match app::dispatch::<InstanceAggregate>(
+                &instance_id,
+                InstanceCommand::Revoke {
+                    revoked_after,
+                    revoked_by: event_store.instance_id(),
+                },
+                None,
+                event_store.clone(),
+                snapshot_store,
+                read_model,
+                clock,
+            ) {
+                Ok(()) => println!("Revoked {} after {}", instance_id, after),
+                Err(err) => eprintln!("Could not revoke {}: {}", instance_id, err),
+            }
+        }
+        Command::Sync { peer } => {
+            init_read_model(
+                event_store.clone(),
+                snapshot_store.clone(),
+                read_model.clone(),
+            );
+            match http_sync_client::sync_with_peer(
+                &HttpSyncClient::new(&peer, signed_event_store.clone()),
+                event_store.clone(),
+                snapshot_store,
+                key_store,
+                read_model.clone(),
+            )
+            .await
+            {
+                Ok(()) => println!("In sync with {}", peer),
+                Err(err) => eprintln!("Sync with {} failed: {}", peer, err),
+            }
+            warn_about_newer_versions(event_store, read_model);
+            warn_about_quarantine(&signed_event_store);
+        }
+        Command::Summary => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&app::read_sync_summary(event_store)).unwrap()
+            );
+        }
+        Command::ExportBundle {
+            path,
+            known,
+            include_keys,
+        } => {
+            // Read below the shredding store, so that bookmark events leave
+            // this device sealed
+            let events: Vec<DomainEvent> = match known {
+                Some(known) => {
+                    let high_water_marks: HighWaterMarks =
+                        serde_json::from_str(&fs::read_to_string(known).unwrap())
+                            .expect("invalid summary file");
+                    app::read_events_missing_from(&high_water_marks, signed_event_store)
+                }
+                None => signed_event_store.events_iter().collect(),
+            };
+            let keys = if include_keys {
+                let aggregate_ids: Vec<String> =
+                    events.iter().map(|e| e.meta.aggregate_id.clone()).collect();
+                app::export_keys(&aggregate_ids, key_store)
+            } else {
+                Default::default()
+            };
+            let bundle = Bundle { keys, events };
+            write_bundle(&path, &bundle).unwrap();
+            println!("Exported {} events", bundle.events.len());
+        }
+        Command::ImportBundle { path } => {
+            let bundle = read_bundle(&path).unwrap();
+            init_read_model(
+                event_store.clone(),
+                snapshot_store.clone(),
+                read_model.clone(),
+            );
+            let total = bundle.events.len();
+            let imported = app::import_events(
+                bundle.events,
+                event_store.clone(),
+                snapshot_store.clone(),
+                read_model.clone(),
+            )
+            .iter()
+            .filter(|result| result.is_ok())
+            .count();
+            println!("Imported {} of {} events", imported, total);
+            app::import_keys(
+                &bundle.keys,
+                event_store,
+                snapshot_store,
+                key_store,
+                read_model,
+            )
+            .unwrap();
+        }
+        Command::Forget { id } => {
+            init_read_model(
+                event_store.clone(),
+                snapshot_store.clone(),
+                read_model.clone(),
+            );
+            match app::dispatch::<BookmarkAggregate>(
+                &id,
+                BookmarkCommand::Forget,
+                None,
+                event_store,
+                snapshot_store,
+                read_model,
+                clock,
+            ) {
+                Ok(()) => println!("Forgot {}", id),
+                Err(err) => eprintln!("Could not forget {}: {}", id, err),
+            }
+        }
+        Command::Compact => {
+            init_read_model(
+                event_store.clone(),
+                snapshot_store.clone(),
+                read_model.clone(),
+            );
+            app::acknowledge_deletions(
+                event_store.clone(),
+                snapshot_store.clone(),
+                read_model.clone(),
+                clock.clone(),
+            )
+            .unwrap();
+            let plan = app::compact(
+                trash_retention,
+                event_store,
+                snapshot_store,
+                read_model,
+                clock,
+            )
+            .unwrap();
+            println!("Compacted {} deleted bookmarks", plan.compactable.len());
+            for aggregate_id in plan.refused {
+                println!(
+                    "Kept {}: not acknowledged by every instance yet",
+                    aggregate_id
+                );
+            }
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    let addr = SocketAddr::from(([127, 0, 0, 1], args.port));
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), args.port);
+    let peer_addr = SocketAddr::new(args.peer_bind, args.peer_port);
     let log_folder_path = Path::new(&env::temp_dir()).join("decentrasync");
 
-    let event_store = Arc::new(FileSystemEventStore::new(log_folder_path.as_os_str()));
-    let read_model = Arc::new(MemoryReadModel::new());
+    let instance_id = args
+        .instance_id
+        .unwrap_or_else(|| load_or_create_instance_id(local_file("instance-id")));
+
+    let signing_key = load_or_create_signing_key(&local_file("identity.key"));
+    let trusted_keys_path = local_file("trusted-keys.json");
+
+    let packed_event_store = Arc::new(FileSystemEventStore::new(
+        log_folder_path.as_os_str(),
+        &instance_id,
+    ));
+    warn_about_unreadable_files(&packed_event_store);
+    let file_event_store: Arc<dyn EventStore> = match &args.passphrase {
+        Some(passphrase) => {
+            let salt = load_or_create_library_salt(
+                &log_folder_path.join(LIBRARY_SALT_FILE),
+                packed_event_store.as_ref(),
+            )
+            .unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                process::exit(1);
+            });
+            let encrypted_event_store =
+                EncryptedEventStore::new(packed_event_store.clone(), passphrase, &salt);
+            if let Err(err) = encrypted_event_store.check_library_key() {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+            Arc::new(encrypted_event_store)
+        }
+        None if packed_event_store
+            .events_iter()
+            .any(|e| matches!(e.payload, DomainEventPayload::Encrypted(_))) =>
+        {
+            eprintln!("The log is encrypted, pass --passphrase to open it");
+            process::exit(1);
+        }
+        None => packed_event_store.clone(),
+    };
+
+    let signed_event_store = Arc::new(
+        SignedEventStore::new(
+            file_event_store,
+            signing_key,
+            load_trusted_keys(&trusted_keys_path),
+        )
+        .with_quarantine_file(&local_file("quarantine.json"))
+        .with_adopted_events_file(&local_file("adopted-events.json")),
+    );
+    let key_store = Arc::new(FileKeyStore::new(&local_file("bookmark-keys.json")));
+    let event_store = Arc::new(ShreddingEventStore::new(
+        signed_event_store.clone(),
+        key_store.clone(),
+    ));
+    let snapshot_store = Arc::new(FileSnapshotStore::new(&local_file("snapshots.json")));
+    let read_model = Arc::new(MemoryReadModel::new().with_file(&local_file("read-model.json")));
     let clock = Arc::new(SystemClock::new());
+    let trash_retention = Duration::from_secs(args.trash_retention_days * 24 * 60 * 60);
+
+    if let Some(command) = args.command {
+        run_command(
+            command,
+            Services {
+                signed_event_store,
+                event_store,
+                snapshot_store,
+                key_store,
+                read_model,
+                clock,
+            },
+            trash_retention,
+            &trusted_keys_path,
+        )
+        .await;
+        return;
+    }
+
+    match app::dispatch::<InstanceAggregate>(
+        &event_store.instance_id(),
+        InstanceCommand::PublishKey {
+            public_key: signed_event_store.public_key(),
+        },
+        None,
+        event_store.clone(),
+        snapshot_store.clone(),
+        read_model.clone(),
+        clock.clone(),
+    ) {
+        Ok(()) | Err(DomainError::KeyAlreadyPublished) => {}
+        Err(err) => panic!("{}", err),
+    }
+
+    init_read_model(
+        event_store.clone(),
+        snapshot_store.clone(),
+        read_model.clone(),
+    );
+    warn_about_newer_versions(event_store.clone(), read_model.clone());
+    warn_about_quarantine(&signed_event_store);
 
-    app::init(event_store.clone(), read_model.clone());
+    tokio::spawn({
+        let event_store = event_store.clone();
+        let snapshot_store = snapshot_store.clone();
+        let read_model = read_model.clone();
+        let clock = clock.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+            loop {
+                interval.tick().await;
+                if let Err(err) = app::purge_trash(
+                    trash_retention,
+                    event_store.clone(),
+                    snapshot_store.clone(),
+                    read_model.clone(),
+                    clock.clone(),
+                ) {
+                    eprintln!("Failed to purge the trash: {}", err);
+                }
+                // Tells other instances which deletions this one has seen
+                if let Err(err) = app::acknowledge_deletions(
+                    event_store.clone(),
+                    snapshot_store.clone(),
+                    read_model.clone(),
+                    clock.clone(),
+                ) {
+                    eprintln!("Failed to acknowledge deletions: {}", err);
+                }
+            }
+        }
+    });
+
+    // Projections that failed are retried until they go through
+    tokio::spawn({
+        let event_store = event_store.clone();
+        let snapshot_store = snapshot_store.clone();
+        let read_model = read_model.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                if let Err(err) = app::catch_up(
+                    event_store.clone(),
+                    snapshot_store.clone(),
+                    read_model.clone(),
+                ) {
+                    eprintln!("Failed to bring the read model up to date: {}", err);
+                }
+            }
+        }
+    });
+
+    let pack_after = Duration::from_secs(args.pack_after_days * 24 * 60 * 60);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            if let Some(created_before) = SystemTime::now().checked_sub(pack_after) {
+                if let Err(err) = packed_event_store.pack_events(created_before) {
+                    eprintln!("Failed to pack events: {}", err);
+                }
+            }
+        }
+    });
+
+    let peer_server = axum::Server::bind(&peer_addr).serve(
+        http_api_axum::create_peer_router(
+            event_store.clone(),
+            snapshot_store.clone(),
+            key_store.clone(),
+            read_model.clone(),
+            signed_event_store.clone(),
+            clock.clone(),
+        )
+        .into_make_service(),
+    );
+    tokio::spawn(async move {
+        if let Err(err) = peer_server.await {
+            eprintln!("Failed to serve the sync API: {}", err);
+        }
+    });
 
     axum::Server::bind(&addr)
         .serve(
-            http_api_axum::create_router(event_store.clone(), read_model.clone(), clock.clone())
-                .into_make_service(),
+            http_api_axum::create_router(
+                event_store.clone(),
+                snapshot_store.clone(),
+                key_store.clone(),
+                read_model.clone(),
+                clock.clone(),
+            )
+            .into_make_service(),
         )
         .await
         .unwrap();
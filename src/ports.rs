@@ -1,23 +1,76 @@
-use crate::domain::data::{BookmarkData, DomainEvent};
+use crate::domain::{
+    chain,
+    checkpoint::Checkpoint,
+    data::{BookmarkData, BookmarkSnapshot, DomainEvent, PeerSyncData, TrashedBookmarkData},
+    reconciliation::EventIds,
+    revocations::Revocations,
+};
 use std::time::SystemTime;
 
 pub trait EventStore: Send + Sync {
-    fn store_event(&self, event: DomainEvent) -> Result<(), EventStoreError>;
+    // Appends the events a command resulted in, all of them or none, linking
+    // them into this instance's chain unless a decorator already did. If
+    // `expected_version` is given, they're only appended if their aggregate
+    // is still at that version.
+    fn store_events(
+        &self,
+        events: Vec<DomainEvent>,
+        expected_version: Option<u64>,
+    ) -> Result<(), EventStoreError>;
     fn import_event(&self, event: DomainEvent) -> Result<(), EventStoreError>;
+    // Swaps the stored event with the same instance and sequence number for
+    // `event`, which is how history gets compacted.
+    fn replace_event(&self, event: DomainEvent) -> Result<(), EventStoreError>;
     fn get_events_for_aggregate(&self, aggregate_id: &str) -> Vec<DomainEvent>;
+    // Number of events stored for an aggregate, whether or not they can be
+    // read through the decorators on top of the store.
+    fn aggregate_version(&self, aggregate_id: &str) -> u64;
     fn events_iter(&self) -> Box<dyn Iterator<Item = DomainEvent>>;
+    // Events in log order that a projection at `checkpoint` may not have been
+    // brought up to date with. Stores that can tell which events it covers
+    // without reading them leave those out.
+    fn events_after(&self, _checkpoint: &Checkpoint) -> Box<dyn Iterator<Item = DomainEvent>> {
+        self.events_iter()
+    }
+    fn event_ids(&self) -> EventIds;
+    // This instance's latest event in its chain. Stores that can find it
+    // without reading the whole log do so.
+    fn chain_head(&self) -> Option<DomainEvent> {
+        chain::head(&self.instance_id(), self.events_iter())
+    }
+    fn instance_id(&self) -> String;
 }
 
 #[derive(thiserror::Error, Debug, PartialEq)]
 pub enum EventStoreError {
     #[error("Generic event store error")]
     Generic,
+    #[error("Event signature missing or not trusted")]
+    InvalidSignature,
+    #[error("Aggregate was changed concurrently")]
+    Conflict,
+    #[error("Stored events can't be decrypted with this passphrase")]
+    Undecryptable,
+    #[error("The library's salt hasn't been synced to this device yet")]
+    MissingLibrarySalt,
 }
 
 pub trait ReadModel: Send + Sync {
+    // Projects an event, advancing the checkpoint if it succeeds. Events the
+    // checkpoint already covers are left alone.
     fn update(&self, event: &DomainEvent) -> Result<(), ReadModelError>;
+    // Advances the checkpoint past an event that doesn't count, e.g. one of
+    // a revoked instance, without projecting it.
+    fn skip(&self, event: &DomainEvent);
+    fn checkpoint(&self) -> Checkpoint;
+    // Keeps what's been projected along with the checkpoint, if the read
+    // model outlives the process at all.
+    fn save(&self) -> Result<(), ReadModelError>;
     fn read_bookmark(&self, id: &str) -> Option<BookmarkData>;
     fn read_bookmarks(&self) -> Option<Vec<BookmarkData>>;
+    fn read_trash(&self) -> Option<Vec<TrashedBookmarkData>>;
+    fn read_peers(&self) -> Option<Vec<PeerSyncData>>;
+    fn clear(&self) -> Result<(), ReadModelError>;
 }
 
 #[derive(thiserror::Error, Debug, PartialEq)]
@@ -26,6 +79,45 @@ pub enum ReadModelError {
     Generic,
 }
 
+// Snapshots only save work, so losing them is harmless. Which events count
+// at all is worked out from revocations across the whole log, so that's kept
+// here too, and cleared along with the snapshots.
+pub trait SnapshotStore: Send + Sync {
+    fn load(&self, aggregate_id: &str) -> Option<BookmarkSnapshot>;
+    fn save(&self, snapshot: BookmarkSnapshot);
+    fn invalidate(&self, aggregate_id: &str);
+    fn clear(&self);
+    fn revocations(&self) -> Option<Revocations>;
+    fn save_revocations(&self, revocations: Revocations);
+}
+
+pub type AggregateKey = [u8; 32];
+
+// Keys sealing each bookmark's events. They're kept out of the log so that
+// destroying one truly forgets the bookmark, and a key is never accepted
+// again once destroyed.
+pub trait KeyStore: Send + Sync {
+    fn key(&self, aggregate_id: &str) -> Option<AggregateKey>;
+    fn save_key(&self, aggregate_id: &str, key: AggregateKey) -> Result<(), KeyStoreError>;
+    fn destroy_key(&self, aggregate_id: &str) -> Result<(), KeyStoreError>;
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum KeyStoreError {
+    #[error("Generic key store error")]
+    Generic,
+    #[error("Key was destroyed")]
+    Destroyed,
+}
+
+// Proves to peers which instance a sync request comes from, and tells
+// whether a request received comes from a trusted instance.
+pub trait PeerAuthenticator: Send + Sync {
+    // Returns this instance's ID and its hex-encoded signature of `message`
+    fn sign(&self, message: &[u8]) -> (String, String);
+    fn verify(&self, instance_id: &str, message: &[u8], signature: &str) -> bool;
+}
+
 pub trait Clock: Send + Sync {
     fn now(&self) -> SystemTime;
 }